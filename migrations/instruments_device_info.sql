@@ -0,0 +1,7 @@
+-- Adds the device details captured from the BLE Device Information and Battery services
+ALTER TABLE instruments ADD COLUMN mac TEXT;
+ALTER TABLE instruments ADD COLUMN manufacturer TEXT;
+ALTER TABLE instruments ADD COLUMN model TEXT;
+ALTER TABLE instruments ADD COLUMN serial TEXT;
+ALTER TABLE instruments ADD COLUMN firmware TEXT;
+ALTER TABLE instruments ADD COLUMN battery INTEGER;
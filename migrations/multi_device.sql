@@ -0,0 +1,74 @@
+-- Allows several instruments per metric, identified by their MAC, and tags continuous
+-- samples with the instrument they came from. Existing samples count as manual (0).
+
+-- Step 1: Create the new tables
+CREATE TABLE IF NOT EXISTS instruments_new (
+    id          INTEGER PRIMARY KEY,
+    metric      TEXT NOT NULL,
+    name        TEXT NOT NULL,
+    introduced  INTEGER NOT NULL,
+    deprecated  INTEGER,
+    tol_min     REAL,
+    tol_max     REAL,
+    notes       TEXT,
+    mac         TEXT UNIQUE,
+    manufacturer TEXT,
+    model       TEXT,
+    serial      TEXT,
+    firmware    TEXT,
+    battery     INTEGER
+);
+CREATE TABLE IF NOT EXISTS heartrate_new (
+    id          INTEGER PRIMARY KEY,
+    timestamp   INTEGER NOT NULL,
+    heartrate   INTEGER NOT NULL,
+    duration    INTEGER DEFAULT (0) NOT NULL,
+    instrument  INTEGER DEFAULT (0) NOT NULL,
+    UNIQUE (timestamp, instrument)
+);
+CREATE TABLE IF NOT EXISTS temperature_new (
+    id          INTEGER PRIMARY KEY,
+    timestamp   INTEGER NOT NULL,
+    temperature REAL NOT NULL,
+    duration    INTEGER DEFAULT (0) NOT NULL,
+    instrument  INTEGER DEFAULT (0) NOT NULL,
+    UNIQUE (timestamp, instrument)
+);
+CREATE TABLE IF NOT EXISTS spo2_new (
+    id          INTEGER PRIMARY KEY,
+    timestamp   INTEGER NOT NULL,
+    spo2        INTEGER NOT NULL,
+    pulse       INTEGER NOT NULL,
+    duration    INTEGER DEFAULT (0) NOT NULL,
+    instrument  INTEGER DEFAULT (0) NOT NULL,
+    UNIQUE (timestamp, instrument)
+);
+
+-- Step 2: Copy data from the old tables to the new tables
+INSERT INTO instruments_new (id, metric, name, introduced, deprecated, tol_min, tol_max, notes, mac, manufacturer, model, serial, firmware, battery)
+SELECT id, metric, name, introduced, deprecated, tol_min, tol_max, notes, mac, manufacturer, model, serial, firmware, battery
+FROM instruments;
+
+INSERT INTO heartrate_new (id, timestamp, heartrate, duration)
+SELECT id, timestamp, heartrate, duration
+FROM heartrate;
+
+INSERT INTO temperature_new (id, timestamp, temperature, duration)
+SELECT id, timestamp, temperature, duration
+FROM temperature;
+
+INSERT INTO spo2_new (id, timestamp, spo2, pulse, duration)
+SELECT id, timestamp, spo2, pulse, duration
+FROM spo2;
+
+-- Step 3: Drop the old tables
+DROP TABLE instruments;
+DROP TABLE heartrate;
+DROP TABLE temperature;
+DROP TABLE spo2;
+
+-- Step 4: Rename the new tables to the old tables' names
+ALTER TABLE instruments_new RENAME TO instruments;
+ALTER TABLE heartrate_new RENAME TO heartrate;
+ALTER TABLE temperature_new RENAME TO temperature;
+ALTER TABLE spo2_new RENAME TO spo2;
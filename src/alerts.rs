@@ -0,0 +1,543 @@
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    args::Args,
+    http::METRICS,
+    output::{self, Field, Output, Record},
+    store::{Consumer, Reading},
+    utils,
+    webhooks::{self, Comparison},
+    Error, SectionedConfigMap,
+};
+use chrono::Utc;
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+
+#[derive(Clone, Copy)]
+enum Window {
+    // Only the new reading
+    Reading,
+    // Every reading for this many seconds, e.g. for 30m
+    For(i64),
+    // The average of the readings in this many seconds, e.g. avg 5m
+    Average(i64),
+    // At least this many readings in this many seconds, e.g. count 3 in 7d, whatever the
+    // new one is
+    Count(i64, i64),
+}
+
+struct Rule {
+    metric: &'static str,
+    field: &'static str,
+    comparison: Comparison,
+    limit: f64,
+    window: Window,
+    // As configured, e.g. "bp sys > 140 count 3 in 7d", which also identifies its alerts
+    rule: String,
+}
+
+impl Rule {
+    // The value the rule raises an alert with, None if it does not hold at the reading.
+    // That is the reading, or the average or count of the window. The duration is that of
+    // a compressed row, None for metrics without.
+    fn evaluate(
+        &self,
+        timestamp: i64,
+        duration: Option<i64>,
+        value: f64,
+        conn: &Connection,
+    ) -> Result<Option<f64>, rusqlite::Error> {
+        let holds = |value: f64| self.comparison.holds(value, self.limit);
+        let (field, op) = (self.field, self.comparison.operator());
+        // A compressed row stands for every second from its timestamp on
+        let column = if duration.is_some() { "duration" } else { "0" };
+        let end = timestamp + duration.unwrap_or(0);
+
+        match self.window {
+            Window::Reading => Ok(holds(value).then_some(value)),
+            Window::For(seconds) => {
+                // The first reading since the last one the rule did not hold for ended
+                let since: Option<i64> = conn.query_row(
+                    &format!(
+                        "SELECT MIN(timestamp) FROM {table} WHERE timestamp <= ?1 AND timestamp > IFNULL(
+                            (SELECT MAX(timestamp + {column}) FROM {table} WHERE timestamp <= ?1 AND NOT ({field} {op} ?2)), ?3);",
+                        table = self.metric,
+                    ),
+                    params![timestamp, self.limit, i64::MIN],
+                    |row| row.get(0),
+                )?;
+                Ok(since.filter(|since| end - since >= seconds).map(|_| value))
+            }
+            Window::Average(seconds) => {
+                // Weighted by the seconds each row covers of the window
+                let average: Option<f64> = conn.query_row(
+                    &format!(
+                        "SELECT SUM({field} * covered) * 1.0 / SUM(covered) FROM (
+                            SELECT {field}, MIN(timestamp + MAX({column}, 1), ?2) - MAX(timestamp, ?1) AS covered
+                            FROM {} WHERE timestamp < ?2 AND timestamp + MAX({column}, 1) > ?1
+                        );",
+                        self.metric
+                    ),
+                    params![end - seconds + 1, end + 1],
+                    |row| row.get(0),
+                )?;
+                Ok(average.filter(|average| holds(*average)))
+            }
+            Window::Count(count, seconds) => {
+                let matching: i64 = conn.query_row(
+                    &format!(
+                        "SELECT COUNT(*) FROM {} WHERE timestamp > ?1 AND timestamp <= ?2 AND {field} {op} ?3;",
+                        self.metric
+                    ),
+                    params![timestamp - seconds, timestamp, self.limit],
+                    |row| row.get(0),
+                )?;
+                Ok((matching >= count).then_some(matching as f64))
+            }
+        }
+    }
+}
+
+// Like 30s, 5m, 2h or 7d
+fn seconds(duration: &str) -> Option<i64> {
+    let unit = match duration.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return None,
+    };
+    let amount = duration[..duration.len() - 1].parse::<i64>().ok()?;
+    (amount > 0).then_some(amount * unit)
+}
+
+// Configured as `rules = <rule>, ...` in section alerts, where a rule is one of
+//   [<metric>] <field> <op> <limit> [for <duration>]
+//   [<metric>] <field> avg <duration> <op> <limit>
+//   [<metric>] <field> <op> <limit> count <n> in <duration>
+// with fields like in section webhooks, e.g. temperature > 38.0 for 30m,
+// heartrate avg 5m > 150 or bp sys > 140 count 3 in 7d.
+fn rules(entries: &str) -> Result<Vec<Rule>, Error> {
+    entries
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let invalid = || {
+                Error::Config(format!(
+                    "Invalid rule '{}' in section alerts, expected e.g. temperature > 38.0 for 30m, heartrate avg 5m > 150 or bp sys > 140 count 3 in 7d",
+                    entry.trim()
+                ))
+            };
+            let parts: Vec<&str> = entry.split_whitespace().collect();
+            let mut rest = &parts[..];
+
+            // The metric is optional, every field belongs to one
+            if let [metric, field, ..] = rest {
+                if METRICS
+                    .iter()
+                    .any(|candidate| candidate.path == *metric && candidate.fields.contains(field))
+                {
+                    rest = &rest[1..];
+                }
+            }
+            let [field, tail @ ..] = rest else {
+                return Err(invalid());
+            };
+            let (metric, field) = webhooks::field(field).ok_or_else(invalid)?;
+
+            let (average, tail) = match tail {
+                ["avg", duration, tail @ ..] => (Some(seconds(duration).ok_or_else(invalid)?), tail),
+                _ => (None, tail),
+            };
+            let [op, limit, tail @ ..] = tail else {
+                return Err(invalid());
+            };
+            let window = match (average, tail) {
+                (Some(span), []) => Window::Average(span),
+                (None, []) => Window::Reading,
+                (None, ["for", duration]) => Window::For(seconds(duration).ok_or_else(invalid)?),
+                (None, ["count", count, "in", duration]) => Window::Count(
+                    count
+                        .parse::<i64>()
+                        .ok()
+                        .filter(|count| *count > 0)
+                        .ok_or_else(invalid)?,
+                    seconds(duration).ok_or_else(invalid)?,
+                ),
+                _ => return Err(invalid()),
+            };
+
+            Ok(Rule {
+                metric,
+                field,
+                comparison: Comparison::parse(op).ok_or_else(invalid)?,
+                limit: limit.parse().map_err(|_| invalid())?,
+                window,
+                rule: parts.join(" "),
+            })
+        })
+        .collect()
+}
+
+// Whether section alerts has rules, so there can be alerts to show
+pub fn configured(conf: Arc<RwLock<SectionedConfigMap>>) -> bool {
+    !utils::from_config_or(conf, "alerts", "rules", "")
+        .trim()
+        .is_empty()
+}
+
+pub fn tables(conn: &Connection) {
+    let _ = conn
+        .execute(
+            "CREATE TABLE IF NOT EXISTS alerts (
+                    id          INTEGER PRIMARY KEY,
+                    rule        TEXT NOT NULL,
+                    metric      TEXT NOT NULL,
+                    value       REAL NOT NULL,
+                    raised      INTEGER NOT NULL,
+                    cleared     INTEGER,
+                    shown       INTEGER DEFAULT (0) NOT NULL,
+                    acknowledged INTEGER
+                );",
+            [],
+        )
+        .map_err(|err| error!("Failed to ensure table 'alerts' exists -> {}", err));
+}
+
+// Evaluates the rules in section alerts on every committed reading, see Listener. A
+// rule that starts to hold raises one alert, which is cleared by the first reading it
+// no longer holds for. Readings older than the newest of their metric are skipped.
+pub struct Alerter {
+    rules: Vec<Rule>,
+}
+
+impl Alerter {
+    // None if no rule is configured
+    pub fn from_config(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Option<Alerter>, Error> {
+        let rules = rules(&utils::from_config_or(conf, "alerts", "rules", ""))?;
+        if rules.is_empty() {
+            return Ok(None);
+        }
+
+        info!("Evaluating {} alert rules", rules.len());
+        Ok(Some(Alerter { rules }))
+    }
+}
+
+impl Consumer for Alerter {
+    fn wants(&self, table: &str) -> bool {
+        self.rules.iter().any(|rule| rule.metric == table)
+    }
+
+    fn consume(&mut self, reading: &Reading, conn: &Connection) {
+        check(reading, conn, &self.rules);
+    }
+}
+
+// Raises or clears the alerts of the rules on the reading's metric
+fn check(reading: &Reading, conn: &Connection, rules: &[Rule]) {
+    let Reading { table, record } = reading;
+    let Some(timestamp) = record.get("timestamp").and_then(Value::as_i64) else {
+        return;
+    };
+    let duration = record
+        .get("duration")
+        .map(|duration| duration.as_i64().unwrap_or(0));
+
+    // A backfilled reading would raise or clear the alerts of the past
+    let newest: Result<Option<i64>, rusqlite::Error> = conn.query_row(
+        &format!("SELECT MAX(timestamp) FROM {};", table),
+        [],
+        |row| row.get(0),
+    );
+    match newest {
+        Ok(Some(newest)) if newest > timestamp => return,
+        Ok(_) => {}
+        Err(err) => {
+            error!("Failed to evaluate alert rules -> {}", err);
+            return;
+        }
+    }
+
+    for rule in rules.iter().filter(|rule| rule.metric == table) {
+        let Some(value) = record.get(rule.field).and_then(Value::as_f64) else {
+            continue;
+        };
+        let updated = rule
+            .evaluate(timestamp, duration, value, conn)
+            .and_then(|raised| update(rule, raised, timestamp, conn));
+        if let Err(err) = updated {
+            error!("Failed to evaluate alert rule {} -> {}", rule.rule, err);
+        }
+    }
+}
+
+// Raises an alert for a rule that started to hold and clears the one of a rule that
+// stopped holding
+fn update(
+    rule: &Rule,
+    raised: Option<f64>,
+    timestamp: i64,
+    conn: &Connection,
+) -> Result<(), rusqlite::Error> {
+    let active: Option<i64> = conn
+        .query_row(
+            "SELECT id FROM alerts WHERE rule = ?1 AND cleared IS NULL;",
+            [&rule.rule],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    match (raised, active) {
+        (Some(value), None) => {
+            conn.execute(
+                "INSERT INTO alerts (rule, metric, value, raised) VALUES (?1, ?2, ?3, ?4);",
+                params![rule.rule, rule.metric, value, timestamp],
+            )?;
+            warn!(
+                "Alert {}: {}, value {}",
+                conn.last_insert_rowid(),
+                rule.rule,
+                value
+            );
+        }
+        (None, Some(id)) => {
+            conn.execute(
+                "UPDATE alerts SET cleared = ?1 WHERE id = ?2;",
+                params![timestamp, id],
+            )?;
+            info!("Alert {} cleared: {}", id, rule.rule);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Lists the unacknowledged alerts, only those not shown before with new, or the latest
+// ones with last, and acknowledges them with ack
+pub fn command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+    let (records, notes) = match input.next() {
+        None => {
+            let records = unacknowledged(false, conn)?;
+            let note = if records.is_empty() {
+                "No unacknowledged alerts"
+            } else {
+                "Unacknowledged alerts, acknowledge them with 'alerts ack <id>'"
+            };
+            (records, vec![String::from(note)])
+        }
+        // Empty without new alerts, so the REPL can ask after every command
+        Some("new") => {
+            let records = unacknowledged(true, conn)?;
+            let notes = if records.is_empty() {
+                Vec::new()
+            } else {
+                vec![String::from(
+                    "New alerts, acknowledge them with 'alerts ack <id>'",
+                )]
+            };
+            (records, notes)
+        }
+        Some("last") => {
+            let count = match input.next() {
+                Some(count) => count.parse::<i64>().map_err(|e| {
+                    Error::Parse(format!("Failed to parse parameter: {}: {}", count, e))
+                })?,
+                None => 10,
+            };
+            let mut records = read("1", count, conn)?;
+            records.reverse();
+            (records, Vec::new())
+        }
+        Some("ack") => {
+            let id = input
+                .next()
+                .ok_or_else(|| Error::Parse(String::from("Expected parameter: <id:i64>")))?;
+            let id = id
+                .parse::<i64>()
+                .map_err(|e| Error::Parse(format!("Failed to parse parameter: {}: {}", id, e)))?;
+            return acknowledge(id, conn);
+        }
+        Some(other) => {
+            return Err(Error::Parse(format!(
+                "Unknown parameter: {}. Expected: [new | last [<count:i64>] | ack <id:i64>]",
+                other
+            )))
+        }
+    };
+
+    Ok(Output::Records { notes, records })
+}
+
+// Oldest first, marked as shown
+fn unacknowledged(only_new: bool, conn: &Connection) -> Result<Vec<Record>, Error> {
+    let filter = if only_new {
+        "acknowledged IS NULL AND shown = 0"
+    } else {
+        "acknowledged IS NULL"
+    };
+    let mut records = read(filter, -1, conn)?;
+    records.reverse();
+
+    if let Some(last) = records.last() {
+        conn.execute(
+            &format!("UPDATE alerts SET shown = 1 WHERE {} AND id <= ?1;", filter),
+            [last.id],
+        )
+        .map_err(Error::database("mark alerts shown"))?;
+    }
+    Ok(records)
+}
+
+// Newest first, up to count or all with a negative one
+fn read(filter: &str, count: i64, conn: &Connection) -> Result<Vec<Record>, Error> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT id, rule, value, raised, cleared, acknowledged FROM alerts
+                WHERE {} ORDER BY id DESC LIMIT ?1;",
+            filter
+        ))
+        .map_err(Error::database("read alerts"))?;
+    stmt.query_map([count], |row| {
+        let id: i64 = row.get(0)?;
+        let cleared: Option<i64> = row.get(4)?;
+        let acknowledged: Option<i64> = row.get(5)?;
+        let mut status = match cleared {
+            Some(cleared) => format!("cleared {}", utils::format_timestamp(cleared)),
+            None => String::from("active"),
+        };
+        if let Some(acknowledged) = acknowledged {
+            status.push_str(&format!(
+                ", acknowledged {}",
+                utils::format_timestamp(acknowledged)
+            ));
+        }
+
+        Ok(Record {
+            id,
+            timestamp: row.get(3)?,
+            duration: None,
+            source: None,
+            fields: vec![
+                Field {
+                    name: "alert",
+                    value: output::Value::Integer(id),
+                    unit: "",
+                    label: "alert {}",
+                },
+                Field {
+                    name: "rule",
+                    value: output::Value::Text(row.get(1)?),
+                    unit: "",
+                    label: "{}",
+                },
+                Field {
+                    name: "value",
+                    value: output::Value::Real(row.get(2)?),
+                    unit: "",
+                    label: "value {}",
+                },
+                Field {
+                    name: "status",
+                    value: output::Value::Text(status),
+                    unit: "",
+                    label: "{}",
+                },
+            ],
+        })
+    })
+    .and_then(|rows| rows.collect::<Result<Vec<Record>, rusqlite::Error>>())
+    .map_err(Error::database("read alerts"))
+}
+
+fn acknowledge(id: i64, conn: &Connection) -> Result<Output, Error> {
+    let acknowledged = conn
+        .execute(
+            "UPDATE alerts SET acknowledged = ?1 WHERE id = ?2 AND acknowledged IS NULL;",
+            params![Utc::now().timestamp(), id],
+        )
+        .map_err(Error::database(format!("acknowledge alert {}", id)))?;
+    if acknowledged == 0 {
+        return Err(Error::Parse(format!("No unacknowledged alert {}", id)));
+    }
+    Ok(Output::message(format!("Acknowledged alert {}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        store::{self, Inserted},
+        Store,
+    };
+
+    fn alerts(conn: &Connection) -> Vec<(i64, Option<i64>)> {
+        conn.prepare("SELECT raised, cleared FROM alerts ORDER BY id;")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn for_window_includes_the_duration_of_compressed_rows() {
+        let store = Store::open(":memory:").unwrap();
+        let conn = store.connection();
+        conn.execute_batch(
+            "INSERT INTO temperature (timestamp, temperature, duration) VALUES (0, 39.0, 1800);",
+        )
+        .unwrap();
+
+        let rule = &rules("temperature > 38.0 for 30m").unwrap()[0];
+        assert_eq!(
+            rule.evaluate(0, Some(1800), 39.0, conn).unwrap(),
+            Some(39.0)
+        );
+        assert_eq!(rule.evaluate(0, Some(1000), 39.0, conn).unwrap(), None);
+    }
+
+    #[test]
+    fn average_weighs_rows_by_the_seconds_they_cover() {
+        let store = Store::open(":memory:").unwrap();
+        let conn = store.connection();
+        conn.execute_batch(
+            "INSERT INTO heartrate (timestamp, heartrate, duration) VALUES (0, 100, 240);
+            INSERT INTO heartrate (timestamp, heartrate, duration) VALUES (250, 220, 49);",
+        )
+        .unwrap();
+
+        // Not the 160 of the two rows
+        let rule = &rules("heartrate avg 5m > 150").unwrap()[0];
+        assert_eq!(rule.evaluate(250, Some(49), 220.0, conn).unwrap(), None);
+        let rule = &rules("heartrate avg 5m > 120").unwrap()[0];
+        let average = rule.evaluate(250, Some(49), 220.0, conn).unwrap().unwrap();
+        assert!((average - 34780.0 / 289.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn backfilled_readings_leave_alerts_alone() {
+        let conn = Connection::open_in_memory().unwrap();
+        store::tables(&conn);
+        conn.execute_batch(
+            "INSERT INTO weight (timestamp, weight) VALUES (100, 102.0);
+            INSERT INTO weight (timestamp, weight) VALUES (50, 90.0);",
+        )
+        .unwrap();
+        let rules = rules("weight > 100").unwrap();
+
+        for rowid in [1, 2] {
+            let row = Inserted {
+                table: String::from("weight"),
+                rowid,
+            };
+            let reading = Reading {
+                record: store::read_inserted(&conn, &row).unwrap().unwrap(),
+                table: row.table,
+            };
+            check(&reading, &conn, &rules);
+        }
+        assert_eq!(alerts(&conn), vec![(100, None)]);
+    }
+}
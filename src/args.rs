@@ -0,0 +1,116 @@
+use std::slice::Iter;
+
+use crate::Error;
+
+// Splits a line into words like a shell does. Whitespace inside single or double
+// quotes is kept, and a backslash outside single quotes takes the next character
+// literally, so `mood "slept well"` passes one parameter.
+pub fn tokenize(line: &str) -> Result<Vec<String>, Error> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    // Distinguishes an empty quoted word from no word at all
+    let mut in_token = false;
+    let mut quote: Option<char> = None;
+
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('"'), '"') => quote = None,
+            (Some('\''), _) => token.push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => {
+                    token.push(escaped);
+                    in_token = true;
+                }
+                None => {
+                    return Err(Error::Parse(String::from(
+                        "Unfinished escape at end of line",
+                    )))
+                }
+            },
+            (Some(_), _) => token.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_token = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            (None, _) => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if let Some(quote) = quote {
+        return Err(Error::Parse(format!("Unterminated quote: {}", quote)));
+    }
+    if in_token {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+// The remaining parameters of a command
+pub struct Args<'a> {
+    tokens: Iter<'a, String>,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(tokens: &'a [String]) -> Args<'a> {
+        Args {
+            tokens: tokens.iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.tokens.next().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
+
+    #[test]
+    fn quotes_keep_whitespace_and_join_adjacent_text() {
+        assert_eq!(words("  mood  \"slept well\" "), vec!["mood", "slept well"]);
+        assert_eq!(words("note 'a  b'c\"d e\""), vec!["note", "a  bcd e"]);
+        assert_eq!(words("mood '' \"\""), vec!["mood", "", ""]);
+        assert_eq!(
+            words("say \"it's\" 'a \"b\"'"),
+            vec!["say", "it's", "a \"b\""]
+        );
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn backslashes_escape_outside_single_quotes() {
+        assert_eq!(words(r"slept\ well"), vec!["slept well"]);
+        assert_eq!(words(r#""a \" b""#), vec![r#"a " b"#]);
+        assert_eq!(words(r"'a\b'"), vec![r"a\b"]);
+        assert_eq!(words(r"\'x"), vec!["'x"]);
+        assert_eq!(words(r"a \\ b"), vec!["a", r"\", "b"]);
+    }
+
+    #[test]
+    fn unfinished_quotes_and_escapes_are_errors() {
+        assert!(tokenize("mood \"slept well").is_err());
+        assert!(tokenize("mood 'slept").is_err());
+        assert!(tokenize(r"mood slept\").is_err());
+    }
+}
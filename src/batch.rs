@@ -0,0 +1,160 @@
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+};
+
+use log::info;
+use rusqlite::Connection;
+
+use crate::{
+    args::{self, Args},
+    commands::dispatch,
+    output::Output,
+    utils, Error, SectionedConfigMap,
+};
+
+// These open transactions of their own, wait for devices, copy or replace the database
+// or delete aged samples
+const UNBATCHABLE: [&str; 14] = [
+    "source",
+    "record",
+    "record_hrp",
+    "record_plx",
+    "ble",
+    "serve",
+    "dashboard",
+    "tui",
+    "backup",
+    "restore",
+    "maintain",
+    "upgrade_tables",
+    "daemon",
+    "subscribe",
+];
+
+enum OnError {
+    Stop,
+    Continue,
+}
+
+// Runs every line of a file as a command inside one transaction. Blank lines and
+// lines starting with # are skipped. A line starting with @<timestamp> records its
+// readings at that time, e.g. `@2024-03-01T08:30 weight 80.2`. On the first failing
+// line everything is rolled back, unless continue is given, which skips failing
+// lines and keeps the rest.
+pub async fn source(
+    input: &mut Args<'_>,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<Output, Error> {
+    let path = input
+        .next()
+        .ok_or_else(|| Error::Parse(String::from("Missing parameter: file_path")))?;
+    let on_error = match input.next() {
+        None | Some("stop") => OnError::Stop,
+        Some("continue") => OnError::Continue,
+        Some(other) => {
+            return Err(Error::Parse(format!(
+                "Unknown parameter: {}. Use stop or continue",
+                other
+            )))
+        }
+    };
+
+    let contents =
+        fs::read_to_string(path).map_err(Error::io(format!("read batch file {}", path)))?;
+
+    let tx = conn
+        .unchecked_transaction()
+        .map_err(Error::database("begin the batch transaction"))?;
+
+    let mut commands = 0;
+    // Commands recording a reading, not rows, which imports may write many of
+    let mut recorded = 0;
+    let mut failures = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        commands += 1;
+
+        match run_line(line, conf.clone(), &tx).await {
+            Ok(Output::Recorded { .. }) => recorded += 1,
+            Ok(_) => {}
+            Err(err) => {
+                failures.push((format!("line {}", number), err));
+                if let OnError::Stop = on_error {
+                    // Dropping the transaction rolls it back
+                    return Err(Error::Batch(
+                        failures,
+                        format!("Stopped at line {}, no readings were inserted", number),
+                    ));
+                }
+            }
+        }
+    }
+
+    tx.commit()
+        .map_err(Error::database(format!("commit batch {}", path)))?;
+    info!(
+        "Batch {} ran {} commands, {} recorded a reading",
+        path, commands, recorded
+    );
+
+    let summary = format!(
+        "Ran {} commands from {}, {} recorded a reading",
+        commands, path, recorded
+    );
+    if failures.is_empty() {
+        Ok(Output::message(summary))
+    } else {
+        let summary = format!("{}, {} failed", summary, failures.len());
+        Err(Error::Batch(failures, summary))
+    }
+}
+
+// Runs one line in a savepoint, so a failing command leaves nothing half written
+async fn run_line(
+    line: &str,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<Output, Error> {
+    let tokens = args::tokenize(line)?;
+    let mut input = Args::new(&tokens);
+    let mut command = input.next().unwrap_or_default();
+
+    let mut entry_time = None;
+    if let Some(timestamp) = command.strip_prefix('@') {
+        entry_time =
+            Some(utils::parse_timestamp(timestamp).ok_or_else(|| {
+                Error::Parse(format!("Failed to parse timestamp: {}", timestamp))
+            })?);
+        command = input.next().unwrap_or_default();
+    }
+
+    if UNBATCHABLE.contains(&command) {
+        return Err(Error::Parse(format!("{} cannot run in a batch", command)));
+    }
+
+    conn.execute_batch("SAVEPOINT batch_line;")
+        .map_err(Error::database("open a savepoint"))?;
+
+    let result = match entry_time {
+        Some(timestamp) => {
+            utils::ENTRY_TIME
+                .scope(timestamp, dispatch(command, &mut input, conf, conn))
+                .await
+        }
+        None => dispatch(command, &mut input, conf, conn).await,
+    };
+
+    let closed = match result {
+        Ok(_) => conn.execute_batch("RELEASE batch_line;"),
+        Err(_) => conn.execute_batch("ROLLBACK TO batch_line; RELEASE batch_line;"),
+    };
+    closed.map_err(Error::database("close the savepoint"))?;
+
+    result
+}
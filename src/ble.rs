@@ -0,0 +1,526 @@
+use futures::{future::join_all, FutureExt, StreamExt};
+use std::{
+    future::Future,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use btleplug::{
+    api::{BDAddr, Central, Characteristic, Manager as _, Peripheral, ScanFilter},
+    platform::Manager,
+};
+use chrono::{Local, NaiveDate, TimeZone};
+use log::{error, info, warn};
+use rusqlite::Connection;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use crate::{
+    args::Args,
+    ble_hrp, ble_htp, ble_plx, ble_sync,
+    instruments::{self, DeviceInfo},
+    utils,
+    writer::{self, Sample},
+    Error, SectionedConfigMap,
+};
+
+const MANUFACTURER_NAME_UUID: &str = "00002a29-0000-1000-8000-00805f9b34fb";
+const MODEL_NUMBER_UUID: &str = "00002a24-0000-1000-8000-00805f9b34fb";
+const SERIAL_NUMBER_UUID: &str = "00002a25-0000-1000-8000-00805f9b34fb";
+const FIRMWARE_REVISION_UUID: &str = "00002a26-0000-1000-8000-00805f9b34fb";
+const BATTERY_LEVEL_UUID: &str = "00002a19-0000-1000-8000-00805f9b34fb";
+
+pub async fn command(
+    input: &mut Args<'_>,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let param = match input.next() {
+        Some(param) => param,
+        None => return Err(Error::Parse(String::from("No further parameters"))),
+    };
+
+    match param {
+        "sync" => ble_sync::sync(input, conf, conn).await,
+        _ => Err(Error::Parse(format!("Unknown ble command: {}", param))),
+    }
+}
+
+pub struct Device {
+    pub profile: String,
+    pub mac: String,
+}
+
+// Configured as `devices = <profile> <mac>, <profile> <mac>, ...` in section ble.
+// Profiles are hrp (heart rate), plx (pulse oximeter), htp (thermometer) and
+// bls (blood pressure cuff, sync only).
+pub fn devices(conf: Arc<RwLock<SectionedConfigMap>>) -> Vec<Device> {
+    let devices = utils::from_config_or(conf, "ble", "devices", "");
+
+    devices
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(profile), Some(mac)) => Some(Device {
+                    profile: profile.to_lowercase(),
+                    mac: String::from(mac),
+                }),
+                (Some(_), None) => {
+                    error!("Invalid device entry '{}' in section ble", entry.trim());
+                    None
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// Folds the single device keys used before the device list into [ble] devices
+pub fn migrate_legacy_config(conf: &mut SectionedConfigMap) {
+    let legacy = [
+        ("ble_hrp", "hrp_mac", "hrp"),
+        ("ble_plx", "plx_mac", "plx"),
+        ("ble_bp", "bp_mac", "bls"),
+    ];
+
+    let mut devices = Vec::new();
+    for (section, key, profile) in legacy {
+        let mac = conf
+            .get_mut(section)
+            .and_then(|secmap| secmap.remove(key))
+            .flatten();
+        if let Some(mac) = mac {
+            info!("Moving {} from section {} to ble devices", key, section);
+            devices.push(format!("{} {}", profile, mac));
+        }
+        if conf.get(section).is_some_and(|secmap| secmap.is_empty()) {
+            conf.remove(section);
+        }
+    }
+
+    if devices.is_empty() {
+        return;
+    }
+
+    let ble = conf.entry(String::from("ble")).or_default();
+    if let Some(Some(existing)) = ble.get("devices") {
+        devices.insert(0, existing.clone());
+    }
+    ble.insert(String::from("devices"), Some(devices.join(", ")));
+}
+
+// Records from all configured devices, or only those of one profile, at the same time.
+// Every device gets its own connection; they share one scan and one writer.
+// Runs until all devices disconnect or Ctrl-C is pressed.
+pub async fn record(
+    profile: Option<&str>,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let stop = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    record_until(profile, conf, conn, stop, None).await
+}
+
+// Like record, but runs until stop completes instead of Ctrl-C. Every sample is also
+// sent to tap, if given, as it arrives.
+pub async fn record_until(
+    profile: Option<&str>,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+    stop: impl Future<Output = ()>,
+    tap: Option<UnboundedSender<Sample>>,
+) -> Result<String, Error> {
+    let devices: Vec<Device> = devices(conf.clone())
+        .into_iter()
+        .filter(|device| profile.is_none_or(|profile| device.profile == profile))
+        .collect();
+
+    if devices.is_empty() {
+        return Err(Error::Config(String::from(
+            "No BLE devices configured for recording. See 'devices' in section ble",
+        )));
+    }
+
+    let peripherals = scan().await?;
+    let settings = writer::Settings::from_config(conf.clone());
+    let (samples, mut incoming) = unbounded_channel::<Sample>();
+    let (forward, receiver) = unbounded_channel();
+
+    let mut recorders = Vec::new();
+    for device in &devices {
+        let mac = device.mac.as_str();
+        let recorder = match device.profile.as_str() {
+            "hrp" => ble_hrp::record_device(mac, &peripherals, conf.clone(), conn, samples.clone())
+                .boxed_local(),
+            "plx" => ble_plx::record_device(mac, &peripherals, conf.clone(), conn, samples.clone())
+                .boxed_local(),
+            "htp" => ble_htp::record_device(mac, &peripherals, conf.clone(), conn, samples.clone())
+                .boxed_local(),
+            "bls" => {
+                info!("Skipping {}, blood pressure cuffs are only synced", mac);
+                continue;
+            }
+            other => {
+                error!("Unknown BLE profile {} for {}", other, mac);
+                continue;
+            }
+        };
+        recorders.push(recorder);
+    }
+    // The writer stops once every recorder has dropped its sender
+    drop(samples);
+
+    // Whether it was stopped rather than all devices went away
+    let recording = async {
+        tokio::select! {
+            _ = join_all(recorders) => {
+                info!("All devices disconnected");
+                false
+            }
+            _ = stop => {
+                info!("Recording stopped");
+                true
+            }
+        }
+    };
+
+    let forwarding = async move {
+        while let Some(sample) = incoming.recv().await {
+            if let Some(tap) = &tap {
+                let _ = tap.send(sample);
+            }
+            if forward.send(sample).is_err() {
+                break;
+            }
+        }
+    };
+
+    let (stopped, _, _) =
+        tokio::join!(recording, forwarding, writer::run(receiver, settings, conn));
+
+    // The recorders were dropped while connected, which leaves the devices connected
+    if stopped {
+        for device in devices.iter().filter(|device| device.profile != "bls") {
+            let Some(peripheral) = identify_device(&device.mac, &peripherals).await else {
+                continue;
+            };
+            if !peripheral.is_connected().await.unwrap_or(false) {
+                continue;
+            }
+            match peripheral.disconnect().await {
+                Ok(_) => info!("Disconnected from {}", device.mac),
+                Err(err) => error!("Failed to disconnect from device {}\n{}", device.mac, err),
+            }
+        }
+    }
+    Ok(String::from("Recording finished"))
+}
+
+// Connects, registers the device as instrument for the metric and feeds every value
+// of the characteristic to the handler together with the instrument id until the
+// device goes away.
+pub async fn record_characteristic(
+    mac: &str,
+    metric: &str,
+    characteristic_uuid: &str,
+    peripherals: &[btleplug::platform::Peripheral],
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+    mut handler: impl FnMut(&[u8], i64),
+) {
+    let device = match connect(mac, peripherals).await {
+        Some(device) => device,
+        None => return,
+    };
+
+    // Samples of devices that could not be registered are still kept, as manual ones
+    let instrument = register_device(mac, metric, &device, conf, conn)
+        .await
+        .unwrap_or(0);
+
+    match characteristic_subscribe(characteristic_uuid, &device).await {
+        Ok(_) => info!(
+            "Subscribed to characteristic {} on {}",
+            characteristic_uuid, mac
+        ),
+        Err(err) => {
+            error!(
+                "Failed to subscribe to characteristic {} on {}\n{}",
+                characteristic_uuid, mac, err
+            )
+        }
+    };
+
+    match device.notifications().await {
+        Ok(mut notification_stream) => {
+            while let Some(data) = notification_stream.as_mut().next().await {
+                if data.uuid.to_string() != characteristic_uuid {
+                    continue;
+                }
+                info!("Receiving data {:?} from {}", data.value, mac);
+                handler(&data.value, instrument);
+            }
+        }
+        Err(err) => error!("Failed to open notification stream for {}\n{}", mac, err),
+    }
+
+    match device.disconnect().await {
+        Ok(_) => info!("Disconnected from {}", mac),
+        Err(err) => error!("Failed to disconnected from device {}\n{}", mac, err),
+    };
+}
+
+pub async fn connect(
+    mac: &str,
+    peripherals: &[btleplug::platform::Peripheral],
+) -> Option<btleplug::platform::Peripheral> {
+    let device = match identify_device(mac, peripherals).await {
+        Some(device) => {
+            info!("Identified device {}", mac);
+            device
+        }
+        None => {
+            error!("Failed to find device {}", mac);
+            return None;
+        }
+    };
+
+    match device.connect().await {
+        Ok(_) => info!("Connected to {}", mac),
+        Err(err) => {
+            error!("Failed to connect to device {}\n{}", mac, err);
+            return None;
+        }
+    };
+
+    info!("Discovering services");
+    match device.discover_services().await {
+        Ok(_) => info!("Discovered services for {}", mac),
+        Err(err) => {
+            error!("Failed to discover services for {}\n{}", mac, err);
+            return None;
+        }
+    };
+
+    Some(device)
+}
+
+pub async fn scan() -> Result<Vec<btleplug::platform::Peripheral>, Error> {
+    let manager = Manager::new().await?;
+
+    // This works even with the adapter turned off in the OS. At least on Windows it seems to.
+    info!("Enumerating adapters. Pick first one found.");
+    let adapter = manager
+        .adapters()
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Ble(String::from("No Bluetooth adapter found")))?;
+
+    // This does NOT work with the adapter turned off.
+    let scan_time = 3; // Heart Rate Profile v10, p.13, Table 5.1 recommends up to 2.5s
+    adapter
+        .start_scan(ScanFilter { services: vec![] })
+        .await
+        .map_err(|err| {
+            Error::Ble(format!(
+                "Adapter is not ready, is Bluetooth turned on? {}",
+                err
+            ))
+        })?;
+    info!("Scanning devices for {}s", scan_time);
+    tokio::time::sleep(Duration::from_secs(scan_time)).await;
+
+    info!("Returning devices");
+    Ok(adapter.peripherals().await?)
+}
+
+async fn identify_device(
+    mac: &str,
+    devices: &[btleplug::platform::Peripheral],
+) -> Option<btleplug::platform::Peripheral> {
+    let bdaddr = match BDAddr::from_str_delim(mac) {
+        Ok(bdaddr) => bdaddr,
+        Err(err) => {
+            error!("Invalid device MAC: {}", err);
+            return None;
+        }
+    };
+
+    for dev in devices {
+        let properties = match dev.properties().await {
+            Ok(p_opt) => match p_opt {
+                Some(p) => p,
+                None => continue,
+            },
+            Err(_) => continue,
+        };
+
+        if bdaddr == properties.address {
+            return Some(dev.clone());
+        }
+    }
+
+    None
+}
+
+pub async fn characteristic_subscribe(
+    characteristic_uuid: &str,
+    device: &btleplug::platform::Peripheral,
+) -> Result<(), Error> {
+    let characteristic = match find_characteristic(characteristic_uuid, device) {
+        Some(c) => c,
+        None => {
+            return Err(Error::Ble(format!(
+                "Characteristic {} not found",
+                characteristic_uuid
+            )))
+        }
+    };
+
+    Ok(device.subscribe(&characteristic).await?)
+}
+
+pub fn find_characteristic(
+    characteristic_uuid: &str,
+    device: &btleplug::platform::Peripheral,
+) -> Option<Characteristic> {
+    device
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid.to_string() == characteristic_uuid)
+}
+
+// Reads the Device Information (0x180A) and Battery (0x180F) services and records them
+// with the instrument for the device. Warns when the battery runs low.
+// Returns the id of the instrument.
+pub async fn register_device(
+    mac: &str,
+    metric: &str,
+    device: &btleplug::platform::Peripheral,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Option<i64> {
+    let info = DeviceInfo {
+        mac: String::from(mac),
+        manufacturer: read_string(MANUFACTURER_NAME_UUID, device).await,
+        model: read_string(MODEL_NUMBER_UUID, device).await,
+        serial: read_string(SERIAL_NUMBER_UUID, device).await,
+        firmware: read_string(FIRMWARE_REVISION_UUID, device).await,
+        battery: read(BATTERY_LEVEL_UUID, device)
+            .await
+            .and_then(|value| value.first().copied()),
+    };
+
+    let instrument = match instruments::upsert_device(metric, &info, conn) {
+        Ok(instrument) => {
+            info!(
+                "Registered {} as instrument {} for {}",
+                mac, instrument, metric
+            );
+            Some(instrument)
+        }
+        Err(err) => {
+            error!("Failed to register instrument {} -> {}", mac, err);
+            None
+        }
+    };
+
+    let threshold = utils::from_config_or(conf, "ble", "battery_warn", "20");
+    let threshold = threshold.parse::<u8>().unwrap_or_else(|_| {
+        error!("Invalid battery_warn in section ble: {}", threshold);
+        20
+    });
+
+    match info.battery {
+        Some(battery) if battery <= threshold => {
+            warn!("Battery low on {}: {}%", mac, battery)
+        }
+        Some(battery) => info!("Battery level of {}: {}%", mac, battery),
+        None => info!("{} does not report its battery level", mac),
+    }
+
+    instrument
+}
+
+async fn read(
+    characteristic_uuid: &str,
+    device: &btleplug::platform::Peripheral,
+) -> Option<Vec<u8>> {
+    let characteristic = find_characteristic(characteristic_uuid, device)?;
+
+    match device.read(&characteristic).await {
+        Ok(value) => Some(value),
+        Err(err) => {
+            error!(
+                "Failed to read characteristic {} -> {}",
+                characteristic_uuid, err
+            );
+            None
+        }
+    }
+}
+
+async fn read_string(
+    characteristic_uuid: &str,
+    device: &btleplug::platform::Peripheral,
+) -> Option<String> {
+    let value = read(characteristic_uuid, device).await?;
+    let value = String::from_utf8_lossy(&value)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string();
+
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+// Date Time characteristic (0x2A08): year (u16), month, day, hours, minutes, seconds.
+// Devices report their local time, so it is interpreted in the local timezone.
+pub fn date_time(value: &[u8]) -> Option<i64> {
+    if value.len() < 7 {
+        return None;
+    }
+
+    let year = u16::from_le_bytes([value[0], value[1]]) as i32;
+    let date = NaiveDate::from_ymd_opt(year, value[2] as u32, value[3] as u32)?;
+    let datetime = date.and_hms_opt(value[4] as u32, value[5] as u32, value[6] as u32)?;
+
+    Local
+        .from_local_datetime(&datetime)
+        .earliest()
+        .map(|dt| dt.timestamp())
+}
+
+// IEEE 11073-20601 SFLOAT: 4 bit signed exponent, 12 bit signed mantissa.
+// NaN, NRes, +/-INFINITY and the reserved value are reported as None.
+pub fn sfloat(raw: u16) -> Option<f32> {
+    let mantissa = raw & 0x0FFF;
+    if (0x07FE..=0x0802).contains(&mantissa) {
+        return None;
+    }
+
+    let mantissa = ((mantissa << 4) as i16 >> 4) as f32;
+    let exponent = (raw as i16 >> 12) as i32;
+
+    Some(mantissa * 10f32.powi(exponent))
+}
+
+// IEEE 11073-20601 FLOAT: 8 bit signed exponent, 24 bit signed mantissa.
+// NaN, NRes, +/-INFINITY and the reserved value are reported as None.
+pub fn float(raw: u32) -> Option<f32> {
+    let mantissa = raw & 0x00FF_FFFF;
+    if (0x007F_FFFE..=0x0080_0002).contains(&mantissa) {
+        return None;
+    }
+
+    let mantissa = ((mantissa << 8) as i32 >> 8) as f32;
+    let exponent = raw as i32 >> 24;
+
+    Some(mantissa * 10f32.powi(exponent))
+}
@@ -0,0 +1,62 @@
+use std::sync::{Arc, RwLock};
+
+use chrono::Utc;
+use log::{error, info};
+use rusqlite::Connection;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{ble, writer::Sample, SectionedConfigMap};
+
+// Temperature Measurement, part of the Health Thermometer Service (0x1809)
+const TEMPERATURE_MEASUREMENT_UUID: &str = "00002a1c-0000-1000-8000-00805f9b34fb";
+
+pub async fn record_device(
+    mac: &str,
+    peripherals: &[btleplug::platform::Peripheral],
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+    samples: UnboundedSender<Sample>,
+) {
+    ble::record_characteristic(
+        mac,
+        "temperature",
+        TEMPERATURE_MEASUREMENT_UUID,
+        peripherals,
+        conf,
+        conn,
+        |value, instrument| {
+            let temperature = match parse_measurement(value) {
+                Some(temperature) => temperature,
+                None => {
+                    info!("Skipping measurement without valid temperature");
+                    return;
+                }
+            };
+
+            let sample = Sample::Temperature {
+                timestamp: Utc::now().timestamp(),
+                instrument,
+                temperature,
+            };
+            match samples.send(sample) {
+                Ok(_) => info!("Received temperature: {}°C from {}", temperature, mac),
+                Err(err) => error!("Failed to queue temperature data\n{}", err),
+            }
+        },
+    )
+    .await;
+}
+
+// Health Thermometer Profile v1.0, 3.1: Flags (u8), then the temperature as FLOAT in
+// Celsius, or in Fahrenheit if flag bit 0 is set. Time stamp and type may follow.
+fn parse_measurement(value: &[u8]) -> Option<f32> {
+    let flags = *value.first()?;
+    let raw = u32::from_le_bytes(value.get(1..5)?.try_into().ok()?);
+    let temperature = ble::float(raw)?;
+
+    if flags & 0x01 == 0 {
+        Some(temperature)
+    } else {
+        Some((temperature - 32.0) * 5.0 / 9.0)
+    }
+}
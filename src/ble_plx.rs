@@ -0,0 +1,66 @@
+use std::sync::{Arc, RwLock};
+
+use chrono::Utc;
+use log::{error, info};
+use rusqlite::Connection;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{ble, writer::Sample, SectionedConfigMap};
+
+// PLX Continuous Measurement, part of the Pulse Oximeter Service (0x1822)
+const CONTINUOUS_MEASUREMENT_UUID: &str = "00002a5f-0000-1000-8000-00805f9b34fb";
+
+pub async fn record_device(
+    mac: &str,
+    peripherals: &[btleplug::platform::Peripheral],
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+    samples: UnboundedSender<Sample>,
+) {
+    ble::record_characteristic(
+        mac,
+        "spo2",
+        CONTINUOUS_MEASUREMENT_UUID,
+        peripherals,
+        conf,
+        conn,
+        |value, instrument| {
+            let (spo2, pulse) = match parse_continuous_measurement(value) {
+                Some(reading) => reading,
+                None => {
+                    info!("Skipping measurement without valid SpO2 and pulse rate");
+                    return;
+                }
+            };
+
+            let sample = Sample::Spo2 {
+                timestamp: Utc::now().timestamp(),
+                instrument,
+                spo2,
+                pulse,
+            };
+            match samples.send(sample) {
+                Ok(_) => info!("Received SpO2: {}% at {}bpm from {}", spo2, pulse, mac),
+                Err(err) => error!("Failed to queue SpO2 data\n{}", err),
+            }
+        },
+    )
+    .await;
+}
+
+// PLX Profile v1.0.1, 3.2: Flags (u8), SpO2PR-Normal SpO2 (SFLOAT), SpO2PR-Normal PR (SFLOAT).
+// All optional fields follow these and are not needed.
+fn parse_continuous_measurement(value: &[u8]) -> Option<(u8, u8)> {
+    if value.len() < 5 {
+        return None;
+    }
+
+    let spo2 = ble::sfloat(u16::from_le_bytes([value[1], value[2]]))?;
+    let pulse = ble::sfloat(u16::from_le_bytes([value[3], value[4]]))?;
+
+    if !(0.0..=100.0).contains(&spo2) || !(0.0..=255.0).contains(&pulse) {
+        return None;
+    }
+
+    Some((spo2.round() as u8, pulse.round() as u8))
+}
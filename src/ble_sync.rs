@@ -0,0 +1,553 @@
+use futures::StreamExt;
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use btleplug::api::{Peripheral, WriteType};
+use chrono::Utc;
+use log::{error, info};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{args::Args, ble, bp, spo2, Error, SectionedConfigMap};
+
+const RACP_UUID: &str = "00002a52-0000-1000-8000-00805f9b34fb";
+
+// RACP op codes and operators, Glucose Profile v1.0, 4.12 and PLX Profile v1.0.1, 3.5
+const OP_REPORT_STORED_RECORDS: u8 = 0x01;
+const OP_RESPONSE_CODE: u8 = 0x06;
+const OPERATOR_NULL: u8 = 0x00;
+const OPERATOR_ALL_RECORDS: u8 = 0x01;
+const OPERATOR_GREATER_OR_EQUAL: u8 = 0x03;
+const FILTER_SEQUENCE_NUMBER: u8 = 0x01;
+
+const RESPONSE_SUCCESS: u8 = 0x01;
+const RESPONSE_INVALID_OPERATOR: u8 = 0x03;
+const RESPONSE_OPERATOR_NOT_SUPPORTED: u8 = 0x04;
+const RESPONSE_NO_RECORDS_FOUND: u8 = 0x06;
+const RESPONSE_OPERAND_NOT_SUPPORTED: u8 = 0x09;
+
+// Devices go quiet between records while reading them from flash, but not for this long
+const IDLE_TIMEOUT: u64 = 30;
+
+enum Record {
+    Bp { timestamp: i64, sys: i64, dia: i64 },
+    Spo2 { timestamp: i64, spo2: u8, pulse: u8 },
+}
+
+struct SyncTarget {
+    metric: &'static str,
+    profile: &'static str,
+    measurement_uuid: &'static str,
+    parse: fn(&[u8]) -> Option<Record>,
+    // Wraps stored measurements together with their sequence number, if the profile has one
+    record_uuid: Option<&'static str>,
+}
+
+const TARGETS: [SyncTarget; 2] = [
+    SyncTarget {
+        metric: "bp",
+        profile: "bls",
+        // Blood Pressure Measurement, part of the Blood Pressure Service (0x1810)
+        measurement_uuid: "00002a35-0000-1000-8000-00805f9b34fb",
+        parse: parse_bp_measurement,
+        // Blood Pressure Record, Blood Pressure Service v1.1
+        record_uuid: Some("00002b36-0000-1000-8000-00805f9b34fb"),
+    },
+    SyncTarget {
+        metric: "spo2",
+        profile: "plx",
+        // PLX Spot-check Measurement, part of the Pulse Oximeter Service (0x1822)
+        measurement_uuid: "00002a5e-0000-1000-8000-00805f9b34fb",
+        parse: parse_spot_check_measurement,
+        record_uuid: None,
+    },
+];
+
+pub fn tables(conn: &Connection) {
+    let _ = conn
+        .execute(
+            "CREATE TABLE IF NOT EXISTS ble_sync (
+                    id          INTEGER PRIMARY KEY,
+                    mac         TEXT NOT NULL,
+                    metric      TEXT NOT NULL,
+                    sequence    INTEGER NOT NULL,
+                    synced      INTEGER NOT NULL,
+                    UNIQUE (mac, metric)
+                );",
+            [],
+        )
+        .map_err(|err| error!("Failed to ensure table 'ble_sync' exists -> {}", err));
+}
+
+pub async fn sync(
+    input: &mut Args<'_>,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let metric = match input.next() {
+        Some(metric) => metric,
+        None => return Err(Error::Parse(String::from("Missing parameter: metric"))),
+    };
+
+    let target = match TARGETS.iter().find(|target| target.metric == metric) {
+        Some(target) => target,
+        None => {
+            return Err(Error::Parse(format!(
+                "Syncing is not supported for {}",
+                metric
+            )))
+        }
+    };
+
+    // Ignores the cursor, e.g. after the device memory was cleared
+    let all = match input.next() {
+        Some("all") => true,
+        Some(param) => return Err(Error::Parse(format!("Unknown parameter: {}", param))),
+        None => false,
+    };
+
+    let devices: Vec<ble::Device> = ble::devices(conf.clone())
+        .into_iter()
+        .filter(|device| device.profile == target.profile)
+        .collect();
+
+    if devices.is_empty() {
+        return Err(Error::Config(format!(
+            "No {} device configured for {}. See 'devices' in section ble",
+            target.profile, target.metric
+        )));
+    }
+
+    let peripherals = ble::scan().await?;
+
+    // Every device is synced even if another one fails
+    let mut output = Vec::new();
+    let mut failures = Vec::new();
+    for device in devices {
+        match sync_device(target, &device.mac, all, &peripherals, conf.clone(), conn).await {
+            Ok(synced) => output.push(synced),
+            Err(err) => failures.push((device.mac, err)),
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(output.join("\n"))
+    } else {
+        output.push(format!("{} devices failed to sync", failures.len()));
+        Err(Error::Batch(failures, output.join("\n")))
+    }
+}
+
+async fn sync_device(
+    target: &SyncTarget,
+    mac: &str,
+    all: bool,
+    peripherals: &[btleplug::platform::Peripheral],
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let cursor = match all {
+        true => None,
+        false => {
+            cursor(mac, target.metric, conn).map_err(Error::database("read the sync cursor"))?
+        }
+    };
+
+    let device = match ble::connect(mac, peripherals).await {
+        Some(device) => device,
+        None => {
+            return Err(Error::Ble(String::from(
+                "Failed to connect, is the device on and in range?",
+            )))
+        }
+    };
+
+    let instrument = ble::register_device(mac, target.metric, &device, conf, conn)
+        .await
+        .unwrap_or(0);
+
+    let records = download(target, cursor, &device).await;
+
+    match device.disconnect().await {
+        Ok(_) => info!("Disconnected from {}", mac),
+        Err(err) => error!("Failed to disconnected from device {}\n{}", mac, err),
+    };
+
+    let (records, sequence) = records?;
+    // Keep the cursor of a previous sync if the device no longer numbers its records
+    let sequence = sequence.max(cursor);
+
+    let inserted = store(mac, target.metric, &records, sequence, instrument, conn)
+        .map_err(Error::database("store synced records"))?;
+    Ok(format!(
+        "Received {} records from {}, recorded {} new {} readings",
+        records.len(),
+        mac,
+        inserted,
+        target.metric
+    ))
+}
+
+fn cursor(mac: &str, metric: &str, conn: &Connection) -> Result<Option<u16>, rusqlite::Error> {
+    conn.query_row(
+        "SELECT sequence FROM ble_sync WHERE mac = ?1 AND metric = ?2;",
+        params![mac, metric],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// The RACP request for the records after the cursor, the highest sequence number synced
+fn request(cursor: Option<u16>) -> Vec<u8> {
+    match cursor.and_then(|cursor| cursor.checked_add(1)) {
+        Some(next) => {
+            let [lo, hi] = next.to_le_bytes();
+            vec![
+                OP_REPORT_STORED_RECORDS,
+                OPERATOR_GREATER_OR_EQUAL,
+                FILTER_SEQUENCE_NUMBER,
+                lo,
+                hi,
+            ]
+        }
+        // After u16::MAX the device would have to wrap, so ask for everything
+        None => vec![OP_REPORT_STORED_RECORDS, OPERATOR_ALL_RECORDS],
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Stored<'a> {
+    Measurement(&'a [u8]),
+    // Split over several notifications, which is not supported
+    Segmented,
+    // Of another characteristic, like the Enhanced Blood Pressure Measurement
+    Other,
+}
+
+// Blood Pressure Service v1.1, 3.4: Segmentation Header (u8), Sequence Number (u16), UUID
+// (u16) of the recorded characteristic, then its value and an optional E2E-CRC
+fn parse_stored_record(value: &[u8], measurement_uuid: u16) -> Option<(u16, Stored<'_>)> {
+    let (header, rest) = value.split_first()?;
+    let sequence = u16::from_le_bytes([*rest.first()?, *rest.get(1)?]);
+    let uuid = u16::from_le_bytes([*rest.get(2)?, *rest.get(3)?]);
+    // Bit 0 marks the first and bit 1 the last segment
+    let stored = match (header & 0x03 == 0x03, uuid == measurement_uuid) {
+        (false, _) => Stored::Segmented,
+        (true, true) => Stored::Measurement(&rest[4..]),
+        (true, false) => Stored::Other,
+    };
+    Some((sequence, stored))
+}
+
+// The highest sequence number synced: the highest received, but short of the first record
+// that could not be read, so the next sync asks for it again
+fn synced(received: Option<u16>, unread: Option<u16>) -> Option<u16> {
+    match unread {
+        Some(unread) => received.min(unread.checked_sub(1)),
+        None => received,
+    }
+}
+
+// Returns the received records and the highest sequence number among them, if the device
+// numbers them
+async fn download(
+    target: &SyncTarget,
+    cursor: Option<u16>,
+    device: &btleplug::platform::Peripheral,
+) -> Result<(Vec<Record>, Option<u16>), Error> {
+    let racp = ble::find_characteristic(RACP_UUID, device).ok_or_else(|| {
+        Error::Ble(String::from(
+            "Device does not expose a Record Access Control Point",
+        ))
+    })?;
+
+    // Stored measurements come wrapped in records with a sequence number if the device
+    // has the characteristic for them, otherwise as plain measurements
+    let record_uuid = target
+        .record_uuid
+        .filter(|uuid| ble::find_characteristic(uuid, device).is_some());
+    ble::characteristic_subscribe(record_uuid.unwrap_or(target.measurement_uuid), device).await?;
+    ble::characteristic_subscribe(RACP_UUID, device).await?;
+    // The short UUID, like 0x2a35
+    let measurement_uuid = u16::from_str_radix(&target.measurement_uuid[4..8], 16).unwrap_or(0);
+
+    let mut notification_stream = device.notifications().await?;
+
+    // Try to only fetch records newer than the cursor first. Not every device supports
+    // filtering by sequence number, so fall back to all records and rely on the
+    // timestamp uniqueness to skip what is already recorded.
+    let mut filtered = record_uuid.is_some() && cursor.is_some();
+    loop {
+        let request = request(cursor.filter(|_| filtered));
+
+        info!("Requesting stored records {:?}", request);
+        device
+            .write(&racp, &request, WriteType::WithResponse)
+            .await
+            .map_err(|err| Error::Ble(format!("Failed to write to RACP: {}", err)))?;
+
+        let mut records = Vec::new();
+        let (mut received, mut unread) = (None, None);
+        let response = loop {
+            let data = tokio::time::timeout(
+                Duration::from_secs(IDLE_TIMEOUT),
+                notification_stream.as_mut().next(),
+            )
+            .await
+            .map_err(|_| Error::Ble(String::from("Timed out waiting for device")))?
+            .ok_or_else(|| Error::Ble(String::from("Device closed the notification stream")))?;
+
+            if data.uuid.to_string() == RACP_UUID {
+                match data.value.as_slice() {
+                    [OP_RESPONSE_CODE, OPERATOR_NULL, OP_REPORT_STORED_RECORDS, code, ..] => {
+                        break *code
+                    }
+                    _ => continue,
+                }
+            }
+
+            let measurement = match record_uuid {
+                Some(uuid) if data.uuid.to_string() == uuid => {
+                    match parse_stored_record(&data.value, measurement_uuid) {
+                        Some((sequence, Stored::Segmented)) => {
+                            info!("Skipping segmented record {}", sequence);
+                            unread =
+                                Some(unread.map_or(sequence, |unread: u16| unread.min(sequence)));
+                            continue;
+                        }
+                        Some((sequence, Stored::Other)) => {
+                            info!("Skipping record {} of another characteristic", sequence);
+                            received = received.max(Some(sequence));
+                            continue;
+                        }
+                        // Also if it has no valid timestamp, it would not parse next time
+                        Some((sequence, Stored::Measurement(measurement))) => {
+                            received = received.max(Some(sequence));
+                            measurement
+                        }
+                        None => continue,
+                    }
+                }
+                None if data.uuid.to_string() == target.measurement_uuid => &data.value[..],
+                _ => continue,
+            };
+            match (target.parse)(measurement) {
+                Some(record) => records.push(record),
+                None => info!("Skipping record without valid timestamp {:?}", data.value),
+            }
+        };
+
+        match response {
+            RESPONSE_SUCCESS | RESPONSE_NO_RECORDS_FOUND => {
+                return Ok((records, synced(received, unread)))
+            }
+            RESPONSE_OPERATOR_NOT_SUPPORTED
+            | RESPONSE_INVALID_OPERATOR
+            | RESPONSE_OPERAND_NOT_SUPPORTED
+                if filtered =>
+            {
+                info!("Device does not support filtering, requesting all records");
+                filtered = false;
+            }
+            code => {
+                return Err(Error::Ble(format!(
+                    "Device responded with RACP code {:#04x}",
+                    code
+                )))
+            }
+        }
+    }
+}
+
+fn store(
+    mac: &str,
+    metric: &str,
+    records: &[Record],
+    sequence: Option<u16>,
+    instrument: i64,
+    conn: &Connection,
+) -> Result<usize, rusqlite::Error> {
+    let tx = conn.unchecked_transaction()?;
+
+    let mut inserted = 0;
+    for record in records {
+        inserted += match *record {
+            Record::Bp {
+                timestamp,
+                sys,
+                dia,
+            } => bp::import_bp(sys, dia, timestamp, &tx)?,
+            Record::Spo2 {
+                timestamp,
+                spo2,
+                pulse,
+            } => spo2::import_spo2(spo2, pulse, timestamp, instrument, &tx)?,
+        };
+    }
+
+    let now = Utc::now().timestamp();
+    match sequence {
+        Some(sequence) => tx.execute(
+            "INSERT INTO ble_sync (mac, metric, sequence, synced) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (mac, metric) DO UPDATE SET sequence = excluded.sequence, synced = excluded.synced;",
+            params![mac, metric, sequence, now],
+        )?,
+        // Without sequence numbers every sync downloads all records
+        None => tx.execute(
+            "UPDATE ble_sync SET synced = ?1 WHERE mac = ?2 AND metric = ?3;",
+            params![now, mac, metric],
+        )?,
+    };
+
+    tx.commit()?;
+    Ok(inserted)
+}
+
+// Blood Pressure Profile v1.1.1, 3.1: Flags (u8), systolic, diastolic and mean arterial
+// pressure (SFLOAT each), then the optional Time Stamp if flag bit 1 is set.
+fn parse_bp_measurement(value: &[u8]) -> Option<Record> {
+    let flags = *value.first()?;
+    if flags & 0x02 == 0 || value.len() < 14 {
+        return None;
+    }
+
+    let sys = ble::sfloat(u16::from_le_bytes([value[1], value[2]]))?;
+    let dia = ble::sfloat(u16::from_le_bytes([value[3], value[4]]))?;
+    let timestamp = ble::date_time(&value[7..14])?;
+
+    // Flag bit 0 selects kPa instead of mmHg
+    let (sys, dia) = if flags & 0x01 == 0 {
+        (sys, dia)
+    } else {
+        (sys * 7.50062, dia * 7.50062)
+    };
+
+    Some(Record::Bp {
+        timestamp,
+        sys: sys.round() as i64,
+        dia: dia.round() as i64,
+    })
+}
+
+// PLX Profile v1.0.1, 3.1: Flags (u8), SpO2 and PR (SFLOAT each), then the optional
+// Timestamp if flag bit 0 is set. Flag bit 4 marks readings taken with an unset clock.
+fn parse_spot_check_measurement(value: &[u8]) -> Option<Record> {
+    let flags = *value.first()?;
+    if flags & 0x01 == 0 || flags & 0x10 != 0 || value.len() < 12 {
+        return None;
+    }
+
+    let spo2 = ble::sfloat(u16::from_le_bytes([value[1], value[2]]))?;
+    let pulse = ble::sfloat(u16::from_le_bytes([value[3], value[4]]))?;
+    let timestamp = ble::date_time(&value[5..12])?;
+
+    if !(0.0..=100.0).contains(&spo2) || !(0.0..=255.0).contains(&pulse) {
+        return None;
+    }
+
+    Some(Record::Spo2 {
+        timestamp,
+        spo2: spo2.round() as u8,
+        pulse: pulse.round() as u8,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_starts_after_the_cursor() {
+        assert_eq!(
+            request(Some(0)),
+            [
+                OP_REPORT_STORED_RECORDS,
+                OPERATOR_GREATER_OR_EQUAL,
+                FILTER_SEQUENCE_NUMBER,
+                1,
+                0
+            ]
+        );
+        assert_eq!(
+            request(Some(0x01ff)),
+            [
+                OP_REPORT_STORED_RECORDS,
+                OPERATOR_GREATER_OR_EQUAL,
+                FILTER_SEQUENCE_NUMBER,
+                0,
+                2
+            ]
+        );
+        assert_eq!(
+            request(None),
+            [OP_REPORT_STORED_RECORDS, OPERATOR_ALL_RECORDS]
+        );
+        assert_eq!(
+            request(Some(u16::MAX)),
+            [OP_REPORT_STORED_RECORDS, OPERATOR_ALL_RECORDS]
+        );
+    }
+
+    #[test]
+    fn stored_record_carries_its_sequence_number() {
+        let measurement = [
+            0x02, 0x78, 0x00, 0x50, 0x00, 0x5d, 0x00, 0xe8, 0x07, 3, 1, 8, 30, 0,
+        ];
+        let mut value = vec![0x03, 0x2a, 0x01, 0x35, 0x2a];
+        value.extend_from_slice(&measurement);
+
+        let (sequence, stored) = parse_stored_record(&value, 0x2a35).unwrap();
+        assert_eq!(sequence, 0x012a);
+        assert_eq!(stored, Stored::Measurement(&measurement[..]));
+        assert!(matches!(
+            parse_bp_measurement(&measurement),
+            Some(Record::Bp {
+                sys: 120,
+                dia: 80,
+                ..
+            })
+        ));
+
+        value[0] = 0x01;
+        assert_eq!(
+            parse_stored_record(&value, 0x2a35),
+            Some((0x012a, Stored::Segmented))
+        );
+        value[0] = 0x03;
+        assert_eq!(
+            parse_stored_record(&value, 0x2b34),
+            Some((0x012a, Stored::Other))
+        );
+        assert_eq!(parse_stored_record(&value[..2], 0x2a35), None);
+    }
+
+    #[test]
+    fn cursor_stops_before_unread_records() {
+        assert_eq!(synced(Some(9), None), Some(9));
+        assert_eq!(synced(Some(9), Some(4)), Some(3));
+        assert_eq!(synced(Some(3), Some(9)), Some(3));
+        // Nothing is synced before record 0
+        assert_eq!(synced(Some(9), Some(0)), None);
+        assert_eq!(synced(None, None), None);
+    }
+
+    #[test]
+    fn cursor_is_the_highest_sequence_number_stored() {
+        let database = crate::Store::open(":memory:").unwrap();
+        let conn = database.connection();
+        let mac = "00:11:22:33:44:55";
+
+        // Numbered from 0, so record 0 is synced and the next request starts at 1
+        store(mac, "bp", &[], Some(0), 0, conn).unwrap();
+        assert_eq!(cursor(mac, "bp", conn).unwrap(), Some(0));
+        store(mac, "bp", &[], Some(7), 0, conn).unwrap();
+        assert_eq!(cursor(mac, "bp", conn).unwrap(), Some(7));
+        assert_eq!(request(Some(7))[3..], [8, 0]);
+
+        // A sync without sequence numbers keeps the cursor
+        store(mac, "bp", &[], None, 0, conn).unwrap();
+        assert_eq!(cursor(mac, "bp", conn).unwrap(), Some(7));
+        assert_eq!(cursor(mac, "spo2", conn).unwrap(), None);
+    }
+}
@@ -1,16 +1,52 @@
-use std::str::SplitWhitespace;
-
-use chrono::Utc;
 use log::error;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, Params, Row};
+
+use crate::{
+    args::Args,
+    output::{Field, Output, Record, Value},
+    utils, Error, Stat,
+};
+
+pub struct BloodpressureORM {
+    pub id: i64,
+    pub timestamp: i64,
+    // mmHg
+    pub sys: i16,
+    pub dia: i16,
+}
 
-use crate::{utils, Stat};
+impl BloodpressureORM {
+    fn read(row: &Row) -> Result<BloodpressureORM, rusqlite::Error> {
+        Ok(BloodpressureORM {
+            id: row.get(0)?,
+            timestamp: row.get(1)?,
+            sys: row.get(2)?,
+            dia: row.get(3)?,
+        })
+    }
 
-struct BloodpressureORM {
-    _id: i64,
-    timestamp: i64,
-    sys: i16,
-    dia: i16,
+    pub fn record(self) -> Record {
+        Record {
+            id: self.id,
+            timestamp: self.timestamp,
+            duration: None,
+            source: None,
+            fields: vec![
+                Field {
+                    name: "sys",
+                    value: Value::Integer(self.sys as i64),
+                    unit: "mmHg",
+                    label: "{} systolic",
+                },
+                Field {
+                    name: "dia",
+                    value: Value::Integer(self.dia as i64),
+                    unit: "mmHg",
+                    label: "{} diastolic",
+                },
+            ],
+        }
+    }
 }
 
 pub struct BP;
@@ -30,109 +66,138 @@ impl Stat for BP {
             .map_err(|err| error!("Failed to ensure table 'bp' exists -> {}", err));
     }
 
-    fn command(input: &mut SplitWhitespace, conn: &Connection) -> String {
+    fn command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
         let param = match input.next() {
             Some(param) => param,
-            None => return String::from("No further parameters"),
+            None => return Err(Error::Parse(String::from("No further parameters"))),
         };
 
         match param {
-            "last" => last(input, conn),
+            "last" => last_command(input, conn),
+            "range" => range_command(input, conn),
             _ => {
-                let sys = match param.parse::<i64>() {
-                    Ok(sys) => sys,
-                    Err(e) => return format!("Failed to parse parameter: <sys:i16>\n{}", e),
-                };
-
-                let dia = match input.next() {
-                    Some(dia) => dia,
-                    None => return String::from("Missing parameter: dia"),
-                };
-                let dia = match dia.parse::<i64>() {
-                    Ok(dia) => dia,
-                    Err(e) => return format!("Failed to parse parameter: <dia:i16>\n{}", e),
-                };
-
-                let timestamp = Utc::now().timestamp();
-                match conn.execute(
-                    "INSERT INTO bp (timestamp, sys, dia) VALUES (?1, ?2, ?3);",
-                    params![timestamp, sys, dia],
-                ) {
-                    Ok(_) => format!("Recorded bp: {}mmHg systolic, {}mmHg diastolic", sys, dia),
-                    Err(err) => {
-                        error!("Failed to write bp to database -> {}", err);
-                        String::from("Failed to write bp to database. Check log for full error.")
-                    }
-                }
+                let sys = param.parse::<i16>().map_err(|err| {
+                    Error::Parse(format!("Failed to parse parameter: <sys:i16> {}", err))
+                })?;
+
+                let dia = input
+                    .next()
+                    .ok_or_else(|| Error::Parse(String::from("Missing parameter: dia")))?;
+                let dia = dia.parse::<i16>().map_err(|err| {
+                    Error::Parse(format!("Failed to parse parameter: <dia:i16> {}", err))
+                })?;
+
+                let entry = insert(utils::entry_time(), sys, dia, conn)
+                    .map_err(Error::database("write bp"))?;
+                Ok(Output::Recorded {
+                    metric: "bp",
+                    record: entry.record(),
+                })
             }
         }
     }
 
     fn help() -> String {
-        String::from("\tbp <last <count:i64>> | <<sys:i16> <dia:i16>>\n")
+        String::from("\tbp <last <count:i64> | range <from> <to>> | <<sys:i16> <dia:i16>>\n")
     }
 }
 
-fn last(input: &mut SplitWhitespace, conn: &Connection) -> String {
-    let mut output = String::new();
+pub fn insert(
+    timestamp: i64,
+    sys: i16,
+    dia: i16,
+    conn: &Connection,
+) -> Result<BloodpressureORM, rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO bp (timestamp, sys, dia) VALUES (?1, ?2, ?3);",
+        params![timestamp, sys, dia],
+    )?;
+
+    Ok(BloodpressureORM {
+        id: conn.last_insert_rowid(),
+        timestamp,
+        sys,
+        dia,
+    })
+}
+
+// Inserts a reading taken at a known time, such as one stored on a device.
+// Returns 0 if a reading with the same timestamp is already recorded.
+pub fn import_bp(
+    sys: i64,
+    dia: i64,
+    timestamp: i64,
+    conn: &Connection,
+) -> Result<usize, rusqlite::Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO bp (timestamp, sys, dia) VALUES (?1, ?2, ?3);",
+        params![timestamp, sys, dia],
+    )
+}
+
+// The latest count entries, newest first
+pub fn last(count: i64, conn: &Connection) -> Result<Vec<BloodpressureORM>, rusqlite::Error> {
+    query("ORDER BY timestamp DESC LIMIT (?1)", [count], conn)
+}
+
+// Entries from from up to but excluding to, oldest first
+pub fn range(
+    from: i64,
+    to: i64,
+    conn: &Connection,
+) -> Result<Vec<BloodpressureORM>, rusqlite::Error> {
+    query(
+        "WHERE timestamp >= ?1 AND timestamp < ?2 ORDER BY timestamp ASC",
+        [from, to],
+        conn,
+    )
+}
+
+fn query<P: Params>(
+    clause: &str,
+    params: P,
+    conn: &Connection,
+) -> Result<Vec<BloodpressureORM>, rusqlite::Error> {
+    let mut query = conn.prepare(&format!(
+        "SELECT id, timestamp, sys, dia FROM bp {};",
+        clause
+    ))?;
+    let entries = query.query_map(params, BloodpressureORM::read)?.collect();
+    entries
+}
+
+fn last_command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+    let mut notes = Vec::new();
 
     let take_default = 3;
     let take = match input.next() {
         Some(take) => take.parse::<i64>().unwrap_or_else(|_| {
-            output.push_str(&format!(
-                "Failed to parse query parameter\nUsing default query parameter {}\n",
-                take_default
-            ));
+            notes.push(String::from("Failed to parse query parameter"));
+            notes.push(format!("Using default query parameter {}", take_default));
             take_default
         }),
         None => {
-            output.push_str(&format!("Using default query parameter {}\n", take_default));
+            notes.push(format!("Using default query parameter {}", take_default));
             take_default
         }
     };
 
-    let mut query = match conn.prepare(
-        "
-        SELECT id, timestamp, sys, dia
-        FROM bp
-        ORDER BY timestamp DESC
-        LIMIT (?1);
-    ",
-    ) {
-        Ok(query) => query,
-        Err(err) => {
-            error!("Failed to prepare query 'last' for bp -> {}", err);
-            output.push_str("Failed to prepare query 'last' for bp. Check log for full error.");
-            return output;
-        }
-    };
+    let entries = last(take, conn).map_err(Error::database(format!(
+        "read the last {} bp entries",
+        take
+    )))?;
+    Ok(Output::Records {
+        notes,
+        records: entries.into_iter().map(BloodpressureORM::record).collect(),
+    })
+}
 
-    let results = query.query_map([take], |row| {
-        Ok(BloodpressureORM {
-            _id: row.get(0)?,
-            timestamp: row.get(1)?,
-            sys: row.get(2)?,
-            dia: row.get(3)?,
-        })
-    });
-
-    match results {
-        Ok(results) => {
-            for result in results {
-                let result = result.unwrap();
-                output.push_str(&format!(
-                    "{}mmHg systolic, {}mmHg diastolic, recorded {}\n",
-                    result.sys,
-                    result.dia,
-                    utils::format_timestamp(result.timestamp)
-                ));
-            }
-        }
-        Err(err) => output.push_str(&format!(
-            "Failed to retrieve last {} entries: {}\n",
-            take, err
-        )),
-    }
+fn range_command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+    let (from, to) = utils::parse_range(input)?;
 
-    output
+    let entries = range(from, to, conn).map_err(Error::database("read bp entries"))?;
+    Ok(Output::Records {
+        notes: Vec::new(),
+        records: entries.into_iter().map(BloodpressureORM::record).collect(),
+    })
 }
@@ -0,0 +1,286 @@
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+};
+
+use chrono::{TimeZone, Utc};
+use log::{error, info};
+use rusqlite::Connection;
+
+use crate::{
+    alerts,
+    args::Args,
+    batch, ble,
+    bp::BP,
+    heartrate::Heartrate,
+    http, instruments,
+    mood::Mood,
+    mqtt,
+    output::Output,
+    retention, schedules,
+    spo2::Spo2,
+    store::{self, Store},
+    temperature::Temperature,
+    tui, webhooks,
+    weight::{self, Weight},
+    Error, SectionedConfigMap, Stat,
+};
+
+// Runs one command, including batch files, which dispatch their lines themselves
+pub async fn execute(
+    command: &str,
+    input: &mut Args<'_>,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<Output, Error> {
+    let result = match command {
+        "source" => batch::source(input, conf, conn).await,
+        _ => dispatch(command, input, conf, conn).await,
+    };
+    if let Err(err) = &result {
+        error!("Command '{}' failed -> {}", command, err);
+    }
+    result
+}
+
+// Runs one command. Returns its output, or why it failed.
+pub async fn dispatch(
+    command: &str,
+    input: &mut Args<'_>,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<Output, Error> {
+    match command {
+        "help" => Ok(Output::message(help())),
+        "weight" => Weight::command(input, conn),
+        "bp" => BP::command(input, conn),
+        "mood" => Mood::command(input, conn),
+        "heartrate" => Heartrate::command(input, conn),
+        "temp" => Temperature::command(input, conn),
+        "spo2" => Spo2::command(input, conn),
+        "record" => ble::record(input.next(), conf, conn)
+            .await
+            .map(Output::message),
+        "record_hrp" => ble::record(Some("hrp"), conf, conn)
+            .await
+            .map(Output::message),
+        "record_plx" => ble::record(Some("plx"), conf, conn)
+            .await
+            .map(Output::message),
+        "ble" => ble::command(input, conf, conn).await.map(Output::message),
+        "instruments" => instruments::list(conn),
+        "serve" => http::serve(false, conf, conn).await.map(Output::message),
+        "dashboard" => http::serve(true, conf, conn).await.map(Output::message),
+        "tui" => tui::run(conf, conn).await.map(Output::message),
+        "subscribe" => mqtt::subscribe(conf, conn).await.map(Output::message),
+        "webhooks" => webhooks::command(input, conf, conn),
+        "alerts" => alerts::command(input, conn),
+        "adherence" => schedules::command(input, conf, conn),
+        "maintain" => retention::maintain(conf, conn).map(Output::message),
+        "ingest_markdown_weight" => ingest_markdown_weight(input, conn).map(Output::message),
+        "backup" => backup(input, conn).map(Output::message),
+        "restore" => restore(input).map(Output::message),
+        "upgrade_tables" => upgrade_tables(input, conn).map(Output::message),
+        "" => Err(Error::Parse(String::from(
+            "Missing command. Enter 'help' to see help",
+        ))),
+        _ => Err(Error::Parse(format!(
+            "Unknown command: {}. Enter 'help' to see help",
+            command
+        ))),
+    }
+}
+
+pub fn help() -> String {
+    let mut help = String::new();
+    help.push_str("List of commands:\n");
+    help.push_str(&Weight::help());
+    help.push_str(&BP::help());
+    help.push_str(&Mood::help());
+    help.push_str(&Heartrate::help());
+    help.push_str(&Temperature::help());
+    help.push_str(&Spo2::help());
+    help.push_str(
+        "\trecord <hrp | plx | htp> - Connects to all configured BLE devices, or those of one profile, and collects their data\n",
+    );
+    help.push_str(
+        "\trecord_hrp - Connects to BLE HRP compatible devices and collects heartrate data\n",
+    );
+    help.push_str(
+        "\trecord_plx - Connects to BLE PLX compatible devices and collects SpO2 and pulse data\n",
+    );
+    help.push_str(
+        "\tble sync <bp | spo2> [all] - Downloads readings stored on a BLE device since the last sync, or all of them\n",
+    );
+    help.push_str(
+        "\tsubscribe - Stores readings published to the topics of subscribe in section mqtt until Ctrl-C\n",
+    );
+    help.push_str(
+        "\twebhooks [<count:i64>] - Lists the latest webhook deliveries for readings crossing the thresholds in section webhooks\n",
+    );
+    help.push_str(
+        "\talerts [new | last [<count:i64>] | ack <id:i64>] - Lists the unacknowledged alerts raised by the rules in section alerts, only those not shown yet, or the latest ones, or acknowledges one\n",
+    );
+    help.push_str(
+        "\tadherence [due | <weeks:i64>] - Reports the readings recorded as scheduled in section schedules per week with streaks, default: 4 weeks, at most 520, or lists those missing today\n",
+    );
+    help.push_str(
+        "\tmaintain - Rolls up aged heartrate, temperature and SpO2 samples as configured in section retention\n",
+    );
+    help.push_str("\tinstruments - Lists the registered instruments\n");
+    help.push_str("\tserve - Serves the HTTP API configured in section http until Ctrl-C\n");
+    help.push_str(
+        "\tdashboard - Serves the API together with a web dashboard of all metrics until Ctrl-C\n",
+    );
+    help.push_str(
+        "\ttui - Full screen view of latest readings, 30 day trends and live heart rate, with keys to log readings\n",
+    );
+    #[cfg(unix)]
+    help.push_str(
+        "\tdaemon - Keeps the database open for other biomon calls on the socket in section daemon until Ctrl-C; record there runs in the background until 'record stop'\n",
+    );
+    help.push_str("\toutput <human | json> - Prints results as text or as JSON records\n");
+    help.push_str(
+        "\tsource <file_path:str> [stop | continue] - Runs the commands in a file in one transaction, stopping and rolling back at the first error by default\n",
+    );
+    help.push_str("\tingest_markdown_weight <file_path:str>\n");
+    help.push_str("\tbackup <backup_path:str> - default: ./biomon.sqlite.bak\n");
+    help.push_str("\testore <backup_path:str> - default: ./biomon.sqlite.bak\n");
+    help.push_str("\tupgrade_tables <file_path:str>\n");
+    help.push_str("\tq -> exit");
+
+    help
+}
+
+fn upgrade_tables(input: &mut Args, conn: &Connection) -> Result<String, Error> {
+    let file = input
+        .next()
+        .ok_or_else(|| Error::Parse(String::from("Missing migration script path")))?;
+
+    let contents =
+        fs::read_to_string(file).map_err(Error::io(format!("read migration script {}", file)))?;
+
+    // Remove comments
+    let contents = contents
+        .lines()
+        .filter(|&line| !line.starts_with("--"))
+        .fold(String::new(), |mut acc, line| {
+            acc.push_str(line);
+            acc.push('\n');
+            acc
+        });
+
+    conn.execute_batch(&contents)
+        .map_err(Error::database(format!("run migration script {}", file)))?;
+    info!("Database migrated");
+    Ok(String::from("Database migrated"))
+}
+
+fn backup(input: &mut Args, conn: &Connection) -> Result<String, Error> {
+    let mut output = String::new();
+
+    let path = match input.next() {
+        Some(param) => param,
+        None => {
+            output.push_str("Using default destination path ./biomon.sqlite.bak\n");
+            "biomon.sqlite.bak"
+        }
+    };
+
+    store::backup(conn, path)?;
+    output.push_str("Backup done\n");
+
+    Ok(output)
+}
+
+fn restore(input: &mut Args) -> Result<String, Error> {
+    let mut output = String::new();
+
+    let path = match input.next() {
+        Some(param) => param,
+        None => {
+            output.push_str("Using default source path ./biomon.sqlite.bak\n");
+            "biomon.sqlite.bak"
+        }
+    };
+
+    Store::restore(path, "biomon.sqlite")?;
+    output.push_str("Restore done\n");
+
+    Ok(output)
+}
+
+fn ingest_markdown_weight(input: &mut Args, conn: &Connection) -> Result<String, Error> {
+    let file = input
+        .next()
+        .ok_or_else(|| Error::Parse(String::from("Missing parameter: file_path")))?;
+
+    let contents =
+        fs::read_to_string(file).map_err(Error::io(format!("read weight file {}", file)))?;
+
+    let mut output = Vec::new();
+    let mut failures = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        // - 2023-12-03: 105.4kg
+        let line = line.replace("- ", "");
+        // 2023-12-03: 105.4kg
+        let mut parts = line.split(':');
+        // ["2023-12-03", " 105.4kg"]
+        let date = match parts.next() {
+            Some(date) => date,
+            None => continue,
+        };
+        let mut date_parts = date.split('-');
+        let year = match date_parts.next().and_then(|year| year.parse::<i32>().ok()) {
+            Some(year) => year,
+            None => continue,
+        };
+        let month = match date_parts
+            .next()
+            .and_then(|month| month.parse::<u32>().ok())
+        {
+            Some(month) => month,
+            None => continue,
+        };
+        let day = match date_parts.next().and_then(|day| day.parse::<u32>().ok()) {
+            Some(day) => day,
+            None => continue,
+        };
+        // assume measurements were taken at 09:00
+        let timestamp = match Utc.with_ymd_and_hms(year, month, day, 9, 0, 0).single() {
+            Some(datetime) => datetime.timestamp(),
+            None => {
+                failures.push((
+                    format!("line {}", index + 1),
+                    Error::Parse(format!("Invalid date: {}", date)),
+                ));
+                continue;
+            }
+        };
+
+        let weight = match parts.next() {
+            Some(weight) => weight.trim().replace("kg", ""),
+            None => continue,
+        };
+
+        let weight = match weight.parse::<f64>() {
+            Ok(weight) => weight,
+            Err(_) => continue,
+        };
+
+        match weight::insert(timestamp, weight, conn) {
+            Ok(_) => output.push(format!("Recorded weight: {}kg", weight)),
+            Err(err) => failures.push((
+                format!("line {}", index + 1),
+                Error::Database(String::from("write weight"), err),
+            )),
+        };
+    }
+
+    if failures.is_empty() {
+        Ok(output.join("\n"))
+    } else {
+        output.push(format!("{} lines failed", failures.len()));
+        Err(Error::Batch(failures, output.join("\n")))
+    }
+}
@@ -0,0 +1,267 @@
+use log::info;
+
+use crate::{args::Args, Error};
+
+// Values with several components, like SpO2 and pulse, are merged component-wise
+pub struct Sample {
+    pub id: i64,
+    pub timestamp: i64,
+    pub value: Vec<f64>,
+    pub duration: i64,
+    pub instrument: i64,
+}
+
+pub enum Strategy {
+    // Merge runs of exactly equal samples
+    Exact,
+    // Merge runs of samples within epsilon of the first sample of the run, per component
+    Deadband(f64),
+    // Merge samples into their mean per fixed interval of seconds
+    Average(i64),
+}
+
+impl Strategy {
+    pub fn parse(input: &mut Args) -> Result<(Strategy, bool), Error> {
+        let mut strategy = Strategy::Exact;
+        let mut dry_run = false;
+
+        while let Some(param) = input.next() {
+            match param {
+                "exact" => strategy = Strategy::Exact,
+                "deadband" => {
+                    let epsilon = input
+                        .next()
+                        .ok_or_else(|| Error::Parse(String::from("Missing parameter: epsilon")))?;
+                    let epsilon = epsilon.parse::<f64>().map_err(|e| {
+                        Error::Parse(format!("Failed to parse parameter: <epsilon:f64> {}", e))
+                    })?;
+                    if epsilon < 0.0 {
+                        return Err(Error::Parse(String::from(
+                            "Invalid parameter: <epsilon:f64> must not be negative",
+                        )));
+                    }
+                    strategy = Strategy::Deadband(epsilon);
+                }
+                "average" => {
+                    let interval = input
+                        .next()
+                        .ok_or_else(|| Error::Parse(String::from("Missing parameter: interval")))?;
+                    let interval = interval.parse::<i64>().map_err(|e| {
+                        Error::Parse(format!("Failed to parse parameter: <interval:i64> {}", e))
+                    })?;
+                    if interval < 1 {
+                        return Err(Error::Parse(String::from(
+                            "Invalid parameter: <interval:i64> must be positive",
+                        )));
+                    }
+                    strategy = Strategy::Average(interval);
+                }
+                "dry" => dry_run = true,
+                _ => {
+                    return Err(Error::Parse(format!(
+                        "Unknown compression parameter: {}",
+                        param
+                    )))
+                }
+            }
+        }
+
+        Ok((strategy, dry_run))
+    }
+
+    pub fn help() -> &'static str {
+        "compress [exact | deadband <epsilon:f64> | average <interval:i64>] [dry]"
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Strategy::Exact => String::from("exact"),
+            Strategy::Deadband(epsilon) => format!("deadband ±{}", epsilon),
+            Strategy::Average(interval) => format!("average {}s", interval),
+        }
+    }
+}
+
+pub struct Plan {
+    // First sample of every run, carrying the value and duration of the whole run
+    pub runs: Vec<Sample>,
+    // Samples merged into a run, to be deleted
+    pub merged: Vec<i64>,
+    // Largest difference between a sample component and the value of its run
+    pub max_error: f64,
+}
+
+impl Plan {
+    pub fn report(&self, strategy: &Strategy, total: usize) -> String {
+        format!(
+            "Compression ({}): {} of {} rows would be saved, max error {}",
+            strategy.describe(),
+            self.merged.len(),
+            total,
+            // Hide float noise, no metric is measured that precisely
+            (self.max_error * 1e4).round() / 1e4
+        )
+    }
+}
+
+// Expects samples ordered by instrument and timestamp. A sample covers duration seconds
+// from its timestamp, or one second if it has not been compressed yet. Only seamlessly
+// adjacent samples of the same instrument are merged, so every run still covers its
+// duration without gaps. Run values pass through quantize, which rounds them to what
+// the table can store, before the error is measured.
+pub fn plan(strategy: &Strategy, raw: Vec<Sample>, quantize: fn(&[f64]) -> Vec<f64>) -> Plan {
+    let mut plan = Plan {
+        runs: Vec::new(),
+        merged: Vec::new(),
+        max_error: 0.0,
+    };
+
+    if raw.len() < 2 {
+        return plan;
+    };
+
+    info!(
+        "Compressing {} elements using {}",
+        raw.len(),
+        strategy.describe()
+    );
+
+    // Samples of the current run, the first one becomes the run
+    let mut run: Vec<Sample> = Vec::new();
+    for mut sample in raw {
+        sample.duration = sample.duration.max(1);
+
+        let extends = match (run.first(), run.last()) {
+            (Some(head), Some(tail)) => {
+                head.instrument == sample.instrument
+                    && tail.timestamp + tail.duration == sample.timestamp
+                    && match strategy {
+                        Strategy::Exact => head.value == sample.value,
+                        Strategy::Deadband(epsilon) => {
+                            distance(&head.value, &sample.value) <= *epsilon
+                        }
+                        Strategy::Average(interval) => {
+                            head.timestamp.div_euclid(*interval)
+                                == sample.timestamp.div_euclid(*interval)
+                        }
+                    }
+            }
+            _ => true,
+        };
+
+        if !extends {
+            close(strategy, std::mem::take(&mut run), quantize, &mut plan);
+        }
+        run.push(sample);
+    }
+    close(strategy, run, quantize, &mut plan);
+
+    plan
+}
+
+fn close(strategy: &Strategy, run: Vec<Sample>, quantize: fn(&[f64]) -> Vec<f64>, plan: &mut Plan) {
+    if run.is_empty() {
+        return;
+    }
+    let duration: i64 = run.iter().map(|sample| sample.duration).sum();
+    let value = match strategy {
+        Strategy::Exact | Strategy::Deadband(_) => run[0].value.clone(),
+        Strategy::Average(_) => {
+            let mean: Vec<f64> = (0..run[0].value.len())
+                .map(|component| {
+                    let weighted: f64 = run
+                        .iter()
+                        .map(|sample| sample.value[component] * sample.duration as f64)
+                        .sum();
+                    weighted / duration as f64
+                })
+                .collect();
+            quantize(&mean)
+        }
+    };
+
+    for sample in &run {
+        plan.max_error = plan.max_error.max(distance(&sample.value, &value));
+    }
+
+    let mut run = run.into_iter();
+    let Some(mut head) = run.next() else {
+        return;
+    };
+    plan.merged.extend(run.map(|sample| sample.id));
+
+    head.value = value;
+    head.duration = duration;
+    plan.runs.push(head);
+}
+
+fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(values: &[(i64, f64)]) -> Vec<Sample> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, (timestamp, value))| Sample {
+                id: index as i64 + 1,
+                timestamp: *timestamp,
+                value: vec![*value],
+                duration: 0,
+                instrument: 0,
+            })
+            .collect()
+    }
+
+    fn unchanged(values: &[f64]) -> Vec<f64> {
+        values.to_vec()
+    }
+
+    fn runs(plan: &Plan) -> Vec<(i64, f64, i64)> {
+        plan.runs
+            .iter()
+            .map(|run| (run.timestamp, run.value[0], run.duration))
+            .collect()
+    }
+
+    #[test]
+    fn deadband_keeps_runs_within_epsilon_of_their_first_sample() {
+        let raw = samples(&[(0, 10.0), (1, 10.3), (2, 9.5), (3, 10.6), (4, 10.2)]);
+        let plan = plan(&Strategy::Deadband(0.5), raw, unchanged);
+
+        assert_eq!(runs(&plan), vec![(0, 10.0, 3), (3, 10.6, 2)]);
+        assert_eq!(plan.merged, vec![2, 3, 5]);
+        assert!((plan.max_error - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn gaps_and_instruments_end_a_run() {
+        let mut raw = samples(&[(0, 60.0), (1, 60.0), (5, 60.0), (6, 60.0)]);
+        raw[3].instrument = 1;
+        let plan = plan(&Strategy::Exact, raw, unchanged);
+
+        assert_eq!(runs(&plan), vec![(0, 60.0, 2), (5, 60.0, 1), (6, 60.0, 1)]);
+        assert_eq!(plan.merged, vec![2]);
+        assert_eq!(plan.max_error, 0.0);
+    }
+
+    #[test]
+    fn average_weighs_samples_by_duration_and_quantizes() {
+        let mut raw = samples(&[(0, 60.0), (3, 70.0), (4, 80.0)]);
+        raw[0].duration = 3;
+        let plan = plan(&Strategy::Average(10), raw, |values| {
+            values.iter().map(|value| value.round()).collect()
+        });
+
+        // (3 * 60 + 70 + 80) / 5 = 66 exactly
+        assert_eq!(runs(&plan), vec![(0, 66.0, 5)]);
+        assert_eq!(plan.max_error, 14.0);
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use log::{error, info, warn};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
+    sync::{
+        mpsc::{unbounded_channel, UnboundedSender},
+        oneshot,
+    },
+    time::{interval, interval_at, Instant},
+};
+
+use crate::{
+    args::Args,
+    ble, commands,
+    mqtt::Subscriber,
+    output::{self, Format, Output},
+    retention,
+    schedules::Reminders,
+    utils, Error, SectionedConfigMap,
+};
+
+// These need the terminal of the caller or serve for as long as it runs, so biomon runs
+// them on a database connection of its own next to the daemon
+pub const LOCAL: [&str; 4] = ["serve", "dashboard", "tui", "subscribe"];
+
+// How often scheduled readings are checked for reminders
+const REMINDER_CHECK: Duration = Duration::from_secs(60);
+
+// One line of JSON each way per command
+#[derive(Serialize, Deserialize)]
+struct Request {
+    args: Vec<String>,
+    json: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    ok: bool,
+    // Rendered like the caller would have rendered it
+    output: String,
+}
+
+// Recordings run in the daemon loop itself, so clients ask it to start and stop them
+enum Control {
+    Record(Option<String>, oneshot::Sender<Result<Output, Error>>),
+    Stop(oneshot::Sender<Result<Output, Error>>),
+}
+
+pub fn socket_path(conf: Arc<RwLock<SectionedConfigMap>>) -> String {
+    utils::from_config_or(conf, "daemon", "socket", "biomon.sock")
+}
+
+// Whoever can connect can read and write every reading, so the socket is bound in a
+// directory only the user can enter and moved to path once it is restricted
+fn listen(path: &str) -> Result<UnixListener, Error> {
+    let private = PathBuf::from(format!("{}.bind", path));
+    let socket = private.join("biomon.sock");
+    // Left behind by a daemon that failed while binding
+    let _ = fs::remove_file(&socket);
+    let _ = fs::remove_dir(&private);
+
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .map_err(Error::io(format!("create {}", private.display())))?;
+    let bound = UnixListener::bind(&socket)
+        .map_err(Error::io(format!("listen on {}", path)))
+        .and_then(|listener| {
+            fs::set_permissions(&socket, Permissions::from_mode(0o600))
+                .map_err(Error::io(format!("restrict access to {}", path)))?;
+            fs::rename(&socket, path).map_err(Error::io(format!("listen on {}", path)))?;
+            Ok(listener)
+        });
+    if bound.is_err() {
+        let _ = fs::remove_file(&socket);
+    }
+    let _ = fs::remove_dir(&private);
+    bound
+}
+
+// Owns the database, the BLE recording and the MQTT subscriptions, reminds of scheduled
+// readings, and runs the commands of biomon calls that connect to the socket, until
+// Ctrl-C. Everything runs on this task, one step of one command at a time, so all writes
+// go through the same connection and writer.
+pub async fn run(
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let path = socket_path(conf.clone());
+    let maintain_hours = utils::from_config_or(conf.clone(), "daemon", "maintain_hours", "24");
+    let maintain_hours = maintain_hours.parse::<u64>().map_err(|_| {
+        Error::Config(format!(
+            "maintain_hours in section daemon must be a number of hours or 0, not {}",
+            maintain_hours
+        ))
+    })?;
+
+    // Readings published by sensors to the topics in section mqtt, if any
+    let mut subscriber = Subscriber::start(conf.clone())?;
+    // Readings scheduled in section schedules, if any
+    let mut reminders = Reminders::from_config(conf.clone())?;
+
+    if UnixStream::connect(&path).await.is_ok() {
+        return Err(Error::Parse(format!(
+            "A biomon daemon is already listening on {}",
+            path
+        )));
+    }
+    // Left behind by a daemon that did not shut down cleanly. Anything else at the path
+    // is likely a typo in the config, like the database.
+    if let Ok(metadata) = fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::Config(format!(
+                "socket {} in section daemon exists and is not a socket",
+                path
+            )));
+        }
+        fs::remove_file(&path).map_err(Error::io(format!("remove the old socket {}", path)))?;
+    }
+    let listener = listen(&path)?;
+
+    info!("Daemon listening on {}", path);
+    println!("Daemon listening on {}", path);
+    println!("Press Ctrl-C to stop");
+
+    // Maintenance is off with 0 hours
+    let period = Duration::from_secs(maintain_hours.max(1) * 3600);
+    let mut maintenance = interval_at(Instant::now() + period, period);
+    let mut reminding = interval(REMINDER_CHECK);
+
+    let (control, mut controls) = unbounded_channel();
+    let mut clients = FuturesUnordered::new();
+    let mut recording: Option<LocalBoxFuture<Result<String, Error>>> = None;
+    let mut stop: Option<oneshot::Sender<()>> = None;
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    clients.push(serve_client(stream, conf.clone(), conn, control.clone()).boxed_local())
+                }
+                Err(err) => error!("Failed to accept daemon client -> {}", err),
+            },
+            Some(()) = clients.next(), if !clients.is_empty() => {}
+            Some(request) = controls.recv() => match request {
+                Control::Record(_, reply) if recording.is_some() => {
+                    let _ = reply.send(Err(Error::Parse(String::from(
+                        "Already recording, stop it with 'record stop'",
+                    ))));
+                }
+                Control::Record(profile, reply) => {
+                    let (sender, receiver) = oneshot::channel();
+                    stop = Some(sender);
+                    let conf = conf.clone();
+                    recording = Some(
+                        async move {
+                            let stopped = async {
+                                let _ = receiver.await;
+                            };
+                            ble::record_until(profile.as_deref(), conf, conn, stopped, None).await
+                        }
+                        .boxed_local(),
+                    );
+                    let _ = reply.send(Ok(Output::message(
+                        "Recording in the daemon, stop it with 'record stop'",
+                    )));
+                }
+                Control::Stop(reply) => {
+                    let result = match stop.take() {
+                        Some(stop) => {
+                            let _ = stop.send(());
+                            Ok(Output::message("Stopping recording"))
+                        }
+                        None => Err(Error::Parse(String::from("Not recording"))),
+                    };
+                    let _ = reply.send(result);
+                }
+            },
+            result = async { recording.as_mut().unwrap().await }, if recording.is_some() => {
+                recording = None;
+                stop = None;
+                match result {
+                    Ok(finished) => info!("{}", finished),
+                    Err(err) => error!("Recording failed -> {}", err),
+                }
+            }
+            Some(message) = async { subscriber.as_mut().unwrap().next().await }, if subscriber.is_some() => {
+                if let Some(subscriber) = &subscriber {
+                    subscriber.store(&message, conn);
+                }
+            }
+            _ = maintenance.tick(), if maintain_hours > 0 => {
+                match retention::maintain(conf.clone(), conn) {
+                    Ok(report) => info!("Scheduled maintenance done\n{}", report),
+                    Err(err) => error!("Scheduled maintenance failed -> {}", err),
+                }
+            }
+            _ = reminding.tick(), if reminders.is_some() => {
+                if let Some(reminders) = &mut reminders {
+                    match reminders.check(conn) {
+                        Ok(reminders) => {
+                            for reminder in reminders {
+                                info!("{}", reminder);
+                                println!("{}", reminder);
+                            }
+                        }
+                        Err(err) => error!("Failed to check schedules -> {}", err),
+                    }
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    // Let the writer flush what it holds
+    if let Some(stop) = stop.take() {
+        let _ = stop.send(());
+    }
+    if let Some(recording) = recording.take() {
+        if let Err(err) = recording.await {
+            error!("Recording failed -> {}", err);
+        }
+    }
+
+    drop(listener);
+    if let Err(err) = fs::remove_file(&path) {
+        warn!("Failed to remove socket {} -> {}", path, err);
+    }
+    Ok(String::from("Daemon stopped"))
+}
+
+// Answers the requests of one client until it disconnects
+async fn serve_client(
+    stream: UnixStream,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+    control: UnboundedSender<Control>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                warn!("Failed to read from daemon client -> {}", err);
+                break;
+            }
+        };
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let format = if request.json {
+                    Format::Json
+                } else {
+                    Format::Human
+                };
+                let result = execute(&request.args, conf.clone(), conn, &control).await;
+                Response {
+                    ok: result.is_ok(),
+                    output: output::render(&result, format),
+                }
+            }
+            Err(err) => Response {
+                ok: false,
+                output: format!("Invalid request: {}", err),
+            },
+        };
+
+        let mut reply = serde_json::to_string(&response).unwrap_or_default();
+        reply.push('\n');
+        if let Err(err) = writer.write_all(reply.as_bytes()).await {
+            warn!("Failed to answer daemon client -> {}", err);
+            break;
+        }
+    }
+}
+
+async fn execute(
+    tokens: &[String],
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+    control: &UnboundedSender<Control>,
+) -> Result<Output, Error> {
+    let mut input = Args::new(tokens);
+    let command = input.next().unwrap_or_default();
+
+    let profile = match command {
+        "record" => input.next(),
+        "record_hrp" => Some("hrp"),
+        "record_plx" => Some("plx"),
+        "daemon" => {
+            return Err(Error::Parse(String::from(
+                "A biomon daemon is already running",
+            )))
+        }
+        // Replacing the database under the daemon would lose what it writes
+        "restore" => {
+            return Err(Error::Parse(String::from(
+                "restore cannot run while the daemon holds the database, stop the daemon first",
+            )))
+        }
+        _ if LOCAL.contains(&command) => {
+            return Err(Error::Parse(format!(
+                "{} cannot run in the daemon, run 'biomon {}' instead",
+                command, command
+            )))
+        }
+        _ => return commands::execute(command, &mut input, conf, conn).await,
+    };
+
+    let (reply, answer) = oneshot::channel();
+    let request = match profile {
+        Some("stop") => Control::Stop(reply),
+        _ => Control::Record(profile.map(String::from), reply),
+    };
+    if control.send(request).is_err() {
+        return Err(Error::Parse(String::from("The daemon is stopping")));
+    }
+    answer
+        .await
+        .unwrap_or_else(|_| Err(Error::Parse(String::from("The daemon is stopping"))))
+}
+
+// A connection to a running daemon
+pub struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    // None if no daemon listens on the socket
+    pub async fn connect(path: &str) -> Option<Client> {
+        let stream = UnixStream::connect(path).await.ok()?;
+        let (reader, writer) = stream.into_split();
+        Some(Client {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        })
+    }
+
+    // Runs a command in the daemon. Returns its rendered output, as Err if the command
+    // failed, or an error if the daemon could not be reached.
+    pub async fn execute(
+        &mut self,
+        args: &[String],
+        format: Format,
+    ) -> Result<Result<String, String>, Error> {
+        let request = Request {
+            args: args.to_vec(),
+            json: format == Format::Json,
+        };
+        let mut line = serde_json::to_string(&request)
+            .map_err(|err| Error::Parse(format!("Invalid request: {}", err)))?;
+        line.push('\n');
+
+        let talk = String::from("reach the daemon");
+        self.writer
+            .write_all(line.as_bytes())
+            .await
+            .map_err(Error::io(talk.clone()))?;
+        let answer = self
+            .lines
+            .next_line()
+            .await
+            .map_err(Error::io(talk.clone()))?
+            .ok_or_else(|| {
+                Error::Io(
+                    talk,
+                    std::io::Error::from(std::io::ErrorKind::UnexpectedEof),
+                )
+            })?;
+
+        let response: Response = serde_json::from_str(&answer)
+            .map_err(|err| Error::Parse(format!("Invalid daemon response: {}", err)))?;
+        Ok(if response.ok {
+            Ok(response.output)
+        } else {
+            Err(response.output)
+        })
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use rustyline::{
+    completion::{Completer, Pair},
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    Context, Helper,
+};
+
+use biomon::args;
+
+// Completes commands and their keywords as listed in help, e.g. `heartrate <last
+// <count:i64> | compress [exact | deadband <epsilon:f64>]>` offers last and compress
+// after heartrate, and exact and deadband further on.
+pub struct Completion {
+    commands: Vec<String>,
+    // Keywords that start an alternative directly inside the command's brackets
+    subcommands: HashMap<String, Vec<String>>,
+    // Keywords that start an alternative nested deeper
+    parameters: HashMap<String, Vec<String>>,
+}
+
+impl Completion {
+    pub fn from_help(help: &str) -> Completion {
+        let mut completion = Completion {
+            commands: Vec::new(),
+            subcommands: HashMap::new(),
+            parameters: HashMap::new(),
+        };
+
+        for line in help.lines().filter(|line| line.starts_with('\t')) {
+            let line = line.trim();
+            let (command, usage) = line.split_once(' ').unwrap_or((line, ""));
+            completion.commands.push(String::from(command));
+
+            for (depth, keyword) in keywords(usage) {
+                let keywords = if depth == 1 {
+                    completion.subcommands.entry(String::from(command))
+                } else {
+                    completion.parameters.entry(String::from(command))
+                };
+                let keywords = keywords.or_default();
+                if !keywords.contains(&keyword) {
+                    keywords.push(keyword);
+                }
+            }
+        }
+
+        completion
+    }
+}
+
+// Plain words at the start of an alternative, with their bracket depth. Typed
+// parameters like <count:i64> are placeholders, not keywords.
+fn keywords(usage: &str) -> Vec<(usize, String)> {
+    let mut keywords = Vec::new();
+    let mut depth: usize = 0;
+    let mut starts_alternative = false;
+
+    let mut rest = usage;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '<' | '[' => {
+                depth += 1;
+                starts_alternative = true;
+            }
+            '>' | ']' => {
+                depth = depth.saturating_sub(1);
+                starts_alternative = false;
+            }
+            '|' => starts_alternative = true,
+            c if c.is_whitespace() => {}
+            _ => {
+                let end = rest
+                    .find(|c: char| c.is_whitespace() || "<>[]|".contains(c))
+                    .unwrap_or(rest.len());
+                let word = &rest[..end];
+                if starts_alternative
+                    && depth > 0
+                    && word
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    keywords.push((depth, String::from(word)));
+                }
+                starts_alternative = false;
+                rest = &rest[end..];
+                continue;
+            }
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    keywords
+}
+
+impl Completer for Completion {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line
+            .rfind(char::is_whitespace)
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        let prefix = &line[start..];
+
+        // An unfinished quote has nothing sensible to complete
+        let words = match args::tokenize(&line[..start]) {
+            Ok(words) => words,
+            Err(_) => return Ok((start, Vec::new())),
+        };
+
+        let candidates = match words.first() {
+            None => Some(&self.commands),
+            Some(command) if words.len() == 1 => self.subcommands.get(command),
+            Some(command) => self.parameters.get(command),
+        };
+
+        let mut matches: Vec<Pair> = candidates
+            .into_iter()
+            .flatten()
+            .filter(|candidate| candidate.starts_with(prefix))
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: format!("{} ", candidate),
+            })
+            .collect();
+        matches.sort_by(|a, b| a.display.cmp(&b.display));
+        matches.dedup_by(|a, b| a.display == b.display);
+
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
@@ -0,0 +1,128 @@
+use std::{fmt, io};
+
+use rusqlite::{ffi, ErrorCode};
+
+// Why a command or library call failed. Rendered once by output::render, so the
+// message has to say what to fix without a look into biomon.log.
+#[derive(Debug)]
+pub enum Error {
+    // What was being done, e.g. "write weight", and the SQLite error
+    Database(String, rusqlite::Error),
+    // Invalid input from the command line, a request or a file
+    Parse(String),
+    // A missing or invalid value in biomon.ini
+    Config(String),
+    // The adapter, a device or its connection failed
+    Ble(String),
+    // What was being done, usually with the path, and the IO error
+    Io(String, io::Error),
+    // Each failed part of a batch, like "line 3" of a file or a device, and a summary
+    Batch(Vec<(String, Error)>, String),
+}
+
+impl Error {
+    pub fn database(action: impl Into<String>) -> impl FnOnce(rusqlite::Error) -> Error {
+        let action = action.into();
+        move |err| Error::Database(action, err)
+    }
+
+    pub fn io(action: impl Into<String>) -> impl FnOnce(io::Error) -> Error {
+        let action = action.into();
+        move |err| Error::Io(action, err)
+    }
+
+    // Short name for scripts reading JSON
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Database(..) => "database",
+            Error::Parse(_) => "parse",
+            Error::Config(_) => "config",
+            Error::Ble(_) => "ble",
+            Error::Io(..) => "io",
+            Error::Batch(..) => "batch",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Database(action, err) => {
+                write!(f, "Failed to {}: {}", action, err)?;
+                match err.sqlite_error_code() {
+                    Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+                        write!(f, " (another biomon is writing, try again)")
+                    }
+                    // Not for the other constraints, like NOT NULL
+                    Some(ErrorCode::ConstraintViolation)
+                        if matches!(
+                            err.sqlite_error().map(|err| err.extended_code),
+                            Some(ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY)
+                        ) =>
+                    {
+                        write!(f, " (an entry with this timestamp may already exist)")
+                    }
+                    Some(ErrorCode::ReadOnly | ErrorCode::CannotOpen) => {
+                        write!(f, " (check the permissions of ./biomon.sqlite)")
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Error::Parse(message) => write!(f, "{}", message),
+            Error::Config(message) => write!(f, "Invalid config: {}", message),
+            Error::Ble(message) => write!(f, "Bluetooth: {}", message),
+            Error::Io(action, err) => write!(f, "Failed to {}: {}", action, err),
+            Error::Batch(failures, summary) => {
+                for (part, err) in failures {
+                    writeln!(f, "{}: {}", part, err)?;
+                }
+                write!(f, "{}", summary)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Database(_, err) => Some(err),
+            Error::Io(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Database(String::from("access the database"), err)
+    }
+}
+
+impl From<btleplug::Error> for Error {
+    fn from(err: btleplug::Error) -> Error {
+        Error::Ble(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    #[test]
+    fn only_unique_constraints_hint_at_the_timestamp() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (timestamp INTEGER UNIQUE, value REAL NOT NULL);")
+            .unwrap();
+        conn.execute_batch("INSERT INTO t VALUES (1, 2.0);")
+            .unwrap();
+        let failed = |sql: &str| {
+            let err = conn.execute_batch(sql).unwrap_err();
+            Error::database("write t")(err).to_string()
+        };
+
+        assert!(failed("INSERT INTO t VALUES (1, 3.0);").contains("timestamp may already exist"));
+        assert!(!failed("INSERT INTO t VALUES (2, NULL);").contains("timestamp may already exist"));
+    }
+}
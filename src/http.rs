@@ -0,0 +1,458 @@
+use std::{
+    io::{self, Read},
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use log::{error, info, warn};
+use percent_encoding::percent_decode_str;
+use rusqlite::Connection;
+use serde_json::Value as JsonValue;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tokio::sync::mpsc;
+
+use crate::{
+    args::Args,
+    bp::BP,
+    heartrate::Heartrate,
+    instruments,
+    mood::{self, Mood},
+    output::{self, Format, Output},
+    spo2::Spo2,
+    temperature::Temperature,
+    utils,
+    weight::Weight,
+    Error, SectionedConfigMap, Stat,
+};
+
+// Bodies are a single reading or instrument, anything larger is a mistake
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+pub(crate) struct Metric {
+    // Name in the url
+    pub(crate) path: &'static str,
+    // Same command the REPL stores and queries it with
+    pub(crate) command: fn(&mut Args, &Connection) -> Result<Output, Error>,
+    // Body fields in the order the command takes them
+    pub(crate) fields: &'static [&'static str],
+    // Continuous metrics also answer stats
+    stats: bool,
+}
+
+pub(crate) const METRICS: [Metric; 6] = [
+    Metric {
+        path: "weight",
+        command: Weight::command,
+        fields: &["weight"],
+        stats: false,
+    },
+    Metric {
+        path: "bp",
+        command: BP::command,
+        fields: &["sys", "dia"],
+        stats: false,
+    },
+    Metric {
+        path: "mood",
+        command: Mood::command,
+        fields: &["mood"],
+        stats: false,
+    },
+    Metric {
+        path: "heartrate",
+        command: Heartrate::command,
+        fields: &["heartrate"],
+        stats: true,
+    },
+    Metric {
+        path: "temperature",
+        command: Temperature::command,
+        fields: &["temperature"],
+        stats: true,
+    },
+    Metric {
+        path: "spo2",
+        command: Spo2::command,
+        fields: &["spo2", "pulse"],
+        stats: true,
+    },
+];
+
+struct Reply {
+    status: u16,
+    result: Result<Output, Error>,
+}
+
+impl Reply {
+    fn ok(result: Result<Output, Error>) -> Reply {
+        let status = match &result {
+            Ok(Output::Recorded { .. }) => 201,
+            Ok(_) => 200,
+            Err(Error::Parse(_)) => 400,
+            Err(err) => {
+                error!("Failed to answer request -> {}", err);
+                500
+            }
+        };
+        Reply { status, result }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Reply {
+        Reply {
+            status,
+            result: Err(Error::Parse(message.into())),
+        }
+    }
+}
+
+// Answers the HTTP API, and with dashboard also serves the dashboard page, until
+// Ctrl-C. Requests are accepted on a thread of their own and handled one after
+// another here, since they all share the connection.
+pub async fn serve(
+    dashboard: bool,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let token = utils::from_config_or(conf.clone(), "http", "token", "");
+    if token.is_empty() {
+        return Err(Error::Config(String::from(
+            "No token set in section http. Set one before serving the API",
+        )));
+    }
+    let bind = utils::from_config_or(conf.clone(), "http", "bind", "127.0.0.1");
+    let port = utils::from_config_or(conf.clone(), "http", "port", "8080");
+    let address = format!("{}:{}", bind, port);
+
+    let server = match Server::http(&address) {
+        Ok(server) => Arc::new(server),
+        Err(err) => {
+            return Err(Error::Io(
+                format!("listen on {}", address),
+                io::Error::other(err),
+            ))
+        }
+    };
+    info!("Serving API on http://{}/api", address);
+    if dashboard {
+        // The page picks the token up from the fragment, which never reaches the server
+        println!("Dashboard on http://{}/#token={}", address, token);
+        println!("Press Ctrl-C to stop");
+    }
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let listener = Arc::clone(&server);
+    thread::spawn(move || {
+        for request in listener.incoming_requests() {
+            if sender.send(request).is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            request = receiver.recv() => match request {
+                Some(request) => handle(request, &token, dashboard, conn),
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    server.unblock();
+    Ok(String::from("Stopped serving API"))
+}
+
+fn handle(mut request: Request, token: &str, dashboard: bool, conn: &Connection) {
+    // The page holds no data, it fetches everything from the API with the token
+    if dashboard && *request.method() == Method::Get {
+        let path = request.url().split('?').next().unwrap_or_default();
+        if path == "/" || path == "/index.html" {
+            respond(request, 200, DASHBOARD, "text/html; charset=utf-8");
+            return;
+        }
+    }
+
+    let reply = if authorized(&request, token) {
+        route(&mut request, conn)
+    } else {
+        warn!(
+            "Rejected unauthorized {} {} from {:?}",
+            request.method(),
+            request.url(),
+            request.remote_addr()
+        );
+        Reply::error(401, "Missing or wrong bearer token")
+    };
+
+    info!("{} {} -> {}", request.method(), request.url(), reply.status);
+
+    let body = output::render(&reply.result, Format::Json);
+    respond(request, reply.status, &body, "application/json");
+}
+
+fn respond(request: Request, status: u16, body: &str, content_type: &str) {
+    let mut response = Response::from_string(body).with_status_code(status);
+    let headers = [
+        ("Content-Type", content_type),
+        // Everything the dashboard needs is inline, so nothing may come from elsewhere
+        (
+            "Content-Security-Policy",
+            "default-src 'self' 'unsafe-inline'",
+        ),
+    ];
+    for (field, value) in headers {
+        if let Ok(header) = Header::from_bytes(field, value) {
+            response = response.with_header(header);
+        }
+    }
+    if let Err(err) = request.respond(response) {
+        error!("Failed to send response -> {}", err);
+    }
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    let expected = format!("Bearer {}", token);
+    request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Authorization"))
+        .any(|header| constant_time_eq(header.value.as_bytes(), expected.as_bytes()))
+}
+
+// Compares without returning early, so response times don't leak the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn route(request: &mut Request, conn: &Connection) -> Reply {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = match path.strip_prefix("/api/") {
+        Some(path) => path.split('/').filter(|s| !s.is_empty()).collect(),
+        None => return Reply::error(404, format!("Not found: {}", path)),
+    };
+    let method = request.method().clone();
+
+    match (&method, segments.as_slice()) {
+        (Method::Get, ["instruments"]) => Reply::ok(instruments::list(conn)),
+        (Method::Put, ["instruments", id]) => {
+            let Ok(id) = id.parse::<i64>() else {
+                return Reply::error(404, format!("Not found: {}", path));
+            };
+            let changes = match read_body(request) {
+                Ok(body) => serde_json::from_value::<instruments::Changes>(body)
+                    .map_err(|err| Error::Parse(format!("Invalid instrument: {}", err))),
+                Err(err) => Err(err),
+            };
+            Reply::ok(changes.and_then(|changes| instruments::put(id, &changes, conn)))
+        }
+        (_, [metric, rest @ ..]) => {
+            let Some(metric) = METRICS.iter().find(|m| m.path == *metric) else {
+                return Reply::error(404, format!("Not found: {}", path));
+            };
+            match (&method, rest) {
+                (Method::Post, []) => {
+                    Reply::ok(read_body(request).and_then(|body| record(metric, &body, conn)))
+                }
+                (Method::Get, []) => Reply::ok(query_range(metric, "range", query, conn)),
+                (Method::Get, [subcommand @ ("stats" | "trend")]) if metric.stats => {
+                    Reply::ok(query_range(metric, subcommand, query, conn))
+                }
+                (_, []) => Reply::error(405, format!("Method not allowed: {} {}", method, path)),
+                _ => Reply::error(404, format!("Not found: {}", path)),
+            }
+        }
+        _ => Reply::error(404, format!("Not found: {}", path)),
+    }
+}
+
+fn read_body(request: &mut Request) -> Result<JsonValue, Error> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES)
+        .read_to_string(&mut body)
+        .map_err(|err| Error::Parse(format!("Failed to read request body: {}", err)))?;
+    serde_json::from_str(&body).map_err(|err| Error::Parse(format!("Invalid JSON body: {}", err)))
+}
+
+// Stores a reading through the same command as typing it into the REPL does. The body
+// holds the value fields and optionally the timestamp it was taken at.
+fn record(metric: &Metric, body: &JsonValue, conn: &Connection) -> Result<Output, Error> {
+    let Some(body) = body.as_object() else {
+        return Err(Error::Parse(String::from("Expected a JSON object")));
+    };
+
+    let mut tokens = Vec::new();
+    for field in metric.fields {
+        match body.get(*field) {
+            Some(JsonValue::String(value)) => tokens.push(value.clone()),
+            Some(JsonValue::Number(value)) => tokens.push(value.to_string()),
+            Some(_) => {
+                return Err(Error::Parse(format!(
+                    "Field {} must be a number or string",
+                    field
+                )))
+            }
+            None => return Err(Error::Parse(format!("Missing field: {}", field))),
+        }
+    }
+    // Anything else would reach the subcommands of the metric, like export or compress
+    for (field, token) in metric.fields.iter().zip(&tokens) {
+        let valid = match metric.path {
+            "mood" => !token.is_empty() && !mood::SUBCOMMANDS.contains(&token.as_str()),
+            _ => token.parse::<f64>().is_ok(),
+        };
+        if !valid {
+            return Err(Error::Parse(format!(
+                "Invalid value for field {}: {}",
+                field, token
+            )));
+        }
+    }
+    let mut input = Args::new(&tokens);
+
+    match body.get("timestamp") {
+        None | Some(JsonValue::Null) => (metric.command)(&mut input, conn),
+        Some(timestamp) => {
+            let timestamp = match timestamp {
+                JsonValue::Number(timestamp) => timestamp.as_i64(),
+                JsonValue::String(timestamp) => utils::parse_timestamp(timestamp),
+                _ => None,
+            }
+            .ok_or_else(|| Error::Parse(format!("Failed to parse timestamp: {}", timestamp)))?;
+
+            utils::ENTRY_TIME.sync_scope(timestamp, || (metric.command)(&mut input, conn))
+        }
+    }
+}
+
+// Runs range, stats or trend with the from, to and for trend step query parameters
+fn query_range(
+    metric: &Metric,
+    subcommand: &str,
+    query: &str,
+    conn: &Connection,
+) -> Result<Output, Error> {
+    let mut from = None;
+    let mut to = None;
+    let mut step = None;
+    for pair in query.split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode_str(&value.replace('+', " "))
+            .decode_utf8()
+            .map_err(|err| Error::Parse(format!("Invalid query parameter {}: {}", key, err)))?
+            .into_owned();
+        match key {
+            "from" => from = Some(value),
+            "to" => to = Some(value),
+            "step" if subcommand == "trend" => step = Some(value),
+            "" => {}
+            _ => return Err(Error::Parse(format!("Unknown query parameter: {}", key))),
+        }
+    }
+
+    let Some(from) = from else {
+        return Err(Error::Parse(String::from("Missing query parameter: from")));
+    };
+    let mut tokens = vec![String::from(subcommand)];
+    if subcommand == "trend" {
+        let Some(step) = step else {
+            return Err(Error::Parse(String::from("Missing query parameter: step")));
+        };
+        tokens.push(step);
+    }
+    tokens.push(from);
+    tokens.extend(to);
+
+    (metric.command)(&mut Args::new(&tokens), conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::Store;
+
+    fn metric(path: &str) -> &'static Metric {
+        METRICS.iter().find(|metric| metric.path == path).unwrap()
+    }
+
+    fn count(table: &str, conn: &Connection) -> i64 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {};", table), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn record_stores_numbers() {
+        let store = Store::open(":memory:").unwrap();
+        let conn = store.connection();
+
+        let body = json!({ "sys": 120, "dia": "80", "timestamp": 1700000000 });
+        assert!(matches!(
+            record(metric("bp"), &body, conn),
+            Ok(Output::Recorded { .. })
+        ));
+        assert_eq!(count("bp", conn), 1);
+    }
+
+    #[test]
+    fn record_refuses_subcommands() {
+        let store = Store::open(":memory:").unwrap();
+        let conn = store.connection();
+        let target = std::env::temp_dir().join(format!("biomon-http-{}.csv", std::process::id()));
+
+        let export = json!({ "spo2": "export", "pulse": target.to_string_lossy() });
+        assert!(record(metric("spo2"), &export, conn).is_err());
+        assert!(!target.exists());
+
+        for body in [
+            json!({ "heartrate": "compress" }),
+            json!({ "heartrate": "NaN" }),
+            json!({ "heartrate": "last" }),
+        ] {
+            assert!(record(metric("heartrate"), &body, conn).is_err());
+        }
+        assert_eq!(count("heartrate", conn), 0);
+    }
+
+    #[test]
+    fn record_refuses_moods_named_like_subcommands() {
+        let store = Store::open(":memory:").unwrap();
+        let conn = store.connection();
+
+        for mood in ["last", "range", ""] {
+            assert!(record(metric("mood"), &json!({ "mood": mood }), conn).is_err());
+        }
+        assert_eq!(count("mood", conn), 0);
+
+        assert!(record(metric("mood"), &json!({ "mood": "content" }), conn).is_ok());
+        assert_eq!(count("mood", conn), 1);
+    }
+
+    #[test]
+    fn record_refuses_non_finite_numbers() {
+        let store = Store::open(":memory:").unwrap();
+        let conn = store.connection();
+
+        for value in ["NaN", "inf", "-inf"] {
+            let Err(err) = record(
+                metric("temperature"),
+                &json!({ "temperature": value }),
+                conn,
+            ) else {
+                panic!("{} was recorded", value);
+            };
+            assert!(err.to_string().contains("not a finite number"), "{}", err);
+            assert!(record(metric("weight"), &json!({ "weight": value }), conn).is_err());
+        }
+        assert_eq!(count("temperature", conn), 0);
+        assert_eq!(count("weight", conn), 0);
+    }
+}
@@ -0,0 +1,228 @@
+use chrono::Utc;
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::{output::Output, Error};
+
+// Samples entered by hand are not tied to an instrument
+pub const MANUAL: i64 = 0;
+
+pub struct DeviceInfo {
+    pub mac: String,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub firmware: Option<String>,
+    pub battery: Option<u8>,
+}
+
+#[derive(Serialize)]
+pub struct Instrument {
+    pub id: i64,
+    pub metric: String,
+    pub name: String,
+    pub introduced: i64,
+    pub deprecated: Option<i64>,
+    pub tol_min: Option<f64>,
+    pub tol_max: Option<f64>,
+    pub notes: Option<String>,
+    pub mac: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub firmware: Option<String>,
+    pub battery: Option<i64>,
+}
+
+impl Instrument {
+    fn read(row: &Row) -> Result<Instrument, rusqlite::Error> {
+        Ok(Instrument {
+            id: row.get(0)?,
+            metric: row.get(1)?,
+            name: row.get(2)?,
+            introduced: row.get(3)?,
+            deprecated: row.get(4)?,
+            tol_min: row.get(5)?,
+            tol_max: row.get(6)?,
+            notes: row.get(7)?,
+            mac: row.get(8)?,
+            manufacturer: row.get(9)?,
+            model: row.get(10)?,
+            serial: row.get(11)?,
+            firmware: row.get(12)?,
+            battery: row.get(13)?,
+        })
+    }
+}
+
+// The hand maintained part of an instrument. Fields left out keep their value.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Changes {
+    pub metric: Option<String>,
+    pub name: Option<String>,
+    pub introduced: Option<i64>,
+    pub deprecated: Option<i64>,
+    pub tol_min: Option<f64>,
+    pub tol_max: Option<f64>,
+    pub notes: Option<String>,
+}
+
+const COLUMNS: &str = "id, metric, name, introduced, deprecated, tol_min, tol_max, notes, mac, manufacturer, model, serial, firmware, battery";
+
+pub fn tables(conn: &Connection) {
+    let _ = conn
+        .execute(
+            "CREATE TABLE IF NOT EXISTS instruments (
+                    id          INTEGER PRIMARY KEY,
+                    metric      TEXT NOT NULL,
+                    name        TEXT NOT NULL,
+                    introduced  INTEGER NOT NULL,
+                    deprecated  INTEGER,
+                    tol_min     REAL,
+                    tol_max     REAL,
+                    notes       TEXT,
+                    mac         TEXT UNIQUE,
+                    manufacturer TEXT,
+                    model       TEXT,
+                    serial      TEXT,
+                    firmware    TEXT,
+                    battery     INTEGER
+                );",
+            [],
+        )
+        .map_err(|err| error!("Failed to ensure table 'instruments' exists -> {}", err));
+}
+
+// Creates the instrument for a device from what it reports about itself, or refreshes
+// the details of the existing one. Name and tolerances are left untouched on update
+// since those are maintained by hand. Returns the id of the instrument.
+pub fn upsert_device(
+    metric: &str,
+    info: &DeviceInfo,
+    conn: &Connection,
+) -> Result<i64, rusqlite::Error> {
+    let name = match (&info.manufacturer, &info.model) {
+        (Some(manufacturer), Some(model)) => format!("{} {}", manufacturer, model),
+        (None, Some(model)) => model.clone(),
+        _ => info.mac.clone(),
+    };
+
+    conn.query_row(
+        "INSERT INTO instruments (metric, name, introduced, mac, manufacturer, model, serial, firmware, battery)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+        ON CONFLICT (mac) DO UPDATE SET
+            metric = excluded.metric,
+            manufacturer = excluded.manufacturer,
+            model = excluded.model,
+            serial = excluded.serial,
+            firmware = excluded.firmware,
+            battery = excluded.battery
+        RETURNING id;",
+        params![
+            metric,
+            name,
+            Utc::now().timestamp(),
+            info.mac,
+            info.manufacturer,
+            info.model,
+            info.serial,
+            info.firmware,
+            info.battery
+        ],
+        |row| row.get(0),
+    )
+}
+
+pub fn list(conn: &Connection) -> Result<Output, Error> {
+    let instruments = conn
+        .prepare(&format!("SELECT {} FROM instruments ORDER BY id;", COLUMNS))
+        .and_then(|mut query| {
+            query
+                .query_map([], Instrument::read)?
+                .collect::<Result<Vec<_>, _>>()
+        });
+
+    let instruments = instruments.map_err(Error::database("list instruments"))?;
+    Ok(Output::Instruments { instruments })
+}
+
+// Updates an instrument, or registers it under the given id if there is none yet.
+// A new instrument needs at least a metric and a name.
+pub fn put(id: i64, changes: &Changes, conn: &Connection) -> Result<Output, Error> {
+    let existing = conn
+        .query_row(
+            &format!("SELECT {} FROM instruments WHERE id = ?1;", COLUMNS),
+            [id],
+            Instrument::read,
+        )
+        .optional()
+        .map_err(Error::database(format!("read instrument {}", id)))?;
+
+    let result = match existing {
+        Some(_) => conn.query_row(
+            &format!(
+                "UPDATE instruments SET
+                    metric = COALESCE(?2, metric),
+                    name = COALESCE(?3, name),
+                    introduced = COALESCE(?4, introduced),
+                    deprecated = COALESCE(?5, deprecated),
+                    tol_min = COALESCE(?6, tol_min),
+                    tol_max = COALESCE(?7, tol_max),
+                    notes = COALESCE(?8, notes)
+                WHERE id = ?1
+                RETURNING {};",
+                COLUMNS
+            ),
+            params![
+                id,
+                changes.metric,
+                changes.name,
+                changes.introduced,
+                changes.deprecated,
+                changes.tol_min,
+                changes.tol_max,
+                changes.notes
+            ],
+            Instrument::read,
+        ),
+        None => {
+            if id == MANUAL {
+                return Err(Error::Parse(format!(
+                    "Instrument id {} is reserved for manual entry",
+                    MANUAL
+                )));
+            }
+            let (Some(metric), Some(name)) = (&changes.metric, &changes.name) else {
+                return Err(Error::Parse(String::from(
+                    "A new instrument needs at least a metric and a name",
+                )));
+            };
+            conn.query_row(
+                &format!(
+                    "INSERT INTO instruments (id, metric, name, introduced, deprecated, tol_min, tol_max, notes)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                    RETURNING {};",
+                    COLUMNS
+                ),
+                params![
+                    id,
+                    metric,
+                    name,
+                    changes.introduced.unwrap_or_else(|| Utc::now().timestamp()),
+                    changes.deprecated,
+                    changes.tol_min,
+                    changes.tol_max,
+                    changes.notes
+                ],
+                Instrument::read,
+            )
+        }
+    };
+
+    let instrument = result.map_err(Error::database(format!("write instrument {}", id)))?;
+    Ok(Output::Instruments {
+        instruments: vec![instrument],
+    })
+}
@@ -0,0 +1,58 @@
+//! Records health data like weight, blood pressure, mood, heart rate, temperature and
+//! SpO2 in a SQLite database, by hand or from BLE devices.
+//!
+//! [`Store`] is the typed entry point: open a database, insert and query each metric,
+//! compress continuous metrics and back the database up. [`commands`] runs the same
+//! text commands as the biomon REPL.
+
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+
+pub mod alerts;
+pub mod args;
+pub mod batch;
+pub mod ble;
+pub mod ble_hrp;
+pub mod ble_htp;
+pub mod ble_plx;
+pub mod ble_sync;
+pub mod bp;
+pub mod commands;
+pub mod compression;
+#[cfg(unix)]
+pub mod daemon;
+pub mod error;
+pub mod heartrate;
+pub mod http;
+pub mod instruments;
+pub mod mood;
+pub mod mqtt;
+pub mod output;
+pub mod retention;
+pub mod schedules;
+pub mod series;
+pub mod spo2;
+pub mod store;
+pub mod temperature;
+pub mod tui;
+pub mod utils;
+pub mod webhooks;
+pub mod weight;
+pub mod writer;
+
+pub use ble_hrp::parse_measurement as parse_heart_rate_measurement;
+pub use error::Error;
+pub use store::Store;
+
+use args::Args;
+use output::Output;
+
+pub trait Stat {
+    fn tables(conn: &Connection);
+    fn command(input: &mut Args, conn: &Connection) -> Result<Output, Error>;
+    fn help() -> String;
+}
+
+// Contents of biomon.ini by section and key
+pub type SectionedConfigMap = HashMap<String, HashMap<String, Option<String>>>;
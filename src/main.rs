@@ -1,147 +1,355 @@
-use bp::BP;
-use chrono::{TimeZone, Utc};
+#[cfg(unix)]
+use biomon::daemon;
+use biomon::{
+    alerts::{self, Alerter},
+    args::{self, Args},
+    ble,
+    commands::{execute, help},
+    mqtt::Publisher,
+    output::{self, Format, Output},
+    retention, schedules,
+    store::{Consumer, Listener, METRICS},
+    tui,
+    webhooks::Notifier,
+    Error, SectionedConfigMap, Store,
+};
+use chrono::Utc;
+use editor::Completion;
 use fern::Dispatch;
-use heartrate::Heartrate;
 use ini::configparser::ini::Ini;
 use std::{
-    collections::HashMap,
-    fs::{self, OpenOptions},
+    env,
+    fs::OpenOptions,
     io,
     path::Path,
-    str::SplitWhitespace,
-    sync::{Arc, RwLock},
-    time::Duration,
+    process::ExitCode,
+    sync::{atomic::Ordering, Arc, RwLock},
 };
-use temperature::Temperature;
 
 use log::{error, info};
-use mood::Mood;
-use rusqlite::{backup::Backup, params, Connection};
-use weight::Weight;
-
-mod ble_hrp;
-mod bp;
-mod heartrate;
-mod mood;
-mod temperature;
-mod utils;
-mod weight;
-
-pub trait Stat {
-    fn tables(conn: &Connection);
-    fn command(input: &mut SplitWhitespace, conn: &Connection) -> String;
-    fn help() -> String;
-}
+use rusqlite::Connection;
+use rustyline::{error::ReadlineError, history::FileHistory, Editor};
+
+mod editor;
 
-type SectionedConfigMap = HashMap<String, HashMap<String, Option<String>>>;
+const HISTORY_PATH: &str = "biomon.history";
 
+// With arguments, runs them as one command and exits with a failure code if the
+// command failed, e.g. `biomon bp last 5`. Without arguments, reads commands from
+// stdin until 'q' or the end of input. A leading --json prints JSON instead of text,
+// and `biomon --batch <file>` runs a file of commands.
 #[tokio::main]
-async fn main() {
-    setup_logger().expect("Failed to setup logger");
+async fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let format = if args.first().is_some_and(|arg| arg == "--json") {
+        args.remove(0);
+        Format::Json
+    } else {
+        Format::Human
+    };
+    // Same as `biomon source <file>`
+    if args.first().is_some_and(|arg| arg == "--batch") {
+        args[0] = String::from("source");
+    }
+    let interactive = args.is_empty();
 
-    let conf = match read_config("biomon.ini") {
+    if let Err(err) = setup_logger(interactive) {
+        eprintln!("Failed to set up logging to ./biomon.log: {}", err);
+        return ExitCode::FAILURE;
+    }
+
+    let mut conf = match read_config("biomon.ini") {
         Ok(conf) => conf,
         Err(err) => {
             error!("Failed to read config -> {}", err);
-            return;
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
         }
     };
+    ble::migrate_legacy_config(&mut conf);
     let conf = Arc::from(RwLock::from(conf));
 
     info!("Biomon launched");
 
-    let conn = match Connection::open("biomon.sqlite") {
-        Ok(conn) => conn,
-        Err(_) => {
-            println!("Failed to open ./biomon.sqlite (Missing permissions?)");
-            println!("Cannot proceed without database");
-            println!("'q' to exit");
-
-            let mut input = String::new();
-            while !input.starts_with('q') {
-                // Wait for user input
-                let _ = io::stdin().read_line(&mut input).map_err(|err| {
-                    error!("Failed to read stdin -> {}", err);
-                    println!("Failed to read stdin. Check log for full error.")
-                });
+    let store;
+    let mut listener = None;
+    #[cfg_attr(not(unix), allow(unused_labels))]
+    let mut runner = 'runner: {
+        // A running daemon owns the database, so commands go to it instead. Servers and
+        // the TUI run here next to it.
+        #[cfg(unix)]
+        if args
+            .first()
+            .is_none_or(|command| command != "daemon" && !daemon::LOCAL.contains(&command.as_str()))
+        {
+            let socket = daemon::socket_path(conf.clone());
+            if let Some(client) = daemon::Client::connect(&socket).await {
+                info!("Running commands in the daemon on {}", socket);
+                break 'runner Runner::Daemon(client);
             }
+        }
 
-            return;
+        // Creates the database and its tables on first launch
+        store = match Store::open("biomon.sqlite") {
+            Ok(store) => store,
+            Err(err) if !interactive => {
+                error!("Failed to open database -> {}", err);
+                eprintln!("{}", err);
+                return ExitCode::FAILURE;
+            }
+            Err(err) => {
+                error!("Failed to open database -> {}", err);
+                println!("{}", err);
+                println!("Cannot proceed without database");
+                println!("'q' to exit");
+
+                let mut input = String::new();
+                while !input.starts_with('q') {
+                    // Wait for user input
+                    let _ = io::stdin().read_line(&mut input).map_err(|err| {
+                        error!("Failed to read stdin -> {}", err);
+                        println!("Failed to read stdin: {}", err)
+                    });
+                }
+
+                return ExitCode::FAILURE;
+            }
+        };
+
+        let conn = store.connection();
+
+        if retention::on_startup(conf.clone()) {
+            match retention::maintain(conf.clone(), conn) {
+                Ok(output) if interactive => print!("{}", output),
+                Ok(_) => {}
+                Err(err) => {
+                    error!("Failed to maintain retention -> {}", err);
+                    eprintln!("{}", err)
+                }
+            }
         }
+
+        let mut consumers: Vec<Box<dyn Consumer>> = Vec::new();
+        // Readings go out to the broker as they are committed, if one is configured
+        match Publisher::start(conf.clone()) {
+            Ok(publisher) => consumers.extend(publisher.map(|publisher| Box::new(publisher) as _)),
+            Err(err) => {
+                error!("Failed to start MQTT publishing -> {}", err);
+                eprintln!("{}", err)
+            }
+        }
+        // Readings crossing a threshold are posted to the webhooks, if any are configured
+        match Notifier::from_config(conf.clone()) {
+            Ok(notifier) => consumers.extend(notifier.map(|notifier| Box::new(notifier) as _)),
+            Err(err) => {
+                error!("Failed to start webhook notifications -> {}", err);
+                eprintln!("{}", err)
+            }
+        }
+        // Readings are checked against the alert rules, if any are configured
+        match Alerter::from_config(conf.clone()) {
+            Ok(alerter) => consumers.extend(alerter.map(|alerter| Box::new(alerter) as _)),
+            Err(err) => {
+                error!("Failed to start alert rules -> {}", err);
+                eprintln!("{}", err)
+            }
+        }
+        match Listener::start("biomon.sqlite", consumers) {
+            Ok(started) => listener = started,
+            Err(err) => {
+                error!("Failed to listen for readings -> {}", err);
+                eprintln!("{}", err)
+            }
+        }
+        store.watch(listener.iter().map(Listener::listener).collect());
+
+        Runner::Local(conn)
     };
 
-    create_tables(&conn);
+    let code = if interactive {
+        repl(format, conf.clone(), &mut runner, listener.as_ref()).await;
+        ExitCode::SUCCESS
+    } else {
+        // The shell already split and unquoted the arguments
+        match runner.run(&args, format, conf.clone()).await {
+            Ok(rendered) => {
+                println!("{}", rendered);
+                ExitCode::SUCCESS
+            }
+            // Scripts reading JSON expect errors on stdout too
+            Err(rendered) if format == Format::Json => {
+                println!("{}", rendered);
+                ExitCode::FAILURE
+            }
+            Err(rendered) => {
+                eprintln!("{}", rendered);
+                ExitCode::FAILURE
+            }
+        }
+    };
+
+    if let Some(listener) = listener {
+        listener.stop().await;
+    }
+
+    // A daemon runs for days, and writing back the config it read at startup would undo
+    // the edits made meanwhile
+    if args.first().is_none_or(|command| command != "daemon") {
+        match write_config("biomon.ini", conf) {
+            Ok(_) => info!("Config saved"),
+            Err(err) => error!("Failed to save config -> {}", err),
+        };
+    }
+
+    code
+}
+
+// Where commands run: on the database opened here, or in a daemon
+enum Runner<'a> {
+    Local(&'a Connection),
+    #[cfg(unix)]
+    Daemon(daemon::Client),
+}
+
+impl Runner<'_> {
+    // The rendered output, as Err if the command failed
+    async fn run(
+        &mut self,
+        tokens: &[String],
+        format: Format,
+        conf: Arc<RwLock<SectionedConfigMap>>,
+    ) -> Result<String, String> {
+        match self {
+            Runner::Local(conn) => {
+                let mut input = Args::new(tokens);
+                let command = input.next().unwrap_or_default();
+
+                // Runs commands itself, so it cannot be one of them
+                let result = match command {
+                    #[cfg(unix)]
+                    "daemon" => daemon::run(conf, conn).await.map(Output::message),
+                    _ => execute(command, &mut input, conf, conn).await,
+                };
+                let rendered = output::render(&result, format);
+                result.map(|_| rendered.clone()).map_err(|_| rendered)
+            }
+            #[cfg(unix)]
+            Runner::Daemon(client) => client.execute(tokens, format).await.unwrap_or_else(|err| {
+                error!("Failed to run command in the daemon -> {}", err);
+                Err(output::render(&Err(err), format))
+            }),
+        }
+    }
+}
+
+async fn repl(
+    mut format: Format,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    runner: &mut Runner<'_>,
+    // None with a daemon, which evaluates the rules itself
+    listener: Option<&Listener>,
+) {
+    let mut editor: Editor<Completion, FileHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            error!("Failed to open line editor -> {}", err);
+            println!("Failed to open line editor: {}", err);
+            return;
+        }
+    };
+    editor.set_helper(Some(Completion::from_help(&help())));
+    // Missing on first launch
+    let _ = editor.load_history(HISTORY_PATH);
 
     println!("NOTE: Commonly used unit are implied for all entered data");
     println!("NOTE: Enter 'help' to see help");
 
-    let mut running = true;
+    // Scheduled readings missing today
+    if schedules::configured(conf.clone()) {
+        show(&["adherence", "due"], conf.clone(), runner).await;
+    }
 
-    while running {
-        let mut input = String::new();
+    // Alerts raised while no REPL was open
+    let alerting = alerts::configured(conf.clone());
+    if alerting {
+        show(&["alerts"], conf.clone(), runner).await;
+    }
 
+    loop {
         // Wait for user input
-        let _ = io::stdin().read_line(&mut input).map_err(|err| {
-            error!("Failed to read stdin -> {}", err);
-            println!("Failed to read stdin. Check log for full error.")
-        });
-
-        let mut input = input.split_whitespace();
-
-        let command = match input.next() {
-            Some(command) => command,
-            None => {
-                println!("Failed to extract command");
-                return;
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            // Ctrl-C discards the current line
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                error!("Failed to read input -> {}", err);
+                println!("Failed to read input: {}", err);
+                break;
+            }
+        };
+
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        let tokens = match args::tokenize(&line) {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                println!("{}", output::render(&Err(err), format));
+                continue;
             }
         };
+        let mut input = Args::new(&tokens);
+
+        // Switching the format is the only command that changes the session itself
+        let rendered = match input.next() {
+            Some("q") => break,
+            Some("output") => {
+                let result = match input.next().and_then(Format::parse) {
+                    Some(selected) => {
+                        format = selected;
+                        Ok(Output::message("Output format changed"))
+                    }
+                    None => Err(Error::Parse(String::from(
+                        "Expected parameter: <human | json>",
+                    ))),
+                };
+                output::render(&result, format)
+            }
+            Some(_) => match runner.run(&tokens, format, conf.clone()).await {
+                Ok(rendered) | Err(rendered) => rendered,
+            },
+            None => continue,
+        };
 
-        // Match against the input string
-        match command {
-            "help" => println!("{}", help()),
-            "weight" => println!("{}", Weight::command(&mut input, &conn)),
-            "bp" => println!("{}", BP::command(&mut input, &conn)),
-            "mood" => println!("{}", Mood::command(&mut input, &conn)),
-            "heartrate" => println!("{}", Heartrate::command(&mut input, &conn)),
-            "temp" => println!("{}", Temperature::command(&mut input, &conn)),
-            "record_hrp" => ble_hrp::start(conf.clone(), &conn).await,
-            "ingest_markdown_weight" => ingest_markdown_weight(&mut input, &conn),
-            "backup" => println!("{}", backup(&mut input, &conn)),
-            "restore" => println!("{}", restore(&mut input)),
-            "upgrade_tables" => println!("{}", upgrade_tables(&mut input, &conn)),
-            "q" => running = false,
-            _ => println!("Unknown command: {}", command),
+        println!("{}", rendered);
+
+        if alerting {
+            if let Some(listener) = listener {
+                listener.settle().await;
+            }
+            show(&["alerts", "new"], conf.clone(), runner).await;
         }
     }
 
-    match write_config("biomon.ini", conf) {
-        Ok(_) => info!("Config saved"),
-        Err(err) => error!("Failed to save config -> {}", err),
-    };
+    if let Err(err) = editor.save_history(HISTORY_PATH) {
+        error!("Failed to save history -> {}", err);
+    }
 }
 
-fn help() -> String {
-    let mut help = String::new();
-    help.push_str("List of commands:\n");
-    help.push_str(&Weight::help());
-    help.push_str(&BP::help());
-    help.push_str(&Mood::help());
-    help.push_str(&Heartrate::help());
-    help.push_str(&Temperature::help());
-    help.push_str(
-        "\trecord_hrp - Connects to BLE HRP compatible device and collects heartrate data\n",
-    );
-    help.push_str("\tingest_markdown_weight <file_path:str>\n");
-    help.push_str("\tbackup <backup_path:str> - default: ./biomon.sqlite.bak\n");
-    help.push_str("\testore <backup_path:str> - default: ./biomon.sqlite.bak\n");
-    help.push_str("\tupgrade_tables <file_path:str>\n");
-    help.push_str("\tq -> exit");
-
-    help
+// Prints what a command has to tell, like alerts or reminders, if anything
+async fn show(command: &[&str], conf: Arc<RwLock<SectionedConfigMap>>, runner: &mut Runner<'_>) {
+    let tokens: Vec<String> = command.iter().map(|token| token.to_string()).collect();
+    match runner.run(&tokens, Format::Human, conf).await {
+        Ok(rendered) | Err(rendered) if !rendered.trim().is_empty() => println!("{}", rendered),
+        _ => {}
+    }
 }
 
-fn setup_logger() -> Result<(), Box<dyn std::error::Error>> {
-    Dispatch::new()
+// Scripts only get the command output on stdout, the log still goes to biomon.log
+fn setup_logger(interactive: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut dispatch = Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
                 "{}[{}][{}] {}",
@@ -152,166 +360,374 @@ fn setup_logger() -> Result<(), Box<dyn std::error::Error>> {
             ))
         })
         .level(log::LevelFilter::Info) // Set default level
-        .chain(std::io::stdout()) // Output to stdout
-        .chain(fern::log_file("biomon.log")?)
-        .apply()?;
+        .chain(fern::log_file("biomon.log")?);
+    if interactive {
+        // Output to stdout, except while the TUI draws there
+        dispatch = dispatch.chain(
+            Dispatch::new()
+                .filter(|_| !tui::ACTIVE.load(Ordering::Relaxed))
+                .chain(std::io::stdout()),
+        );
+    }
+    dispatch.apply()?;
     Ok(())
 }
 
-fn tables(conn: &Connection) {
-    let _ = conn
-        .execute(
-            "CREATE TABLE IF NOT EXISTS instruments (
-                    id          INTEGER PRIMARY KEY,
-                    metric      TEXT UNIQUE NOT NULL,
-                    name        TEXT NOT NULL,
-                    introduced  INTEGER NOT NULL,
-                    deprecated  INTEGER,
-                    tol_min     REAL,
-                    tol_max     REAL,
-                    notes       TEXT
-                );",
-            [],
-        )
-        .map_err(|err| error!("Failed to ensure table 'instruments' exists -> {}", err));
-}
+fn read_config(path: &str) -> Result<SectionedConfigMap, Error> {
+    if !Path::new(path).exists() {
+        OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path)
+            .map_err(Error::io(format!("create missing {}", path)))?;
+        info!("Missing file create: biomon.ini");
+    }
 
-fn create_tables(conn: &Connection) {
-    tables(conn);
-    Weight::tables(conn);
-    BP::tables(conn);
-    Mood::tables(conn);
-    Heartrate::tables(conn);
-    Temperature::tables(conn);
+    let mut ini = Ini::new();
+    ini.load(path)
+        .map_err(|err| Error::Config(format!("{} {}", path, err)))
 }
 
-fn upgrade_tables(input: &mut SplitWhitespace, conn: &Connection) -> String {
-    let file = match input.next() {
-        Some(file) => file,
-        None => {
-            error!("Missing migration script path");
-            return String::from("Missing migration script path");
-        }
-    };
+fn write_config(path: &str, conf: Arc<RwLock<SectionedConfigMap>>) -> Result<(), io::Error> {
+    let mut ini = Ini::new();
 
-    let contents = match fs::read_to_string(file) {
-        Ok(contents) => contents,
-        Err(err) => {
-            error!("Failed to read migration script -> {}", err);
-            String::from("Failed to read migration script. Check log for full error.")
-        }
-    };
+    if let Err(err) = set_with_default(&mut ini, conf.clone(), "ble", "devices", None) {
+        error!(
+            "Failed to set config for section 'ble' and key 'devices' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    // Remove comments
-    let contents = contents
-        .lines()
-        .filter(|&line| !line.starts_with("--"))
-        .fold(String::new(), |mut acc, line| {
-            acc.push_str(line);
-            acc.push('\n');
-            acc
-        });
-
-    match conn.execute_batch(&contents) {
-        Ok(_) => {
-            info!("Database migrated");
-            String::from("Database migrated")
-        }
-        Err(err) => {
-            error!("Failed to run database migration script -> {}", err);
-            String::from("Failed to run database migration script. Check log for full error.")
-        }
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "ble",
+        "battery_warn",
+        Some(String::from("20")),
+    ) {
+        error!(
+            "Failed to set config for section 'ble' and key 'battery_warn' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "recording",
+        "flush_secs",
+        Some(String::from("10")),
+    ) {
+        error!(
+            "Failed to set config for section 'recording' and key 'flush_secs' -> {}",
+            err
+        );
+        return Err(err);
     }
-}
 
-fn backup(input: &mut SplitWhitespace, conn: &Connection) -> String {
-    let mut output = String::new();
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "recording",
+        "flush_samples",
+        Some(String::from("60")),
+    ) {
+        error!(
+            "Failed to set config for section 'recording' and key 'flush_samples' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    let path = match input.next() {
-        Some(param) => param,
-        None => {
-            output.push_str("Using default destination path ./biomon.sqlite.bak\n");
-            "biomon.sqlite.bak"
-        }
-    };
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "recording",
+        "collision",
+        Some(String::from("first")),
+    ) {
+        error!(
+            "Failed to set config for section 'recording' and key 'collision' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    let mut backup_conn = match Connection::open(path) {
-        Ok(backup_conn) => backup_conn,
-        Err(err) => {
-            output.push_str(&format!("Failed to create/open backup target\n{}\n", err));
-            return output;
-        }
-    };
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "retention",
+        "raw_days",
+        Some(String::from("0")),
+    ) {
+        error!(
+            "Failed to set config for section 'retention' and key 'raw_days' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    let backup = match Backup::new(conn, &mut backup_conn) {
-        Ok(backup) => backup,
-        Err(e) => {
-            output.push_str(&format!("Failed to initialize backup\n{}\n", e));
-            return output;
-        }
-    };
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "retention",
+        "minute_days",
+        Some(String::from("0")),
+    ) {
+        error!(
+            "Failed to set config for section 'retention' and key 'minute_days' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    match backup.run_to_completion(5, Duration::from_millis(250), None) {
-        Ok(_) => output.push_str("Backup done\n"),
-        Err(_) => output.push_str("Backup failed\n"),
-    };
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "retention",
+        "on_startup",
+        Some(String::from("false")),
+    ) {
+        error!(
+            "Failed to set config for section 'retention' and key 'on_startup' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    output
-}
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "http",
+        "bind",
+        Some(String::from("127.0.0.1")),
+    ) {
+        error!(
+            "Failed to set config for section 'http' and key 'bind' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-fn restore(input: &mut SplitWhitespace) -> String {
-    let mut output = String::new();
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "http",
+        "port",
+        Some(String::from("8080")),
+    ) {
+        error!(
+            "Failed to set config for section 'http' and key 'port' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    let path = match input.next() {
-        Some(param) => param,
-        None => {
-            output.push_str("Using default source path ./biomon.sqlite.bak\n");
-            "biomon.sqlite.bak"
-        }
-    };
+    if let Err(err) = set_with_default(&mut ini, conf.clone(), "http", "token", None) {
+        error!(
+            "Failed to set config for section 'http' and key 'token' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    match fs::remove_file("biomon.sqlite") {
-        Ok(_) => output.push_str("Removed database\n"),
-        Err(err) => {
-            output.push_str(&format!("Failed to remove database\n{}\n", err));
-            return output;
-        }
-    };
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "tui",
+        "max_heartrate",
+        Some(String::from("190")),
+    ) {
+        error!(
+            "Failed to set config for section 'tui' and key 'max_heartrate' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    match fs::copy(path, "biomon.sqlite") {
-        Ok(_) => output.push_str("Restore done\n"),
-        Err(_) => output.push_str("Restore failed\n"),
-    };
+    if let Err(err) = set_with_default(&mut ini, conf.clone(), "webhooks", "urls", None) {
+        error!(
+            "Failed to set config for section 'webhooks' and key 'urls' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    output
-}
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "webhooks",
+        "thresholds",
+        Some(String::from("sys >= 180, dia >= 120, temperature > 38.5")),
+    ) {
+        error!(
+            "Failed to set config for section 'webhooks' and key 'thresholds' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-fn read_config(path: &str) -> Result<SectionedConfigMap, String> {
-    if !Path::new(path).exists() {
-        match OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(path)
-        {
-            Ok(_) => info!("Missing file create: biomon.ini"),
-            Err(err) => {
-                error!("Failed to create missing file: biomon.ini -> {}", err);
-                return Err(err.to_string());
-            }
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "webhooks",
+        "attempts",
+        Some(String::from("5")),
+    ) {
+        error!(
+            "Failed to set config for section 'webhooks' and key 'attempts' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(&mut ini, conf.clone(), "alerts", "rules", None) {
+        error!(
+            "Failed to set config for section 'alerts' and key 'rules' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    // No metric is scheduled by default
+    for metric in METRICS {
+        if let Err(err) = set_with_default(&mut ini, conf.clone(), "schedules", metric, None) {
+            error!(
+                "Failed to set config for section 'schedules' and key '{}' -> {}",
+                metric, err
+            );
+            return Err(err);
         }
     }
 
-    let mut ini = Ini::new();
-    ini.load(path)
-}
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "daemon",
+        "socket",
+        Some(String::from("biomon.sock")),
+    ) {
+        error!(
+            "Failed to set config for section 'daemon' and key 'socket' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-fn write_config(path: &str, conf: Arc<RwLock<SectionedConfigMap>>) -> Result<(), io::Error> {
-    let mut ini = Ini::new();
+    if let Err(err) = set_with_default(&mut ini, conf.clone(), "mqtt", "host", None) {
+        error!(
+            "Failed to set config for section 'mqtt' and key 'host' -> {}",
+            err
+        );
+        return Err(err);
+    }
 
-    if let Err(err) = set_with_default(&mut ini, conf, "ble_hrp", "hrp_mac", None) {
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "mqtt",
+        "port",
+        Some(String::from("1883")),
+    ) {
         error!(
-            "Failed to set config for section 'ble_hrp' and key 'hrp_mac' -> {}",
+            "Failed to set config for section 'mqtt' and key 'port' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "mqtt",
+        "client_id",
+        Some(String::from("biomon")),
+    ) {
+        error!(
+            "Failed to set config for section 'mqtt' and key 'client_id' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(&mut ini, conf.clone(), "mqtt", "username", None) {
+        error!(
+            "Failed to set config for section 'mqtt' and key 'username' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(&mut ini, conf.clone(), "mqtt", "password", None) {
+        error!(
+            "Failed to set config for section 'mqtt' and key 'password' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(&mut ini, conf.clone(), "mqtt", "subscribe", None) {
+        error!(
+            "Failed to set config for section 'mqtt' and key 'subscribe' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "mqtt",
+        "topic",
+        Some(String::from("biomon")),
+    ) {
+        error!(
+            "Failed to set config for section 'mqtt' and key 'topic' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "mqtt",
+        "discovery",
+        Some(String::from("true")),
+    ) {
+        error!(
+            "Failed to set config for section 'mqtt' and key 'discovery' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf.clone(),
+        "mqtt",
+        "discovery_prefix",
+        Some(String::from("homeassistant")),
+    ) {
+        error!(
+            "Failed to set config for section 'mqtt' and key 'discovery_prefix' -> {}",
+            err
+        );
+        return Err(err);
+    }
+
+    if let Err(err) = set_with_default(
+        &mut ini,
+        conf,
+        "daemon",
+        "maintain_hours",
+        Some(String::from("24")),
+    ) {
+        error!(
+            "Failed to set config for section 'daemon' and key 'maintain_hours' -> {}",
             err
         );
         return Err(err);
@@ -328,10 +744,7 @@ fn set_with_default(
     default: Option<String>,
 ) -> Result<(), io::Error> {
     let conf = conf.read().map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to aquire lock on config map -> {}", err),
-        )
+        io::Error::other(format!("Failed to aquire lock on config map -> {}", err))
     })?;
 
     let value = conf
@@ -343,67 +756,3 @@ fn set_with_default(
     ini.set(section, key, value);
     Ok(())
 }
-
-fn ingest_markdown_weight(input: &mut SplitWhitespace, conn: &Connection) {
-    let file = match input.next() {
-        Some(file) => file,
-        None => return,
-    };
-
-    let contents = match fs::read_to_string(file) {
-        Ok(contents) => contents,
-        Err(err) => {
-            error!("Failed to read weight file -> {}", err);
-            return;
-        }
-    };
-
-    for line in contents.lines() {
-        // - 2023-12-03: 105.4kg
-        let line = line.replace("- ", "");
-        // 2023-12-03: 105.4kg
-        let mut parts = line.split(':');
-        // ["2023-12-03", " 105.4kg"]
-        let date = match parts.next() {
-            Some(date) => date,
-            None => continue,
-        };
-        let mut date_parts = date.split('-');
-        let year = match date_parts.next().and_then(|year| year.parse::<i32>().ok()) {
-            Some(year) => year,
-            None => continue,
-        };
-        let month = match date_parts
-            .next()
-            .and_then(|month| month.parse::<u32>().ok())
-        {
-            Some(month) => month,
-            None => continue,
-        };
-        let day = match date_parts.next().and_then(|day| day.parse::<u32>().ok()) {
-            Some(day) => day,
-            None => continue,
-        };
-        // assume measurements were taken at 09:00
-        let timestamp = Utc
-            .with_ymd_and_hms(year, month, day, 9, 0, 0)
-            .unwrap()
-            .timestamp();
-
-        let weight = match parts.next() {
-            Some(weight) => weight.trim().replace("kg", ""),
-            None => continue,
-        };
-
-        match conn.execute(
-            "INSERT INTO weight (timestamp, weight) VALUES (?1, ?2);",
-            params![timestamp, weight],
-        ) {
-            Ok(_) => println!("Recorded weight: {}kg", weight),
-            Err(err) => {
-                error!("Failed to write weight to database -> {}", err);
-                println!("Failed to write weight to database. Check log for full error.");
-            }
-        };
-    }
-}
@@ -1,15 +1,44 @@
-use std::str::SplitWhitespace;
-
-use chrono::Utc;
 use log::error;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, Params, Row};
+
+use crate::{
+    args::Args,
+    output::{Field, Output, Record, Value},
+    utils, Error, Stat,
+};
+
+// A mood named like one of these would run it instead of being recorded
+pub const SUBCOMMANDS: [&str; 2] = ["last", "range"];
+
+pub struct MoodORM {
+    pub id: i64,
+    pub timestamp: i64,
+    pub mood: String,
+}
 
-use crate::{utils, Stat};
+impl MoodORM {
+    fn read(row: &Row) -> Result<MoodORM, rusqlite::Error> {
+        Ok(MoodORM {
+            id: row.get(0)?,
+            timestamp: row.get(1)?,
+            mood: row.get(2)?,
+        })
+    }
 
-struct MoodORM {
-    _id: i64,
-    timestamp: i64,
-    mood: String,
+    pub fn record(self) -> Record {
+        Record {
+            id: self.id,
+            timestamp: self.timestamp,
+            duration: None,
+            source: None,
+            fields: vec![Field {
+                name: "mood",
+                value: Value::Text(self.mood),
+                unit: "",
+                label: "{}",
+            }],
+        }
+    }
 }
 
 pub struct Mood;
@@ -28,93 +57,100 @@ impl Stat for Mood {
             .map_err(|err| error!("Failed to ensure table 'mood' exists -> {}", err));
     }
 
-    fn command(input: &mut SplitWhitespace, conn: &Connection) -> String {
+    fn command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
         let param = match input.next() {
             Some(param) => param,
-            None => return String::from("No further parameters"),
+            None => return Err(Error::Parse(String::from("No further parameters"))),
         };
 
         match param {
-            "last" => last(input, conn),
+            "last" => last_command(input, conn),
+            "range" => range_command(input, conn),
             _ => {
-                let timestamp = Utc::now().timestamp();
-                match conn.execute(
-                    "INSERT INTO mood (timestamp, mood) VALUES (?1, ?2);",
-                    params![timestamp, param],
-                ) {
-                    Ok(_) => format!("Recorded mood: {}", param),
-                    Err(err) => {
-                        error!("Failed to write mood to database -> {}", err);
-                        String::from("Failed to write mood to database. Check log for full error.")
-                    }
-                }
+                let entry = insert(utils::entry_time(), param, conn)
+                    .map_err(Error::database("write mood"))?;
+                Ok(Output::Recorded {
+                    metric: "mood",
+                    record: entry.record(),
+                })
             }
         }
     }
 
     fn help() -> String {
-        String::from("\tmood <last <count:i64> | mood:str>\n")
+        String::from("\tmood <last <count:i64> | range <from> <to> | mood:str>\n")
     }
 }
 
-fn last(input: &mut SplitWhitespace, conn: &Connection) -> String {
-    let mut output = String::new();
+pub fn insert(timestamp: i64, mood: &str, conn: &Connection) -> Result<MoodORM, rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO mood (timestamp, mood) VALUES (?1, ?2);",
+        params![timestamp, mood],
+    )?;
+
+    Ok(MoodORM {
+        id: conn.last_insert_rowid(),
+        timestamp,
+        mood: String::from(mood),
+    })
+}
+
+// The latest count entries, newest first
+pub fn last(count: i64, conn: &Connection) -> Result<Vec<MoodORM>, rusqlite::Error> {
+    query("ORDER BY timestamp DESC LIMIT (?1)", [count], conn)
+}
+
+// Entries from from up to but excluding to, oldest first
+pub fn range(from: i64, to: i64, conn: &Connection) -> Result<Vec<MoodORM>, rusqlite::Error> {
+    query(
+        "WHERE timestamp >= ?1 AND timestamp < ?2 ORDER BY timestamp ASC",
+        [from, to],
+        conn,
+    )
+}
+
+fn query<P: Params>(
+    clause: &str,
+    params: P,
+    conn: &Connection,
+) -> Result<Vec<MoodORM>, rusqlite::Error> {
+    let mut query = conn.prepare(&format!("SELECT id, timestamp, mood FROM mood {};", clause))?;
+    let entries = query.query_map(params, MoodORM::read)?.collect();
+    entries
+}
+
+fn last_command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+    let mut notes = Vec::new();
 
     let take_default = 3;
     let take = match input.next() {
         Some(take) => take.parse::<i64>().unwrap_or_else(|_| {
-            output.push_str(&format!(
-                "Failed to parse query parameter\nUsing default query parameter {}\n",
-                take_default
-            ));
+            notes.push(String::from("Failed to parse query parameter"));
+            notes.push(format!("Using default query parameter {}", take_default));
             take_default
         }),
         None => {
-            output.push_str(&format!("Using default query parameter {}\n", take_default));
+            notes.push(format!("Using default query parameter {}", take_default));
             take_default
         }
     };
 
-    let mut query = match conn.prepare(
-        "
-        SELECT id, timestamp, mood
-        FROM mood
-        ORDER BY timestamp DESC
-        LIMIT (?1);
-    ",
-    ) {
-        Ok(query) => query,
-        Err(err) => {
-            error!("Failed to prepare query 'last' for mood -> {}", err);
-            output.push_str("Failed to prepare query 'last' for mood. Check log for full error.");
-            return output;
-        }
-    };
+    let entries = last(take, conn).map_err(Error::database(format!(
+        "read the last {} mood entries",
+        take
+    )))?;
+    Ok(Output::Records {
+        notes,
+        records: entries.into_iter().map(MoodORM::record).collect(),
+    })
+}
 
-    let results = query.query_map([take], |row| {
-        Ok(MoodORM {
-            _id: row.get(0)?,
-            timestamp: row.get(1)?,
-            mood: row.get(2)?,
-        })
-    });
-
-    match results {
-        Ok(results) => {
-            for result in results {
-                let result = result.unwrap();
-                output.push_str(&format!(
-                    "{}, recorded {}\n",
-                    result.mood,
-                    utils::format_timestamp(result.timestamp)
-                ));
-            }
-        }
-        Err(err) => output.push_str(&format!(
-            "Failed to retrieve last {} entries: {}\n",
-            take, err
-        )),
-    }
+fn range_command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+    let (from, to) = utils::parse_range(input)?;
 
-    output
+    let entries = range(from, to, conn).map_err(Error::database("read mood entries"))?;
+    Ok(Output::Records {
+        notes: Vec::new(),
+        records: entries.into_iter().map(MoodORM::record).collect(),
+    })
 }
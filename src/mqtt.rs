@@ -0,0 +1,703 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, Publish, QoS};
+use rusqlite::{Connection, ErrorCode};
+use serde_json::{json, Value};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time::sleep,
+};
+
+use crate::{
+    args::Args,
+    http::{Metric, METRICS},
+    output::Output,
+    store::{self, Consumer, Reading},
+    utils, Error, SectionedConfigMap,
+};
+
+// Home Assistant sensors: table, column, name, unit and device class
+const SENSORS: [(&str, &str, &str, &str, Option<&str>); 8] = [
+    ("weight", "weight", "Weight", "kg", Some("weight")),
+    ("bp", "sys", "Systolic pressure", "mmHg", None),
+    ("bp", "dia", "Diastolic pressure", "mmHg", None),
+    ("mood", "mood", "Mood", "", None),
+    ("heartrate", "heartrate", "Heart rate", "bpm", None),
+    (
+        "temperature",
+        "temperature",
+        "Temperature",
+        "°C",
+        Some("temperature"),
+    ),
+    ("spo2", "spo2", "SpO2", "%", None),
+    ("spo2", "pulse", "Pulse", "bpm", None),
+];
+
+pub struct Settings {
+    options: MqttOptions,
+    // Readings of a table go to <topic>/<table>
+    topic: String,
+    // Prefix of the Home Assistant discovery topics, None to not announce sensors
+    discovery: Option<String>,
+    node: String,
+    sources: Vec<Source>,
+}
+
+// A topic whose messages are stored as readings of a metric
+struct Source {
+    metric: &'static Metric,
+    // May hold the wildcards + and #
+    topic: String,
+    // JSON paths of the values, empty if the payload is the values themselves
+    paths: Vec<String>,
+    // JSON path of the time the reading was taken
+    timestamp: Option<String>,
+}
+
+impl Settings {
+    // None without a host in section mqtt
+    pub fn from_config(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Option<Settings>, Error> {
+        let setting =
+            |key: &str, default: &str| utils::from_config_or(conf.clone(), "mqtt", key, default);
+
+        let host = setting("host", "");
+        if host.is_empty() {
+            return Ok(None);
+        }
+        let port = setting("port", "1883");
+        let port = port.parse::<u16>().map_err(|_| {
+            Error::Config(format!(
+                "port in section mqtt must be a port number, not {}",
+                port
+            ))
+        })?;
+
+        let node = setting("client_id", "biomon");
+        let mut options = MqttOptions::new(node.clone(), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let username = setting("username", "");
+        if !username.is_empty() {
+            options.set_credentials(username, setting("password", ""));
+        }
+
+        let discovery = match setting("discovery", "true").as_str() {
+            "true" => Some(setting("discovery_prefix", "homeassistant")),
+            "false" => None,
+            other => {
+                return Err(Error::Config(format!(
+                    "discovery in section mqtt must be true or false, not {}",
+                    other
+                )))
+            }
+        };
+
+        let topic = setting("topic", "biomon");
+        let sources = sources(&setting("subscribe", ""))?;
+        // Readings published by biomon would come back and be stored again
+        for source in &sources {
+            if store::METRICS
+                .iter()
+                .any(|table| matches(&source.topic, &format!("{}/{}", topic, table)))
+            {
+                return Err(Error::Config(format!(
+                    "subscribe topic {} in section mqtt includes the readings biomon publishes to {}",
+                    source.topic, topic
+                )));
+            }
+        }
+
+        Ok(Some(Settings {
+            options,
+            topic,
+            discovery,
+            node,
+            sources,
+        }))
+    }
+
+    // Its own client id, as a broker drops the older of two connections with one id
+    fn subscriber_options(&self) -> MqttOptions {
+        let (host, port) = self.options.broker_address();
+        let mut options = MqttOptions::new(format!("{}-subscriber", self.node), host, port);
+        options.set_keep_alive(self.options.keep_alive());
+        if let Some((username, password)) = self.options.credentials() {
+            options.set_credentials(username, password);
+        }
+        options
+    }
+}
+
+// Configured as `subscribe = <metric> <topic> [<path> ...] [@<path>], ...` in section
+// mqtt. Without paths the payload is the values separated by spaces, like `37.2` or
+// `120 80`. With paths it is JSON, and each path, like $.temp or $.values.0, picks a
+// value in the order the metric takes them. @<path> picks the time the reading was
+// taken, otherwise it is the time the message arrived.
+fn sources(entries: &str) -> Result<Vec<Source>, Error> {
+    entries
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let mut parts = entry.split_whitespace();
+            let (Some(metric), Some(topic)) = (parts.next(), parts.next()) else {
+                return Err(Error::Config(format!(
+                    "subscribe entry '{}' in section mqtt needs a metric and a topic",
+                    entry.trim()
+                )));
+            };
+            // Mood is text, which would reach the mood subcommands
+            let metric = METRICS
+                .iter()
+                .find(|candidate| candidate.path == metric && candidate.path != "mood")
+                .ok_or_else(|| {
+                    Error::Config(format!(
+                        "Cannot subscribe to {} in section mqtt, use weight, bp, heartrate, temperature or spo2",
+                        metric
+                    ))
+                })?;
+
+            let (timestamp, paths): (Vec<&str>, Vec<&str>) =
+                parts.partition(|part| part.starts_with('@'));
+            if !paths.is_empty() && paths.len() != metric.fields.len() {
+                return Err(Error::Config(format!(
+                    "subscribe entry '{}' in section mqtt needs a path for each of {}",
+                    entry.trim(),
+                    metric.fields.join(", ")
+                )));
+            }
+            if paths.is_empty() && !timestamp.is_empty() {
+                return Err(Error::Config(format!(
+                    "subscribe entry '{}' in section mqtt reads the time from JSON, so its values need paths too",
+                    entry.trim()
+                )));
+            }
+
+            Ok(Source {
+                metric,
+                topic: String::from(topic),
+                paths: paths.into_iter().map(String::from).collect(),
+                timestamp: timestamp.first().map(|path| String::from(&path[1..])),
+            })
+        })
+        .collect()
+}
+
+// Whether a topic filter with the wildcards + and # matches a topic
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(actual)) if level == actual => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+// A JSON path like $.sensor.temp or values.0
+fn lookup<'a>(json: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.trim_start_matches('$').trim_start_matches('.');
+    path.split('.')
+        .filter(|key| !key.is_empty())
+        .try_fold(json, |value, key| match value {
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+            _ => value.get(key),
+        })
+}
+
+// Publishes the readings committed to the database, see Listener
+pub struct Publisher {
+    readings: UnboundedSender<Reading>,
+    publishing: JoinHandle<()>,
+    network: JoinHandle<()>,
+}
+
+impl Publisher {
+    // None if no broker is configured
+    pub fn start(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Option<Publisher>, Error> {
+        let settings = match Settings::from_config(conf)? {
+            Some(settings) => settings,
+            None => return Ok(None),
+        };
+
+        info!(
+            "Publishing readings to MQTT broker {}:{}",
+            settings.options.broker_address().0,
+            settings.options.broker_address().1
+        );
+        let (client, eventloop) = AsyncClient::new(settings.options.clone(), 100);
+        let (readings, received) = unbounded_channel();
+
+        Ok(Some(Publisher {
+            readings,
+            network: tokio::spawn(network(eventloop, client.clone(), Vec::new(), None)),
+            publishing: tokio::spawn(publish(received, client, settings)),
+        }))
+    }
+}
+
+impl Consumer for Publisher {
+    // Publishing waits on the broker, so it happens in a task of its own
+    fn consume(&mut self, reading: &Reading, _: &Connection) {
+        let _ = self.readings.send(reading.clone());
+    }
+
+    // Publishes the readings still queued and disconnects
+    fn finish(self: Box<Self>) -> BoxFuture<'static, ()> {
+        let Publisher {
+            readings,
+            publishing,
+            network,
+        } = *self;
+        drop(readings);
+        Box::pin(async move {
+            let _ = publishing.await;
+            let _ = network.await;
+        })
+    }
+}
+
+// Until the sender of readings is dropped
+async fn publish(
+    mut readings: UnboundedReceiver<Reading>,
+    client: AsyncClient,
+    settings: Settings,
+) {
+    if let Some(prefix) = &settings.discovery {
+        announce(&client, prefix, &settings).await;
+    }
+
+    while let Some(reading) = readings.recv().await {
+        send(&client, &settings.topic, reading).await;
+    }
+
+    if let Err(err) = client.disconnect().await {
+        error!("Failed to disconnect from MQTT broker -> {}", err);
+    }
+}
+
+async fn send(client: &AsyncClient, topic: &str, reading: Reading) {
+    let topic = format!("{}/{}", topic, reading.table);
+    let payload = Value::Object(reading.record).to_string();
+    // Retained, so subscribers see the latest reading of metrics taken once a day
+    if let Err(err) = client
+        .publish(&topic, QoS::AtLeastOnce, true, payload)
+        .await
+    {
+        error!("Failed to publish to {} -> {}", topic, err);
+    }
+}
+
+// Home Assistant discovery, retained so sensors reappear after a restart of it
+async fn announce(client: &AsyncClient, prefix: &str, settings: &Settings) {
+    for (topic, config) in discovery(prefix, settings) {
+        if let Err(err) = client
+            .publish(&topic, QoS::AtLeastOnce, true, config.to_string())
+            .await
+        {
+            error!("Failed to publish to {} -> {}", topic, err);
+        }
+    }
+}
+
+// The config topic and payload of each sensor
+fn discovery(prefix: &str, settings: &Settings) -> Vec<(String, Value)> {
+    let mut configs = Vec::new();
+    for (table, column, name, unit, device_class) in SENSORS {
+        let mut config = json!({
+            "name": name,
+            "unique_id": format!("{}_{}_{}", settings.node, table, column),
+            "state_topic": format!("{}/{}", settings.topic, table),
+            "value_template": format!("{{{{ value_json.{} }}}}", column),
+            "device": {
+                "identifiers": [settings.node],
+                "name": "biomon",
+            },
+        });
+        // Text like mood has neither a unit nor a history of values to average
+        if !unit.is_empty() {
+            config["unit_of_measurement"] = json!(unit);
+            config["state_class"] = json!("measurement");
+        }
+        if let Some(device_class) = device_class {
+            config["device_class"] = json!(device_class);
+        }
+
+        let topic = format!(
+            "{}/sensor/{}/{}_{}/config",
+            prefix, settings.node, table, column
+        );
+        configs.push((topic, config));
+    }
+    configs
+}
+
+// Stores the messages of the topics in subscribe of section mqtt
+pub struct Subscriber {
+    sources: Vec<Source>,
+    messages: UnboundedReceiver<Publish>,
+    network: JoinHandle<()>,
+}
+
+impl Subscriber {
+    // None if no broker or no topics are configured
+    pub fn start(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Option<Subscriber>, Error> {
+        let settings = match Settings::from_config(conf)? {
+            Some(settings) if !settings.sources.is_empty() => settings,
+            _ => return Ok(None),
+        };
+
+        let topics: Vec<String> = settings
+            .sources
+            .iter()
+            .map(|source| source.topic.clone())
+            .collect();
+        info!("Subscribing to MQTT topics {}", topics.join(", "));
+        let (client, eventloop) = AsyncClient::new(settings.subscriber_options(), 10);
+        let (messages, received) = unbounded_channel();
+
+        Ok(Some(Subscriber {
+            sources: settings.sources,
+            messages: received,
+            network: tokio::spawn(network(eventloop, client, topics, Some(messages))),
+        }))
+    }
+
+    pub async fn next(&mut self) -> Option<Publish> {
+        self.messages.recv().await
+    }
+
+    // Stores a message for each topic it matches, returning how many readings were new.
+    // A reading already stored at its timestamp is skipped.
+    pub fn store(&self, message: &Publish, conn: &Connection) -> usize {
+        let mut stored = 0;
+        for source in self
+            .sources
+            .iter()
+            .filter(|source| matches(&source.topic, &message.topic))
+        {
+            // A retained message may be days old and already stored
+            if message.retain && source.timestamp.is_none() {
+                info!(
+                    "Skipped retained message on {}, the time it was taken is unknown",
+                    message.topic
+                );
+                continue;
+            }
+
+            match record(source, message, conn) {
+                Ok(_) => {
+                    info!("Stored {} from {}", source.metric.path, message.topic);
+                    stored += 1;
+                }
+                Err(Error::Database(_, err))
+                    if err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) =>
+                {
+                    info!(
+                        "Skipped {} from {}, already stored",
+                        source.metric.path, message.topic
+                    )
+                }
+                Err(err) => error!("Failed to store message on {} -> {}", message.topic, err),
+            }
+        }
+        stored
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.network.abort();
+    }
+}
+
+// Stores a reading through the same command as typing it into the REPL does
+fn record(source: &Source, message: &Publish, conn: &Connection) -> Result<Output, Error> {
+    let payload = std::str::from_utf8(&message.payload)
+        .map_err(|_| Error::Parse(String::from("Payload is not text")))?;
+
+    let (tokens, timestamp) = if source.paths.is_empty() {
+        let tokens: Vec<String> = payload.split_whitespace().map(String::from).collect();
+        (tokens, None)
+    } else {
+        let json: Value = serde_json::from_str(payload)
+            .map_err(|err| Error::Parse(format!("Invalid JSON payload: {}", err)))?;
+        let tokens = source
+            .paths
+            .iter()
+            .map(|path| match lookup(&json, path) {
+                Some(Value::Number(value)) => Ok(value.to_string()),
+                Some(Value::String(value)) => Ok(value.clone()),
+                _ => Err(Error::Parse(format!("No value at {} in {}", path, payload))),
+            })
+            .collect::<Result<Vec<String>, Error>>()?;
+        let timestamp = match &source.timestamp {
+            Some(path) => Some(
+                match lookup(&json, path) {
+                    Some(Value::Number(timestamp)) => timestamp.as_i64(),
+                    Some(Value::String(timestamp)) => utils::parse_timestamp(timestamp),
+                    _ => None,
+                }
+                .ok_or_else(|| Error::Parse(format!("No timestamp at {} in {}", path, payload)))?,
+            ),
+            None => None,
+        };
+        (tokens, timestamp)
+    };
+
+    // Anything else would reach the subcommands of the metric
+    if tokens.len() != source.metric.fields.len()
+        || tokens.iter().any(|token| token.parse::<f64>().is_err())
+    {
+        return Err(Error::Parse(format!(
+            "Expected {} as numbers, got {}",
+            source.metric.fields.join(", "),
+            payload
+        )));
+    }
+
+    let mut input = Args::new(&tokens);
+    match timestamp {
+        Some(timestamp) => {
+            utils::ENTRY_TIME.sync_scope(timestamp, || (source.metric.command)(&mut input, conn))
+        }
+        None => (source.metric.command)(&mut input, conn),
+    }
+}
+
+// Stores the readings published to the subscribed topics until Ctrl-C
+pub async fn subscribe(
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let Some(mut subscriber) = Subscriber::start(conf)? else {
+        return Err(Error::Config(String::from(
+            "Set host and subscribe in section mqtt to subscribe",
+        )));
+    };
+
+    println!("Storing readings published to the topics in section mqtt");
+    println!("Press Ctrl-C to stop");
+
+    let mut stored = 0;
+    loop {
+        tokio::select! {
+            Some(message) = subscriber.next() => stored += subscriber.store(&message, conn),
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    Ok(format!("Stored {} readings", stored))
+}
+
+// Drives a connection, reconnecting until the client disconnects. Sessions are clean,
+// so topics are subscribed again on each connect, and their messages are forwarded.
+// Holds a client so the requests stay open until the broker closed the connection, as
+// closing it with acks unread would reset it and lose what the broker has not read yet.
+async fn network(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    topics: Vec<String>,
+    messages: Option<UnboundedSender<Publish>>,
+) {
+    let mut reachable = true;
+    let mut disconnecting = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                reachable = true;
+                for topic in &topics {
+                    if let Err(err) = client.try_subscribe(topic.clone(), QoS::AtLeastOnce) {
+                        error!("Failed to subscribe to {} -> {}", topic, err);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(message))) => {
+                if let Some(messages) = &messages {
+                    let _ = messages.send(message);
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => disconnecting = true,
+            Ok(_) => {}
+            Err(_) if disconnecting => return,
+            Err(err) => {
+                // Logged once per outage rather than on every retry
+                if reachable {
+                    warn!("MQTT broker unreachable, retrying -> {}", err);
+                    reachable = false;
+                }
+                sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::Store;
+
+    fn conf(entries: &[(&str, &str)]) -> Arc<RwLock<SectionedConfigMap>> {
+        let section = entries
+            .iter()
+            .map(|(key, value)| (String::from(*key), Some(String::from(*value))))
+            .collect();
+        Arc::new(RwLock::new(HashMap::from([(
+            String::from("mqtt"),
+            section,
+        )])))
+    }
+
+    fn source(entry: &str) -> Source {
+        sources(entry).unwrap().remove(0)
+    }
+
+    #[test]
+    fn filters_match_with_wildcards() {
+        assert!(matches("home/+/temp", "home/bath/temp"));
+        assert!(matches("home/#", "home/bath/temp"));
+        assert!(matches("home/#", "home"));
+        assert!(matches("home/bath", "home/bath"));
+        assert!(!matches("home/+", "home/bath/temp"));
+        assert!(!matches("home/bath/temp", "home/bath"));
+        assert!(!matches("home/kitchen", "home/bath"));
+    }
+
+    #[test]
+    fn paths_pick_values_from_json() {
+        let json = json!({ "sensor": { "temp": 37.2 }, "values": [120, 80] });
+        assert_eq!(lookup(&json, "$.sensor.temp"), Some(&json!(37.2)));
+        assert_eq!(lookup(&json, "values.1"), Some(&json!(80)));
+        assert_eq!(lookup(&json, "$.values.x"), None);
+        assert_eq!(lookup(&json, "$.missing"), None);
+    }
+
+    #[test]
+    fn subscribe_entries_need_a_path_per_field() {
+        let parsed = sources("temperature home/+/temp, bp home/bp $.sys $.dia @$.time").unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].paths, vec!["$.sys", "$.dia"]);
+        assert_eq!(parsed[1].timestamp.as_deref(), Some("$.time"));
+
+        assert!(sources("weight").is_err());
+        assert!(sources("mood home/mood").is_err());
+        assert!(sources("bp home/bp $.sys").is_err());
+        assert!(sources("temperature home/temp @$.time").is_err());
+    }
+
+    #[test]
+    fn subscribing_to_published_readings_is_refused() {
+        let settings = conf(&[("host", "localhost"), ("subscribe", "weight biomon/#")]);
+        assert!(Settings::from_config(settings).is_err());
+        let settings = conf(&[("host", "localhost"), ("subscribe", "weight scale/weight")]);
+        assert!(Settings::from_config(settings).unwrap().is_some());
+    }
+
+    #[test]
+    fn payloads_are_stored_only_as_numbers() {
+        let store = Store::open(":memory:").unwrap();
+        let conn = store.connection();
+        let temperature = source("temperature home/temp");
+        let message = |payload: &str| Publish::new("home/temp", QoS::AtLeastOnce, payload);
+
+        assert!(record(&temperature, &message("37.5"), conn).is_ok());
+        for payload in ["last 5", "export /tmp/biomon-mqtt.csv", "NaN", "37 38", ""] {
+            assert!(record(&temperature, &message(payload), conn).is_err());
+        }
+        assert_eq!(store.temperature().last(10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn json_payloads_carry_their_timestamp() {
+        let store = Store::open(":memory:").unwrap();
+        let conn = store.connection();
+        let bp = source("bp home/bp $.sys $.dia @$.time");
+        let message = |payload: &str| Publish::new("home/bp", QoS::AtLeastOnce, payload);
+
+        let payload = r#"{ "sys": 120, "dia": 80, "time": 1790000000 }"#;
+        assert!(record(&bp, &message(payload), conn).is_ok());
+        let stored = store.last_bp(1).unwrap();
+        assert_eq!(stored[0].timestamp, 1790000000);
+        assert_eq!((stored[0].sys, stored[0].dia), (120, 80));
+
+        assert!(record(&bp, &message(r#"{ "sys": 120, "time": 1 }"#), conn).is_err());
+        assert!(record(&bp, &message(r#"{ "sys": 120, "dia": 80 }"#), conn).is_err());
+        assert!(record(&bp, &message("120 80"), conn).is_err());
+    }
+
+    #[test]
+    fn sensors_are_announced_with_units() {
+        let settings = Settings::from_config(conf(&[("host", "localhost")]))
+            .unwrap()
+            .unwrap();
+        let configs = discovery("homeassistant", &settings);
+        assert_eq!(configs.len(), SENSORS.len());
+
+        let (topic, weight) = &configs[0];
+        assert_eq!(topic, "homeassistant/sensor/biomon/weight_weight/config");
+        assert_eq!(weight["state_topic"], "biomon/weight");
+        assert_eq!(weight["value_template"], "{{ value_json.weight }}");
+        assert_eq!(weight["unit_of_measurement"], "kg");
+        assert_eq!(weight["device_class"], "weight");
+
+        let (_, mood) = &configs[3];
+        assert!(mood.get("unit_of_measurement").is_none());
+        assert!(mood.get("state_class").is_none());
+    }
+
+    // Needs a broker like mosquitto on localhost:1883 and passes without one
+    #[tokio::test]
+    async fn subscriber_stores_readings_from_a_local_broker() {
+        if std::net::TcpStream::connect("127.0.0.1:1883").is_err() {
+            eprintln!("No MQTT broker on localhost:1883, skipped");
+            return;
+        }
+        let topic = format!("biomon-test/{}/temp", std::process::id());
+        let settings = conf(&[
+            ("host", "127.0.0.1"),
+            ("client_id", "biomon-test"),
+            ("subscribe", &format!("temperature {}", topic)),
+        ]);
+        let mut subscriber = Subscriber::start(settings).unwrap().unwrap();
+
+        let (client, mut eventloop) = AsyncClient::new(
+            MqttOptions::new("biomon-test-publisher", "127.0.0.1", 1883),
+            10,
+        );
+        let publishing = tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+        // The subscription may not be in place yet, so publish until a message arrives
+        let message = timeout(Duration::from_secs(10), async {
+            loop {
+                client
+                    .publish(&topic, QoS::AtLeastOnce, false, "37.5")
+                    .await
+                    .unwrap();
+                if let Ok(Some(message)) =
+                    timeout(Duration::from_millis(500), subscriber.next()).await
+                {
+                    return message;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        publishing.abort();
+
+        let store = Store::open(":memory:").unwrap();
+        assert_eq!(subscriber.store(&message, store.connection()), 1);
+    }
+}
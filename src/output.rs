@@ -0,0 +1,331 @@
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{instruments::Instrument, utils, Error};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "human" => Some(Format::Human),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Real(value) => write!(f, "{}", value),
+            Value::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// Widens through the shortest decimal, so 36.6f32 stays 36.6 instead of 36.59999847
+pub fn real(value: f32) -> Value {
+    Value::Real(value.to_string().parse().unwrap_or(value as f64))
+}
+
+#[derive(Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    pub value: Value,
+    pub unit: &'static str,
+    // How humans read it, with {} standing for value and unit, e.g. "pulse {}"
+    #[serde(skip)]
+    pub label: &'static str,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = format!("{}{}", self.value, self.unit);
+        write!(f, "{}", self.label.replace("{}", &value))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Record {
+    pub id: i64,
+    pub timestamp: i64,
+    // Seconds covered by a sample of a continuous metric
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<i64>,
+    // Name of the instrument the sample came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub fields: Vec<Field>,
+}
+
+impl Record {
+    pub fn values(&self) -> String {
+        self.fields
+            .iter()
+            .map(Field::to_string)
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+#[derive(Serialize)]
+pub struct ColumnStats {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+// Stats of the samples starting in one step of a trend
+#[derive(Serialize)]
+pub struct Bucket {
+    pub timestamp: i64,
+    pub samples: i64,
+    pub columns: Vec<ColumnStats>,
+}
+
+// Scheduled readings of one week that were recorded, only counting the slots that ended
+// or were recorded
+#[derive(Serialize)]
+pub struct Week {
+    pub timestamp: i64,
+    pub scheduled: i64,
+    pub completed: i64,
+    pub percent: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Adherence {
+    pub metric: &'static str,
+    // Daily time windows, e.g. 06:00-10:00
+    pub slots: Vec<String>,
+    // Days in a row all scheduled readings were recorded
+    pub current_streak: i64,
+    pub longest_streak: i64,
+    pub weeks: Vec<Week>,
+}
+
+// What a command returns, rendered for humans or as JSON
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Output {
+    Message {
+        message: String,
+    },
+    Recorded {
+        metric: &'static str,
+        record: Record,
+    },
+    Records {
+        // Remarks like falling back to a default parameter
+        notes: Vec<String>,
+        records: Vec<Record>,
+    },
+    Stats {
+        metric: &'static str,
+        // Seconds covered by samples in the range
+        samples: i64,
+        columns: Vec<ColumnStats>,
+    },
+    Trend {
+        metric: &'static str,
+        // Seconds per bucket
+        step: i64,
+        buckets: Vec<Bucket>,
+    },
+    Instruments {
+        instruments: Vec<Instrument>,
+    },
+    Adherence {
+        metrics: Vec<Adherence>,
+    },
+}
+
+impl Output {
+    pub fn message(message: impl Into<String>) -> Output {
+        Output::Message {
+            message: message.into(),
+        }
+    }
+}
+
+// Tagged like Output, so a reader can tell both apart by type
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Failure<'a> {
+    Error { kind: &'a str, message: String },
+}
+
+// The one place errors are turned into text
+pub fn render(result: &Result<Output, Error>, format: Format) -> String {
+    match format {
+        Format::Human => match result {
+            Ok(output) => human(output),
+            Err(err) => err.to_string(),
+        },
+        Format::Json => match result {
+            Ok(output) => serde_json::to_string(output),
+            Err(err) => serde_json::to_string(&Failure::Error {
+                kind: err.kind(),
+                message: err.to_string(),
+            }),
+        }
+        .unwrap_or_else(|err| json!({ "type": "error", "message": err.to_string() }).to_string()),
+    }
+}
+
+fn human(output: &Output) -> String {
+    match output {
+        Output::Message { message } => message.clone(),
+        Output::Recorded { metric, record } => format!("Recorded {}: {}", metric, record.values()),
+        Output::Records { notes, records } => {
+            let mut output = String::new();
+            for note in notes {
+                output.push_str(note);
+                output.push('\n');
+            }
+            for record in records {
+                output.push_str(&record.values());
+                if let Some(duration) = record.duration {
+                    output.push_str(&format!(" ({}s)", duration));
+                }
+                output.push_str(&format!(
+                    "{}, recorded {}\n",
+                    utils::format_source(&record.source),
+                    utils::format_timestamp(record.timestamp)
+                ));
+            }
+            output
+        }
+        Output::Stats {
+            metric,
+            samples,
+            columns,
+        } => {
+            if *samples == 0 {
+                return format!("No {} data in range\n", metric);
+            }
+
+            let mut output = format!("{} samples\n", samples);
+            for column in columns {
+                output.push_str(&format!(
+                    "{}: min {}{unit}, avg {:.1}{unit}, max {}{unit}\n",
+                    column.name,
+                    column.min,
+                    column.mean,
+                    column.max,
+                    unit = column.unit
+                ));
+            }
+            output
+        }
+        Output::Trend {
+            metric, buckets, ..
+        } => {
+            if buckets.is_empty() {
+                return format!("No {} data in range\n", metric);
+            }
+
+            let mut output = String::new();
+            for bucket in buckets {
+                let columns: Vec<String> = bucket
+                    .columns
+                    .iter()
+                    .map(|column| {
+                        format!(
+                            "{} {:.1}{unit} ({} to {})",
+                            column.name,
+                            column.mean,
+                            column.min,
+                            column.max,
+                            unit = column.unit
+                        )
+                    })
+                    .collect();
+                output.push_str(&format!(
+                    "{}: {}, {} samples\n",
+                    utils::format_timestamp(bucket.timestamp),
+                    columns.join(", "),
+                    bucket.samples
+                ));
+            }
+            output
+        }
+        Output::Instruments { instruments } => {
+            if instruments.is_empty() {
+                return String::from("No instruments registered\n");
+            }
+
+            let mut output = String::new();
+            for instrument in instruments {
+                output.push_str(&format!(
+                    "{}: {} ({}), introduced {}",
+                    instrument.id,
+                    instrument.name,
+                    instrument.metric,
+                    utils::format_timestamp(instrument.introduced)
+                ));
+                if let Some(deprecated) = instrument.deprecated {
+                    output.push_str(&format!(
+                        ", deprecated {}",
+                        utils::format_timestamp(deprecated)
+                    ));
+                }
+                if let (Some(tol_min), Some(tol_max)) = (instrument.tol_min, instrument.tol_max) {
+                    output.push_str(&format!(", tolerance {} to {}", tol_min, tol_max));
+                }
+                if let Some(battery) = instrument.battery {
+                    output.push_str(&format!(", battery {}%", battery));
+                }
+                if let Some(notes) = &instrument.notes {
+                    output.push_str(&format!(" - {}", notes));
+                }
+                output.push('\n');
+            }
+            output
+        }
+        Output::Adherence { metrics } => {
+            let mut output = String::new();
+            for adherence in metrics {
+                output.push_str(&format!(
+                    "{} ({}): current streak {} days, longest {} days\n",
+                    adherence.metric,
+                    adherence.slots.join(", "),
+                    adherence.current_streak,
+                    adherence.longest_streak
+                ));
+                for week in &adherence.weeks {
+                    let completed = match week.percent {
+                        Some(percent) => {
+                            format!("{} of {} ({}%)", week.completed, week.scheduled, percent)
+                        }
+                        None => String::from("nothing scheduled"),
+                    };
+                    output.push_str(&format!(
+                        "\tweek of {}: {}\n",
+                        utils::format_timestamp(week.timestamp),
+                        completed
+                    ));
+                }
+            }
+            output
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::{Arc, RwLock};
+
+use chrono::Utc;
+use log::info;
+use rusqlite::Connection;
+
+use crate::{
+    heartrate,
+    series::{Series, Value},
+    spo2, temperature, utils, Error, SectionedConfigMap,
+};
+
+// Days after which raw samples become minutes and minutes become hours.
+// None keeps the finer resolution forever, which is the default for both.
+pub struct Policy {
+    raw_days: Option<i64>,
+    minute_days: Option<i64>,
+}
+
+impl Policy {
+    pub fn from_config(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Policy, Error> {
+        Ok(Policy {
+            raw_days: days(conf.clone(), "raw_days", "0")?,
+            minute_days: days(conf, "minute_days", "0")?,
+        })
+    }
+}
+
+fn days(
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    key: &str,
+    default: &str,
+) -> Result<Option<i64>, Error> {
+    let days = utils::from_config_or(conf, "retention", key, default);
+    match days.parse::<i64>() {
+        Ok(0) => Ok(None),
+        Ok(days) if days > 0 => Ok(Some(days)),
+        _ => Err(Error::Config(format!(
+            "{} in section retention must be a number of days or 0, not {}",
+            key, days
+        ))),
+    }
+}
+
+pub fn on_startup(conf: Arc<RwLock<SectionedConfigMap>>) -> bool {
+    utils::from_config_or(conf, "retention", "on_startup", "false") == "true"
+}
+
+// Rolls up aged samples of every continuous metric according to the policy
+pub fn maintain(conf: Arc<RwLock<SectionedConfigMap>>, conn: &Connection) -> Result<String, Error> {
+    let policy = Policy::from_config(conf)?;
+    let now = Utc::now().timestamp();
+
+    let results = [
+        (
+            heartrate::SERIES.table,
+            maintain_series(&heartrate::SERIES, &policy, now, conn),
+        ),
+        (
+            temperature::SERIES.table,
+            maintain_series(&temperature::SERIES, &policy, now, conn),
+        ),
+        (
+            spo2::SERIES.table,
+            maintain_series(&spo2::SERIES, &policy, now, conn),
+        ),
+    ];
+
+    let mut output = String::new();
+    let mut failures = Vec::new();
+    for (table, result) in results {
+        match result {
+            Ok(rolled_up) => output.push_str(&rolled_up),
+            Err(err) => failures.push((String::from(table), err)),
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(output)
+    } else {
+        Err(Error::Batch(failures, output))
+    }
+}
+
+fn maintain_series<V: Value>(
+    series: &Series<V>,
+    policy: &Policy,
+    now: i64,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let raw_cutoff = policy.raw_days.map(|days| now - days * 86400);
+    // Minutes are kept for minute_days after raw samples expire
+    let minute_cutoff = policy
+        .minute_days
+        .map(|days| raw_cutoff.unwrap_or(now) - days * 86400);
+
+    let (raw, minutes) = series
+        .maintain(raw_cutoff, minute_cutoff, conn)
+        .map_err(Error::database("roll up data"))?;
+    info!(
+        "Rolled up {} raw and {} minute rows of {}",
+        raw, minutes, series.table
+    );
+    Ok(format!(
+        "{}: rolled up {} raw rows into minutes and {} minute rows into hours\n",
+        series.table, raw, minutes
+    ))
+}
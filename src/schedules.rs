@@ -0,0 +1,471 @@
+use std::{
+    collections::HashSet,
+    ops::RangeInclusive,
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate, NaiveTime, TimeZone, Utc};
+use rusqlite::Connection;
+
+use crate::{
+    args::Args,
+    output::{Adherence, Output, Week},
+    store::METRICS,
+    utils, Error, SectionedConfigMap,
+};
+
+// A daily window a reading is expected in, in local time
+struct Slot {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Slot {
+    // The window on a day as timestamps, None if the day skips its start or end
+    fn on(&self, day: NaiveDate) -> Option<(i64, i64)> {
+        let at = |time: NaiveTime| {
+            Local
+                .from_local_datetime(&day.and_time(time))
+                .earliest()
+                .map(|datetime| datetime.timestamp())
+        };
+        Some((at(self.start)?, at(self.end)?))
+    }
+
+    fn label(&self) -> String {
+        format!(
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+struct Schedule {
+    metric: &'static str,
+    slots: Vec<Slot>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Done,
+    // Open and not recorded yet
+    Due,
+    Missed,
+    Upcoming,
+}
+
+impl Schedule {
+    fn status(
+        &self,
+        slot: &Slot,
+        day: NaiveDate,
+        now: i64,
+        conn: &Connection,
+    ) -> Result<Status, rusqlite::Error> {
+        status(slot, day, now, |start, end| {
+            conn.query_row(
+                &format!(
+                    "SELECT EXISTS (SELECT 1 FROM {} WHERE timestamp >= ?1 AND timestamp < ?2);",
+                    self.metric
+                ),
+                [start, end],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    // The local days from a day on with a reading in the slot, in one query
+    fn recorded_days(
+        &self,
+        slot: &Slot,
+        from: i64,
+        conn: &Connection,
+    ) -> Result<HashSet<NaiveDate>, rusqlite::Error> {
+        let mut statement = conn.prepare(&format!(
+            "SELECT DISTINCT date(timestamp, 'unixepoch', 'localtime') FROM {}
+            WHERE timestamp >= ?1
+                AND time(timestamp, 'unixepoch', 'localtime') >= ?2
+                AND time(timestamp, 'unixepoch', 'localtime') < ?3;",
+            self.metric
+        ))?;
+        let days = statement
+            .query_map(
+                (
+                    from,
+                    slot.start.format("%H:%M:%S").to_string(),
+                    slot.end.format("%H:%M:%S").to_string(),
+                ),
+                |row| row.get::<_, String>(0),
+            )?
+            .filter_map(|day| match day {
+                Ok(day) => NaiveDate::parse_from_str(&day, "%Y-%m-%d").ok().map(Ok),
+                Err(err) => Some(Err(err)),
+            })
+            .collect();
+        days
+    }
+}
+
+// Whether a slot on a day was recorded, with recorded telling for its start and end
+fn status(
+    slot: &Slot,
+    day: NaiveDate,
+    now: i64,
+    recorded: impl FnOnce(i64, i64) -> Result<bool, rusqlite::Error>,
+) -> Result<Status, rusqlite::Error> {
+    let Some((start, end)) = slot.on(day) else {
+        return Ok(Status::Upcoming);
+    };
+    if start > now {
+        return Ok(Status::Upcoming);
+    }
+
+    Ok(match recorded(start, end)? {
+        true => Status::Done,
+        false if end > now => Status::Due,
+        false => Status::Missed,
+    })
+}
+
+// Configured in section schedules as `<metric> = <from>-<to>, ...` with local times of
+// day, e.g. `weight = 06:00-10:00` for every morning and `bp = 06:00-10:00, 18:00-22:00`
+// for twice a day
+fn schedules(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Vec<Schedule>, Error> {
+    let mut schedules = Vec::new();
+    for metric in METRICS {
+        let entries = utils::from_config_or(conf.clone(), "schedules", metric, "");
+        let slots = entries
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let invalid = || {
+                    Error::Config(format!(
+                        "Invalid time window '{}' for {} in section schedules, expected e.g. 06:00-10:00",
+                        entry.trim(),
+                        metric
+                    ))
+                };
+                let (start, end) = entry.trim().split_once('-').ok_or_else(invalid)?;
+                let time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M");
+                let slot = Slot {
+                    start: time(start).map_err(|_| invalid())?,
+                    end: time(end).map_err(|_| invalid())?,
+                };
+                if slot.start >= slot.end {
+                    return Err(invalid());
+                }
+                Ok(slot)
+            })
+            .collect::<Result<Vec<Slot>, Error>>()?;
+
+        if !slots.is_empty() {
+            schedules.push(Schedule { metric, slots });
+        }
+    }
+    Ok(schedules)
+}
+
+// Whether section schedules has any, so there can be readings to remind of
+pub fn configured(conf: Arc<RwLock<SectionedConfigMap>>) -> bool {
+    schedules(conf).is_ok_and(|schedules| !schedules.is_empty())
+}
+
+// Tells the daemon once about each scheduled reading that is due or was missed today
+pub struct Reminders {
+    schedules: Vec<Schedule>,
+    // Metric, start of the slot and whether it was missed
+    reminded: HashSet<(&'static str, i64, bool)>,
+}
+
+impl Reminders {
+    // None without schedules
+    pub fn from_config(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Option<Reminders>, Error> {
+        let schedules = schedules(conf)?;
+        if schedules.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Reminders {
+            schedules,
+            reminded: HashSet::new(),
+        }))
+    }
+
+    // The reminders not given yet
+    pub fn check(&mut self, conn: &Connection) -> Result<Vec<String>, Error> {
+        let now = Utc::now().timestamp();
+        let today = Local::now().date_naive();
+        // Older slots cannot come up again
+        self.reminded
+            .retain(|(_, start, _)| now - start < 2 * 86400);
+
+        let mut reminders = Vec::new();
+        for (schedule, slot, status) in due(&self.schedules, today, now, conn)? {
+            let start = slot.on(today).map_or(0, |(start, _)| start);
+            if self
+                .reminded
+                .insert((schedule.metric, start, status == Status::Missed))
+            {
+                reminders.push(reminder(schedule, slot, status));
+            }
+        }
+        Ok(reminders)
+    }
+}
+
+// The slots of today that are due or were missed
+fn due<'a>(
+    schedules: &'a [Schedule],
+    today: NaiveDate,
+    now: i64,
+    conn: &Connection,
+) -> Result<Vec<(&'a Schedule, &'a Slot, Status)>, Error> {
+    let mut due = Vec::new();
+    for schedule in schedules {
+        for slot in &schedule.slots {
+            let status = schedule
+                .status(slot, today, now, conn)
+                .map_err(Error::database(format!(
+                    "read the {} schedule",
+                    schedule.metric
+                )))?;
+            if matches!(status, Status::Due | Status::Missed) {
+                due.push((schedule, slot, status));
+            }
+        }
+    }
+    Ok(due)
+}
+
+fn reminder(schedule: &Schedule, slot: &Slot, status: Status) -> String {
+    match status {
+        Status::Missed => format!(
+            "Reminder: missed {} between {} today",
+            schedule.metric,
+            slot.label()
+        ),
+        _ => format!(
+            "Reminder: {} is due until {}",
+            schedule.metric,
+            slot.end.format("%H:%M")
+        ),
+    }
+}
+
+// The number of weeks adherence reports, up to ten years
+const WEEKS: RangeInclusive<u64> = 1..=520;
+
+// Prints the scheduled readings missing today with due, nothing if all are done, or
+// reports the last weeks
+pub fn command(
+    input: &mut Args,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<Output, Error> {
+    let schedules = schedules(conf)?;
+    let weeks = match input.next() {
+        Some("due") => {
+            let now = Utc::now().timestamp();
+            let reminders: Vec<String> = due(&schedules, Local::now().date_naive(), now, conn)?
+                .into_iter()
+                .map(|(schedule, slot, status)| reminder(schedule, slot, status))
+                .collect();
+            return Ok(Output::message(reminders.join("\n")));
+        }
+        Some(weeks) => weeks
+            .parse::<u64>()
+            .ok()
+            .filter(|weeks| WEEKS.contains(weeks))
+            .ok_or_else(|| {
+                Error::Parse(format!(
+                    "Failed to parse parameter: {}: expected due or a number of weeks from 1 to {}",
+                    weeks,
+                    WEEKS.end()
+                ))
+            })?,
+        None => 4,
+    };
+    if schedules.is_empty() {
+        return Err(Error::Config(String::from(
+            "No schedules in section schedules, add e.g. weight = 06:00-10:00",
+        )));
+    }
+
+    let metrics = schedules
+        .iter()
+        .map(|schedule| adherence(schedule, weeks, conn))
+        .collect::<Result<Vec<Adherence>, Error>>()?;
+    Ok(Output::Adherence { metrics })
+}
+
+// Slots recorded per week since the first reading of the metric, and the days in a row
+// all of them were
+fn adherence(schedule: &Schedule, weeks: u64, conn: &Connection) -> Result<Adherence, Error> {
+    let now = Utc::now().timestamp();
+    let today = Local::now().date_naive();
+    let report_from = today
+        .checked_sub_days(Days::new(
+            today.weekday().num_days_from_monday() as u64 + 7 * (weeks - 1),
+        ))
+        .ok_or_else(|| Error::Parse(format!("Cannot report {} weeks back", weeks)))?;
+
+    let first: Option<i64> = conn
+        .query_row(
+            &format!("SELECT MIN(timestamp) FROM {};", schedule.metric),
+            [],
+            |row| row.get(0),
+        )
+        .map_err(Error::database("read adherence"))?;
+    // Nothing was scheduled before the metric was first recorded
+    let first = first
+        .and_then(|first| DateTime::from_timestamp(first, 0))
+        .map_or(today, |first| first.with_timezone(&Local).date_naive());
+    let from = Local
+        .from_local_datetime(&first.and_time(NaiveTime::MIN))
+        .earliest()
+        .map_or(0, |from| from.timestamp());
+    let recorded = schedule
+        .slots
+        .iter()
+        .map(|slot| schedule.recorded_days(slot, from, conn))
+        .collect::<Result<Vec<HashSet<NaiveDate>>, rusqlite::Error>>()
+        .map_err(Error::database("read adherence"))?;
+
+    let mut report: Vec<Week> = (0..weeks)
+        .map(|week| {
+            let start = report_from + Days::new(7 * week);
+            Week {
+                timestamp: Local
+                    .from_local_datetime(&start.and_time(NaiveTime::MIN))
+                    .earliest()
+                    .map_or(0, |start| start.timestamp()),
+                scheduled: 0,
+                completed: 0,
+                percent: None,
+            }
+        })
+        .collect();
+
+    let (mut current, mut longest) = (0, 0);
+    let mut day = first;
+    while day <= today {
+        let mut statuses = Vec::new();
+        for (slot, days) in schedule.slots.iter().zip(&recorded) {
+            statuses.push(
+                status(slot, day, now, |_, _| Ok(days.contains(&day)))
+                    .map_err(Error::database("read adherence"))?,
+            );
+        }
+
+        if day >= report_from {
+            let week = &mut report[((day - report_from).num_days() / 7) as usize];
+            for status in &statuses {
+                match status {
+                    Status::Done => {
+                        week.scheduled += 1;
+                        week.completed += 1;
+                    }
+                    Status::Missed => week.scheduled += 1,
+                    Status::Due | Status::Upcoming => {}
+                }
+            }
+        }
+
+        current = streak(current, &statuses);
+        longest = longest.max(current);
+
+        day = day + Days::new(1);
+    }
+
+    for week in &mut report {
+        week.percent = percent(week.completed, week.scheduled);
+    }
+
+    Ok(Adherence {
+        metric: schedule.metric,
+        slots: schedule.slots.iter().map(Slot::label).collect(),
+        current_streak: current,
+        longest_streak: longest,
+        weeks: report,
+    })
+}
+
+// The streak after a day with the statuses of its slots
+fn streak(current: i64, statuses: &[Status]) -> i64 {
+    if statuses.iter().all(|status| *status == Status::Done) {
+        current + 1
+    } else if statuses.contains(&Status::Missed) {
+        0
+    } else {
+        // Today is not over yet
+        current
+    }
+}
+
+// Rounded half up, None if nothing was scheduled
+fn percent(completed: i64, scheduled: i64) -> Option<i64> {
+    (scheduled > 0).then(|| (completed * 200 + scheduled) / (scheduled * 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot() -> Slot {
+        Slot {
+            start: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(10, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn status_depends_on_the_slot_being_open_past_or_upcoming() {
+        let (slot, day) = (slot(), NaiveDate::from_ymd_opt(2026, 1, 15).unwrap());
+        let (start, end) = slot.on(day).unwrap();
+        let at = |now: i64, recorded: bool| {
+            status(&slot, day, now, |from, to| {
+                assert_eq!((from, to), (start, end));
+                Ok(recorded)
+            })
+            .unwrap()
+        };
+
+        assert!(at(start - 1, true) == Status::Upcoming);
+        assert!(at(start, false) == Status::Due);
+        assert!(at(start + 60, true) == Status::Done);
+        assert!(at(end, false) == Status::Missed);
+        assert!(at(end + 3600, true) == Status::Done);
+        // Not asked for a slot that has not started
+        let upcoming = status(&slot, day, start - 1, |_, _| panic!("asked"));
+        assert!(upcoming.unwrap() == Status::Upcoming);
+    }
+
+    #[test]
+    fn missed_slots_break_the_streak_but_open_ones_do_not() {
+        use Status::*;
+        let days = [
+            vec![Done, Done],
+            vec![Done, Done],
+            vec![Done, Missed],
+            vec![Done, Done],
+            // Today, with the evening still to come
+            vec![Done, Upcoming],
+        ];
+        let (mut current, mut longest) = (0, 0);
+        for statuses in &days {
+            current = streak(current, statuses);
+            longest = longest.max(current);
+        }
+        assert_eq!((current, longest), (1, 2));
+        assert_eq!(streak(3, &[Due]), 3);
+    }
+
+    #[test]
+    fn percent_rounds_half_up() {
+        assert_eq!(percent(0, 0), None);
+        assert_eq!(percent(2, 3), Some(67));
+        assert_eq!(percent(1, 3), Some(33));
+        assert_eq!(percent(1, 8), Some(13));
+        assert_eq!(percent(7, 7), Some(100));
+    }
+}
@@ -0,0 +1,986 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    marker::PhantomData,
+};
+
+use chrono::Utc;
+use log::error;
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection, Row};
+
+use crate::{
+    args::Args,
+    compression::{self, Strategy},
+    instruments,
+    output::{self, Bucket, ColumnStats, Field, Output, Record},
+    utils,
+    writer::Collision,
+    Error,
+};
+
+pub struct Column {
+    pub name: &'static str,
+    pub sql_type: &'static str,
+    pub unit: &'static str,
+    // How humans read it, see output::Field
+    pub label: &'static str,
+}
+
+// The value of one sample of a continuous metric. It is stored in one table column
+// per component and handled as f64 components by compression and stats.
+pub trait Value: Copy + Sized {
+    const COLUMNS: &'static [Column];
+    // Parameters for manual entry, as shown in help
+    const USAGE: &'static str;
+
+    fn parse(param: &str, input: &mut Args) -> Result<Self, Error>;
+    fn read(row: &Row, offset: usize) -> rusqlite::Result<Self>;
+    fn components(&self) -> Vec<f64>;
+    // Rounds components to what the columns can store
+    fn from_components(components: &[f64]) -> Self;
+
+    fn fields(&self) -> Vec<Field> {
+        Self::COLUMNS
+            .iter()
+            .zip(self.components())
+            .map(|(column, component)| Field {
+                name: column.name,
+                value: match column.sql_type {
+                    "INTEGER" => output::Value::Integer(component as i64),
+                    _ => output::real(component as f32),
+                },
+                unit: column.unit,
+                label: column.label,
+            })
+            .collect()
+    }
+}
+
+pub struct SeriesORM<V: Value> {
+    pub id: i64,
+    pub timestamp: i64,
+    pub value: V,
+    pub duration: i64,
+    pub instrument: i64,
+    pub source: Option<String>,
+}
+
+impl<V: Value> SeriesORM<V> {
+    pub fn record(&self) -> Record {
+        Record {
+            id: self.id,
+            timestamp: self.timestamp,
+            duration: Some(self.duration),
+            source: self.source.clone(),
+            fields: self.value.fields(),
+        }
+    }
+}
+
+pub struct Tier {
+    pub name: &'static str,
+    pub seconds: i64,
+}
+
+// Resolutions raw samples are rolled up to as they age, finest first
+pub const TIERS: [Tier; 2] = [
+    Tier {
+        name: "minute",
+        seconds: 60,
+    },
+    Tier {
+        name: "hour",
+        seconds: 3600,
+    },
+];
+
+// A year of per-second lines is about a gigabyte of CSV
+const MAX_EXPANDED_SECONDS: i64 = 366 * 86400;
+
+pub struct Expanded<V: Value> {
+    // Rows ordered by timestamp that have not started yet
+    rows: std::iter::Peekable<std::vec::IntoIter<SeriesORM<V>>>,
+    // Rows covering the current second, ordered by timestamp
+    active: Vec<SeriesORM<V>>,
+    timestamp: i64,
+    to: i64,
+}
+
+impl<V: Value> Iterator for Expanded<V> {
+    type Item = (i64, Option<V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.timestamp >= self.to {
+            return None;
+        }
+        let timestamp = self.timestamp;
+        self.timestamp += 1;
+
+        while let Some(row) = self.rows.next_if(|row| row.timestamp <= timestamp) {
+            self.active.push(row);
+        }
+        self.active
+            .retain(|row| row.timestamp + row.duration.max(1) > timestamp);
+
+        Some((timestamp, self.active.first().map(|row| row.value)))
+    }
+}
+
+pub struct Compression {
+    // What was merged and the largest error it introduced
+    pub report: String,
+    // Rows merged away, none on a dry run
+    pub deleted: usize,
+}
+
+pub struct Stats {
+    // Seconds covered by samples, which is the number of samples before compression
+    pub samples: i64,
+    pub columns: Vec<ColumnStats>,
+}
+
+// A continuous metric sampled about once per second, like heart rate, in a table of
+// its own. Consecutive samples may be compressed into one row covering duration
+// seconds from its timestamp. Uncompressed rows have a duration of 0.
+pub struct Series<V: Value> {
+    pub table: &'static str,
+    // Name of the metric in messages
+    pub label: &'static str,
+    value: PhantomData<V>,
+}
+
+impl<V: Value> Series<V> {
+    pub const fn new(table: &'static str, label: &'static str) -> Series<V> {
+        Series {
+            table,
+            label,
+            value: PhantomData,
+        }
+    }
+
+    pub fn tables(&self, conn: &Connection) {
+        let columns: String = V::COLUMNS
+            .iter()
+            .map(|column| format!("{:<12}{} NOT NULL,\n", column.name, column.sql_type))
+            .collect();
+
+        let _ = conn
+            .execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (
+                    id          INTEGER PRIMARY KEY,
+                    timestamp   INTEGER NOT NULL,
+                    {}
+                    duration    INTEGER DEFAULT (0) NOT NULL,
+                    instrument  INTEGER DEFAULT (0) NOT NULL,
+                    UNIQUE (timestamp, instrument)
+                );",
+                    self.table, columns
+                ),
+                [],
+            )
+            .map_err(|err| error!("Failed to ensure table '{}' exists -> {}", self.table, err));
+
+        let aggregates: String = V::COLUMNS
+            .iter()
+            .map(|column| {
+                format!(
+                    "{0}_min REAL NOT NULL,\n{0}_avg REAL NOT NULL,\n{0}_max REAL NOT NULL,\n",
+                    column.name
+                )
+            })
+            .collect();
+
+        for tier in &TIERS {
+            let table = self.rollup_table(tier);
+            let _ = conn
+                .execute(
+                    &format!(
+                        "CREATE TABLE IF NOT EXISTS {} (
+                        timestamp   INTEGER NOT NULL,
+                        instrument  INTEGER NOT NULL,
+                        seconds     INTEGER NOT NULL,
+                        {}
+                        UNIQUE (timestamp, instrument)
+                    );",
+                        table, aggregates
+                    ),
+                    [],
+                )
+                .map_err(|err| error!("Failed to ensure table '{}' exists -> {}", table, err));
+        }
+    }
+
+    pub fn command(&self, input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+        let param = match input.next() {
+            Some(param) => param,
+            None => return Err(Error::Parse(String::from("No further parameters"))),
+        };
+
+        match param {
+            "last" => self.last_command(input, conn),
+            "range" => self.range_command(input, conn),
+            "stats" => self.stats_command(input, conn),
+            "trend" => self.trend_command(input, conn),
+            "compress" => self.compress_command(input, conn),
+            "export" => self.export(input, conn),
+            _ => {
+                let value = V::parse(param, input)?;
+
+                let timestamp = utils::entry_time();
+                self.write(value, timestamp, instruments::MANUAL, Collision::Fail, conn)
+                    .map_err(Error::database(format!("write {} data", self.label)))?;
+                Ok(Output::Recorded {
+                    metric: self.label,
+                    record: Record {
+                        id: conn.last_insert_rowid(),
+                        timestamp,
+                        duration: None,
+                        source: None,
+                        fields: value.fields(),
+                    },
+                })
+            }
+        }
+    }
+
+    pub fn help(&self) -> String {
+        format!(
+            "\t{} <last <count:i64> | range <from> <to> | stats <from> <to> | trend <step:i64> <from> <to> | export [--expanded] <path> [<from> [<to>]] | {} | {}>\n",
+            self.table,
+            Strategy::help(),
+            V::USAGE
+        )
+    }
+
+    pub fn write(
+        &self,
+        value: V,
+        timestamp: i64,
+        instrument: i64,
+        collision: Collision,
+        conn: &Connection,
+    ) -> Result<usize, rusqlite::Error> {
+        let names: Vec<&str> = V::COLUMNS.iter().map(|column| column.name).collect();
+        let placeholders: String = (0..names.len()).map(|i| format!(", ?{}", i + 3)).collect();
+
+        let mut params = vec![SqlValue::Integer(timestamp), SqlValue::Integer(instrument)];
+        params.extend(value.components().into_iter().map(SqlValue::Real));
+
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (timestamp, instrument, {}) VALUES (?1, ?2{}) {};",
+                self.table,
+                names.join(", "),
+                placeholders,
+                collision.clause(&names)
+            ),
+            params_from_iter(params),
+        )
+    }
+
+    pub fn last(&self, take: i64, conn: &Connection) -> Result<Vec<SeriesORM<V>>, rusqlite::Error> {
+        self.query("ORDER BY t.timestamp DESC LIMIT (?1)", [take], conn)
+    }
+
+    // Rows covering any part of [from, to), oldest first
+    pub fn range(
+        &self,
+        from: i64,
+        to: i64,
+        conn: &Connection,
+    ) -> Result<Vec<SeriesORM<V>>, rusqlite::Error> {
+        self.query(
+            "WHERE t.timestamp < ?2 AND t.timestamp + MAX(t.duration, 1) > ?1
+            ORDER BY t.timestamp ASC, t.instrument ASC",
+            [from, to],
+            conn,
+        )
+    }
+
+    // One entry per second of [from, to), oldest first, holding the value of the row
+    // covering that second or None for a gap. Where rows of several instruments
+    // overlap, the one that started first wins. Seconds are generated lazily, since
+    // a long range holds millions of them.
+    pub fn expand(
+        &self,
+        from: i64,
+        to: i64,
+        conn: &Connection,
+    ) -> Result<Expanded<V>, rusqlite::Error> {
+        Ok(Expanded {
+            rows: self.range(from, to, conn)?.into_iter().peekable(),
+            active: Vec::new(),
+            timestamp: from,
+            to,
+        })
+    }
+
+    // First second covered by any row and the second after the last one
+    pub fn bounds(&self, conn: &Connection) -> Result<Option<(i64, i64)>, rusqlite::Error> {
+        conn.query_row(
+            &format!(
+                "SELECT MIN(timestamp), MAX(timestamp + MAX(duration, 1)) FROM {};",
+                self.table
+            ),
+            [],
+            |row| {
+                Ok(match (row.get(0)?, row.get(1)?) {
+                    (Some(from), Some(to)) => Some((from, to)),
+                    _ => None,
+                })
+            },
+        )
+    }
+
+    // Weighted by the seconds each row covers, so compression does not skew the mean.
+    // Older data is read from the rollups retention left of it, whose buckets count
+    // completely if they overlap the range at all.
+    pub fn stats(&self, from: i64, to: i64, conn: &Connection) -> Result<Stats, rusqlite::Error> {
+        conn.query_row(
+            &format!(
+                "SELECT COALESCE(SUM(seconds), 0), {} FROM ({});",
+                Self::aggregates(),
+                self.overlapping()
+            ),
+            [from, to],
+            |row| Self::read_stats(row, 0),
+        )
+    }
+
+    // Stats of consecutive buckets of step seconds starting at from. Buckets without
+    // samples are left out.
+    pub fn trend(
+        &self,
+        from: i64,
+        to: i64,
+        step: i64,
+        conn: &Connection,
+    ) -> Result<Vec<(i64, Stats)>, rusqlite::Error> {
+        let mut query = conn.prepare(&format!(
+            "SELECT ?1 + (MAX(timestamp, ?1) - ?1) / ?3 * ?3 AS bucket, SUM(seconds), {}
+            FROM ({})
+            GROUP BY bucket
+            ORDER BY bucket;",
+            Self::aggregates(),
+            self.overlapping()
+        ))?;
+
+        let buckets = query
+            .query_map([from, to, step], |row| {
+                Ok((row.get(0)?, Self::read_stats(row, 1)?))
+            })?
+            .collect();
+        buckets
+    }
+
+    // Raw and rolled up rows overlapping ?1 to ?2, see aggregated
+    fn overlapping(&self) -> String {
+        let tiers: Vec<String> = std::iter::once(None)
+            .chain(TIERS.iter().map(Some))
+            .map(|tier| {
+                format!(
+                    "SELECT * FROM ({}) WHERE timestamp < ?2 AND timestamp + span > ?1",
+                    self.aggregated(tier)
+                )
+            })
+            .collect();
+        tiers.join(" UNION ALL ")
+    }
+
+    // Min, mean weighted by seconds and max of every column
+    fn aggregates() -> String {
+        let aggregates: Vec<String> = V::COLUMNS
+            .iter()
+            .map(|column| {
+                format!(
+                    "MIN({0}_min), SUM({0}_avg * seconds * 1.0) / SUM(seconds), MAX({0}_max)",
+                    column.name
+                )
+            })
+            .collect();
+        aggregates.join(", ")
+    }
+
+    fn read_stats(row: &Row, offset: usize) -> Result<Stats, rusqlite::Error> {
+        let samples: i64 = row.get::<_, Option<i64>>(offset)?.unwrap_or(0);
+        let mut columns = Vec::new();
+        for (i, column) in V::COLUMNS.iter().enumerate() {
+            let offset = offset + 1 + i * 3;
+            columns.push(ColumnStats {
+                name: column.name,
+                unit: column.unit,
+                min: narrow(row.get::<_, Option<f64>>(offset)?.unwrap_or(f64::NAN)),
+                mean: narrow(row.get::<_, Option<f64>>(offset + 1)?.unwrap_or(f64::NAN)),
+                max: narrow(row.get::<_, Option<f64>>(offset + 2)?.unwrap_or(f64::NAN)),
+            });
+        }
+
+        Ok(Stats { samples, columns })
+    }
+
+    // Rolls raw rows older than raw_cutoff into minutes and minutes older than
+    // minute_cutoff into hours, in one transaction. A compressed row is rolled up once it
+    // ends before the cutoff, split over the buckets it spans. Returns the number of raw
+    // and minute rows rolled up.
+    pub fn maintain(
+        &self,
+        raw_cutoff: Option<i64>,
+        minute_cutoff: Option<i64>,
+        conn: &Connection,
+    ) -> Result<(usize, usize), rusqlite::Error> {
+        utils::atomically(conn, || {
+            let raw = match raw_cutoff {
+                Some(cutoff) => self.rollup(None, &TIERS[0], cutoff, conn)?,
+                None => 0,
+            };
+            let minutes = match minute_cutoff {
+                Some(cutoff) => self.rollup(Some(&TIERS[0]), &TIERS[1], cutoff, conn)?,
+                None => 0,
+            };
+
+            Ok((raw, minutes))
+        })
+    }
+
+    fn rollup(
+        &self,
+        source: Option<&Tier>,
+        target: &Tier,
+        cutoff: i64,
+        conn: &Connection,
+    ) -> Result<usize, rusqlite::Error> {
+        // Only whole buckets are rolled up, so none is split between tiers
+        let cutoff = cutoff.div_euclid(target.seconds) * target.seconds;
+
+        let mut columns = Vec::new();
+        let mut aggregates = Vec::new();
+        let mut merges = Vec::new();
+        for column in V::COLUMNS {
+            columns.push(format!("{0}_min, {0}_avg, {0}_max", column.name));
+            aggregates.push(format!(
+                "MIN({0}_min), SUM({0}_avg * seconds * 1.0) / SUM(seconds), MAX({0}_max)",
+                column.name
+            ));
+            merges.push(format!(
+                "{0}_min = MIN({0}_min, excluded.{0}_min),
+                {0}_avg = ({0}_avg * seconds + excluded.{0}_avg * excluded.seconds)
+                    / (seconds + excluded.seconds),
+                {0}_max = MAX({0}_max, excluded.{0}_max)",
+                column.name
+            ));
+        }
+
+        // A row is cut at every bucket boundary it spans, each piece covering its share of
+        // the seconds. Late imports may land in a bucket that was rolled up before.
+        conn.execute(
+            &format!(
+                "WITH RECURSIVE pieces (start, stop, instrument, seconds, span, {values}) AS (
+                    SELECT timestamp, timestamp + span, instrument, seconds, span, {values}
+                    FROM ({source}) WHERE timestamp + span <= ?1
+                    UNION ALL
+                    SELECT (start / {bucket} + 1) * {bucket}, stop, instrument, seconds, span, {values}
+                    FROM pieces WHERE (start / {bucket} + 1) * {bucket} < stop
+                )
+                INSERT INTO {} (timestamp, instrument, seconds, {})
+                SELECT bucket, instrument, MAX(CAST(ROUND(SUM(seconds)) AS INTEGER), 1), {}
+                FROM (
+                    SELECT (start / {bucket}) * {bucket} AS bucket, instrument,
+                        seconds * (MIN(stop, (start / {bucket} + 1) * {bucket}) - start) * 1.0 / span
+                            AS seconds,
+                        {values}
+                    FROM pieces
+                )
+                WHERE true
+                GROUP BY bucket, instrument
+                ON CONFLICT (timestamp, instrument) DO UPDATE SET
+                    {},
+                    seconds = seconds + excluded.seconds;",
+                self.rollup_table(target),
+                columns.join(", "),
+                aggregates.join(", "),
+                merges.join(",\n"),
+                values = columns.join(", "),
+                source = self.aggregated(source),
+                bucket = target.seconds
+            ),
+            [cutoff],
+        )?;
+
+        let (table, span) = match source {
+            Some(tier) => (self.rollup_table(tier), tier.seconds.to_string()),
+            None => (String::from(self.table), String::from("MAX(duration, 1)")),
+        };
+        conn.execute(
+            &format!("DELETE FROM {} WHERE timestamp + {} <= ?1;", table, span),
+            [cutoff],
+        )
+    }
+
+    fn rollup_table(&self, tier: &Tier) -> String {
+        format!("{}_{}", self.table, tier.name)
+    }
+
+    // Rows of the raw table or a rollup as timestamp, instrument, the seconds covered
+    // by samples, the seconds spanned, and min, avg and max of every column
+    fn aggregated(&self, tier: Option<&Tier>) -> String {
+        match tier {
+            Some(tier) => {
+                let columns: Vec<String> = V::COLUMNS
+                    .iter()
+                    .map(|column| format!("{0}_min, {0}_avg, {0}_max", column.name))
+                    .collect();
+                format!(
+                    "SELECT timestamp, instrument, seconds, {} AS span, {} FROM {}",
+                    tier.seconds,
+                    columns.join(", "),
+                    self.rollup_table(tier)
+                )
+            }
+            None => {
+                let columns: Vec<String> = V::COLUMNS
+                    .iter()
+                    .map(|column| {
+                        format!(
+                            "{0} AS {0}_min, {0} AS {0}_avg, {0} AS {0}_max",
+                            column.name
+                        )
+                    })
+                    .collect();
+                format!(
+                    "SELECT timestamp, instrument, MAX(duration, 1) AS seconds,
+                    MAX(duration, 1) AS span, {} FROM {}",
+                    columns.join(", "),
+                    self.table
+                )
+            }
+        }
+    }
+
+    fn query<P: rusqlite::Params>(
+        &self,
+        clause: &str,
+        params: P,
+        conn: &Connection,
+    ) -> Result<Vec<SeriesORM<V>>, rusqlite::Error> {
+        let columns: Vec<String> = V::COLUMNS
+            .iter()
+            .map(|column| format!("t.{}", column.name))
+            .collect();
+
+        let mut query = conn.prepare(&format!(
+            "
+            SELECT t.id, t.timestamp, t.duration, t.instrument, i.name, {}
+            FROM {} t
+            LEFT JOIN instruments i ON i.id = t.instrument
+            {};
+        ",
+            columns.join(", "),
+            self.table,
+            clause
+        ))?;
+
+        let results = query.query_map(params, |row| {
+            Ok(SeriesORM {
+                id: row.get(0)?,
+                timestamp: row.get(1)?,
+                duration: row.get(2)?,
+                instrument: row.get(3)?,
+                source: row.get(4)?,
+                value: V::read(row, 5)?,
+            })
+        })?;
+
+        results.collect()
+    }
+
+    fn last_command(&self, input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+        let mut notes = Vec::new();
+
+        let take_default = 3;
+        let take = match input.next() {
+            Some(take) => take.parse::<i64>().unwrap_or_else(|_| {
+                notes.push(String::from("Failed to parse query parameter"));
+                notes.push(format!("Using default query parameter {}", take_default));
+                take_default
+            }),
+            None => {
+                notes.push(format!("Using default query parameter {}", take_default));
+                take_default
+            }
+        };
+
+        let results = self.last(take, conn).map_err(Error::database(format!(
+            "read the last {} {} entries",
+            take, self.label
+        )))?;
+        Ok(Output::Records {
+            notes,
+            records: results.iter().map(SeriesORM::record).collect(),
+        })
+    }
+
+    fn range_command(&self, input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+        let (from, to) = utils::parse_range(input)?;
+
+        let results = self
+            .range(from, to, conn)
+            .map_err(Error::database(format!("read {} entries", self.label)))?;
+        Ok(Output::Records {
+            notes: Vec::new(),
+            records: results.iter().map(SeriesORM::record).collect(),
+        })
+    }
+
+    fn stats_command(&self, input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+        let (from, to) = utils::parse_range(input)?;
+
+        let stats = self
+            .stats(from, to, conn)
+            .map_err(Error::database(format!("compute {} stats", self.label)))?;
+        Ok(Output::Stats {
+            metric: self.label,
+            samples: stats.samples,
+            columns: stats.columns,
+        })
+    }
+
+    fn trend_command(&self, input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+        let step = match input.next() {
+            Some(step) => step.parse::<i64>().map_err(|err| {
+                Error::Parse(format!("Failed to parse parameter: <step:i64> {}", err))
+            })?,
+            None => return Err(Error::Parse(String::from("Missing parameter: step"))),
+        };
+        if step <= 0 {
+            return Err(Error::Parse(String::from(
+                "Step must be at least one second",
+            )));
+        }
+        let (from, to) = utils::parse_range(input)?;
+
+        let buckets = self
+            .trend(from, to, step, conn)
+            .map_err(Error::database(format!("compute {} trend", self.label)))?;
+        Ok(Output::Trend {
+            metric: self.label,
+            step,
+            buckets: buckets
+                .into_iter()
+                .map(|(timestamp, stats)| Bucket {
+                    timestamp,
+                    samples: stats.samples,
+                    columns: stats.columns,
+                })
+                .collect(),
+        })
+    }
+
+    // Writes rows as CSV, or with --expanded one line per second with empty values for
+    // gaps. Without a range the whole table is exported.
+    fn export(&self, input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+        let mut expanded = false;
+        let mut params = Vec::new();
+        for param in input {
+            match param {
+                "--expanded" => expanded = true,
+                _ => params.push(param),
+            }
+        }
+
+        let mut params = params.into_iter();
+        let path = match params.next() {
+            Some(path) => path,
+            None => return Err(Error::Parse(String::from("Missing parameter: path"))),
+        };
+
+        let read = Error::database(format!("read {} data to export", self.label));
+        let (from, to) = match params.next() {
+            Some(from) => {
+                let from = utils::parse_timestamp(from).ok_or_else(|| {
+                    Error::Parse(format!("Failed to parse parameter: <from> {}", from))
+                })?;
+                let to = match params.next() {
+                    Some(to) => utils::parse_timestamp(to).ok_or_else(|| {
+                        Error::Parse(format!("Failed to parse parameter: <to> {}", to))
+                    })?,
+                    None => Utc::now().timestamp() + 1,
+                };
+                (from, to)
+            }
+            None => match self.bounds(conn) {
+                Ok(Some(bounds)) => bounds,
+                Ok(None) => return Err(Error::Parse(format!("No {} data to export", self.label))),
+                Err(err) => return Err(read(err)),
+            },
+        };
+
+        if expanded && to - from > MAX_EXPANDED_SECONDS {
+            return Err(Error::Parse(format!(
+                "Range of {} days is too long to expand, export at most {} days at once",
+                (to - from) / 86400,
+                MAX_EXPANDED_SECONDS / 86400
+            )));
+        }
+
+        let columns: Vec<&str> = V::COLUMNS.iter().map(|column| column.name).collect();
+        let written = if expanded {
+            let expanded = self.expand(from, to, conn).map_err(read)?;
+            let lines = expanded.map(|(timestamp, value)| {
+                let values = match value {
+                    Some(value) => value.components(),
+                    None => Vec::new(),
+                };
+                let mut line = vec![timestamp.to_string()];
+                line.extend(
+                    (0..columns.len())
+                        .map(|i| values.get(i).copied().map(field).unwrap_or_default()),
+                );
+                line
+            });
+            write_csv(path, &["timestamp"], &columns, lines)
+        } else {
+            let rows = self.range(from, to, conn).map_err(read)?;
+            let lines = rows.iter().map(|row| {
+                let mut line = vec![
+                    row.timestamp.to_string(),
+                    row.duration.max(1).to_string(),
+                    row.source.clone().unwrap_or_default(),
+                ];
+                line.extend(row.value.components().into_iter().map(field));
+                line
+            });
+            write_csv(path, &["timestamp", "duration", "source"], &columns, lines)
+        };
+
+        let lines = written.map_err(Error::io(format!("write {}", path)))?;
+        Ok(Output::message(format!(
+            "Exported {} {} lines to {}",
+            lines, self.label, path
+        )))
+    }
+
+    // Compresses rows from the last compressed one of each instrument onwards, since
+    // everything before it has been compressed already. Reports what the strategy
+    // saves and applies it in one transaction, unless it is a dry run.
+    fn compress_command(&self, input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+        let (strategy, dry_run) = Strategy::parse(input)?;
+
+        let compression = self
+            .compress(&strategy, dry_run, conn)
+            .map_err(Error::database(format!("compress {} data", self.label)))?;
+        if dry_run {
+            Ok(Output::message(compression.report))
+        } else {
+            Ok(Output::message(format!(
+                "{}\nReduced entries by {}",
+                compression.report, compression.deleted
+            )))
+        }
+    }
+
+    // Merges the samples recorded since the last compression according to the strategy,
+    // in one transaction. A dry run only reports what would be merged.
+    pub fn compress(
+        &self,
+        strategy: &Strategy,
+        dry_run: bool,
+        conn: &Connection,
+    ) -> Result<Compression, rusqlite::Error> {
+        let raw = self.read_uncompressed(conn)?;
+
+        let total = raw.len();
+        let plan = compression::plan(strategy, raw, |components| {
+            V::from_components(components).components()
+        });
+        let report = plan.report(strategy, total);
+
+        if dry_run {
+            return Ok(Compression { report, deleted: 0 });
+        }
+
+        let assignments: String = V::COLUMNS
+            .iter()
+            .enumerate()
+            .map(|(i, column)| format!("{} = ?{}, ", column.name, i + 3))
+            .collect();
+
+        let deleted = utils::atomically(conn, || -> Result<usize, rusqlite::Error> {
+            let mut update = conn.prepare(&format!(
+                "UPDATE {} SET {}duration = ?2 WHERE id = ?1;",
+                self.table, assignments
+            ))?;
+            for run in &plan.runs {
+                let mut params = vec![SqlValue::Integer(run.id), SqlValue::Integer(run.duration)];
+                params.extend(run.value.iter().copied().map(SqlValue::Real));
+                update.execute(params_from_iter(params))?;
+            }
+
+            let mut delete = conn.prepare(&format!("DELETE FROM {} WHERE id = ?1;", self.table))?;
+            let mut deleted = 0;
+            for id in &plan.merged {
+                deleted += delete.execute([id])?;
+            }
+
+            Ok(deleted)
+        })?;
+
+        Ok(Compression { report, deleted })
+    }
+
+    fn read_uncompressed(
+        &self,
+        conn: &Connection,
+    ) -> Result<Vec<compression::Sample>, rusqlite::Error> {
+        let rows = self.query(
+            &format!(
+                "LEFT JOIN (
+                    SELECT instrument, MAX(timestamp) AS timestamp
+                    FROM {}
+                    WHERE duration > 0
+                    GROUP BY instrument
+                ) c ON c.instrument = t.instrument
+                WHERE t.timestamp >= COALESCE(c.timestamp, 0)
+                ORDER BY t.instrument, t.timestamp ASC",
+                self.table
+            ),
+            [],
+            conn,
+        )?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| compression::Sample {
+                id: row.id,
+                timestamp: row.timestamp,
+                value: row.value.components(),
+                duration: row.duration,
+                instrument: row.instrument,
+            })
+            .collect())
+    }
+}
+
+// Stored values are at most f32 precision, so print them without widening noise
+fn field(component: f64) -> String {
+    (component as f32).to_string()
+}
+
+pub(crate) fn narrow(component: f64) -> f64 {
+    field(component).parse().unwrap_or(component)
+}
+
+fn write_csv(
+    path: &str,
+    header: &[&str],
+    columns: &[&str],
+    lines: impl Iterator<Item = Vec<String>>,
+) -> Result<usize, io::Error> {
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+
+    let mut written = 0;
+    let header = [header, columns].concat().join(",");
+    writeln!(writer, "{}", header)?;
+    for line in lines {
+        // Instrument names are the only free text
+        let line: Vec<String> = line
+            .into_iter()
+            .map(|field| {
+                if field.contains([',', '"', '\n']) {
+                    format!("\"{}\"", field.replace('"', "\"\""))
+                } else {
+                    field
+                }
+            })
+            .collect();
+        writeln!(writer, "{}", line.join(","))?;
+        written += 1;
+    }
+
+    writer.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{heartrate, Store};
+
+    #[test]
+    fn rollup_splits_compressed_rows_across_buckets() {
+        let database = Store::open(":memory:").unwrap();
+        let conn = database.connection();
+        // 90 s at 60 bpm from 30 s into the first minute, then 10 s at 90 bpm
+        conn.execute_batch(
+            "INSERT INTO heartrate (timestamp, heartrate, duration) VALUES (30, 60, 90);
+            INSERT INTO heartrate (timestamp, heartrate, duration) VALUES (120, 90, 10);
+            INSERT INTO heartrate (timestamp, heartrate, duration) VALUES (170, 70, 20);",
+        )
+        .unwrap();
+
+        let (raw, _) = heartrate::SERIES.maintain(Some(180), None, conn).unwrap();
+        assert_eq!(raw, 2);
+
+        let minutes: Vec<(i64, i64, f64)> = conn
+            .prepare("SELECT timestamp, seconds, heartrate_avg FROM heartrate_minute ORDER BY timestamp;")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            minutes,
+            vec![(0, 30, 60.0), (60, 60, 60.0), (120, 10, 90.0)]
+        );
+
+        // The row running past the cutoff waits for the next rollup
+        let left: i64 = conn
+            .query_row("SELECT COUNT(*) FROM heartrate;", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(left, 1);
+    }
+
+    #[test]
+    fn expanded_seconds_prefer_the_row_that_started_first() {
+        let database = Store::open(":memory:").unwrap();
+        let conn = database.connection();
+        conn.execute_batch(
+            "INSERT INTO heartrate (timestamp, heartrate, duration, instrument) VALUES (10, 60, 5, 1);
+            INSERT INTO heartrate (timestamp, heartrate, duration, instrument) VALUES (12, 90, 0, 2);
+            INSERT INTO heartrate (timestamp, heartrate, duration, instrument) VALUES (14, 80, 4, 2);
+            INSERT INTO heartrate (timestamp, heartrate, duration, instrument) VALUES (20, 70, 0, 1);",
+        )
+        .unwrap();
+
+        let seconds: Vec<(i64, Option<u8>)> = heartrate::SERIES
+            .expand(9, 22, conn)
+            .unwrap()
+            .map(|(timestamp, value)| (timestamp, value.map(|bpm| bpm.0)))
+            .collect();
+        assert_eq!(
+            seconds,
+            vec![
+                (9, None),
+                (10, Some(60)),
+                (11, Some(60)),
+                (12, Some(60)),
+                (13, Some(60)),
+                (14, Some(60)),
+                (15, Some(80)),
+                (16, Some(80)),
+                (17, Some(80)),
+                (18, None),
+                (19, None),
+                (20, Some(70)),
+                (21, None),
+            ]
+        );
+
+        // A row started before the range still covers its first seconds
+        let first = heartrate::SERIES.expand(12, 13, conn).unwrap().next();
+        assert_eq!(
+            first.map(|(timestamp, value)| (timestamp, value.map(|bpm| bpm.0))),
+            Some((12, Some(60)))
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use rusqlite::{Connection, Row};
+
+use crate::{
+    args::Args,
+    output::Output,
+    series::{Column, Series, Value},
+    writer::Collision,
+    Error, Stat,
+};
+
+#[derive(Clone, Copy)]
+pub struct Oximetry {
+    pub spo2: u8,
+    pub pulse: u8,
+}
+
+impl Value for Oximetry {
+    const COLUMNS: &'static [Column] = &[
+        Column {
+            name: "spo2",
+            sql_type: "INTEGER",
+            unit: "%",
+            label: "{}",
+        },
+        Column {
+            name: "pulse",
+            sql_type: "INTEGER",
+            unit: "bpm",
+            label: "pulse {}",
+        },
+    ];
+    const USAGE: &'static str = "<spo2:u8> <pulse:u8>";
+
+    fn parse(param: &str, input: &mut Args) -> Result<Self, Error> {
+        let spo2 = param
+            .parse::<u8>()
+            .map_err(|e| Error::Parse(format!("Failed to parse parameter: <spo2:u8> {}", e)))?;
+        if spo2 > 100 {
+            return Err(Error::Parse(String::from(
+                "Invalid parameter: <spo2:u8> must be at most 100",
+            )));
+        }
+
+        let pulse = input
+            .next()
+            .ok_or_else(|| Error::Parse(String::from("Missing parameter: pulse")))?;
+        let pulse = pulse
+            .parse::<u8>()
+            .map_err(|e| Error::Parse(format!("Failed to parse parameter: <pulse:u8> {}", e)))?;
+
+        Ok(Oximetry { spo2, pulse })
+    }
+
+    fn read(row: &Row, offset: usize) -> rusqlite::Result<Self> {
+        Ok(Oximetry {
+            spo2: row.get(offset)?,
+            pulse: row.get(offset + 1)?,
+        })
+    }
+
+    fn components(&self) -> Vec<f64> {
+        vec![self.spo2 as f64, self.pulse as f64]
+    }
+
+    fn from_components(components: &[f64]) -> Self {
+        Oximetry {
+            spo2: components[0].round() as u8,
+            pulse: components[1].round() as u8,
+        }
+    }
+}
+
+pub const SERIES: Series<Oximetry> = Series::new("spo2", "SpO2");
+
+pub struct Spo2;
+
+impl Stat for Spo2 {
+    fn tables(conn: &Connection) {
+        SERIES.tables(conn)
+    }
+
+    fn command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+        SERIES.command(input, conn)
+    }
+
+    fn help() -> String {
+        SERIES.help()
+    }
+}
+
+pub fn write_spo2(
+    spo2: u8,
+    pulse: u8,
+    timestamp: i64,
+    instrument: i64,
+    collision: Collision,
+    conn: &Connection,
+) -> Result<usize, rusqlite::Error> {
+    SERIES.write(
+        Oximetry { spo2, pulse },
+        timestamp,
+        instrument,
+        collision,
+        conn,
+    )
+}
+
+// Inserts a reading taken at a known time, such as one stored on a device.
+// Returns 0 if a reading with the same timestamp is already recorded.
+pub fn import_spo2(
+    spo2: u8,
+    pulse: u8,
+    timestamp: i64,
+    instrument: i64,
+    conn: &Connection,
+) -> Result<usize, rusqlite::Error> {
+    write_spo2(spo2, pulse, timestamp, instrument, Collision::First, conn)
+}
@@ -0,0 +1,545 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use futures::future::{join_all, BoxFuture};
+use log::{error, warn};
+use rusqlite::{
+    backup::Backup, hooks::Action, types::ValueRef, Connection, OpenFlags, OptionalExtension,
+};
+use serde_json::{json, Map, Value as JsonValue};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    alerts, ble_sync,
+    bp::{self, BloodpressureORM, BP},
+    compression::Strategy,
+    heartrate::{self, Bpm, Heartrate},
+    instruments,
+    mood::{self, Mood, MoodORM},
+    series::{self, Compression, Expanded, Series, SeriesORM, Stats, Value},
+    spo2::{self, Oximetry, Spo2},
+    temperature::{self, Celsius, Temperature},
+    webhooks,
+    weight::{self, Weight, WeightORM},
+    writer::Collision,
+    Error, Stat,
+};
+
+// Tables holding readings, as opposed to instruments and sync state
+pub const METRICS: [&str; 6] = ["weight", "bp", "mood", "heartrate", "temperature", "spo2"];
+
+// Readings still queued at exit, and what the consumers finish, get this long
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A row committed to one of the METRICS tables
+#[derive(Clone, Debug)]
+pub struct Inserted {
+    pub table: String,
+    pub rowid: i64,
+}
+
+// The columns of a committed row except its id, as read by a Listener
+#[derive(Clone)]
+pub struct Reading {
+    pub table: String,
+    pub record: Map<String, JsonValue>,
+}
+
+// Does something with every committed reading, like publishing it, see Listener
+pub trait Consumer: Send {
+    // Readings of other tables are not read for it
+    fn wants(&self, _table: &str) -> bool {
+        true
+    }
+
+    // Called once before the first reading, e.g. to start a task using the connection
+    fn start(&mut self, _conn: &Arc<Mutex<Connection>>) {}
+
+    // Called in commit order, with the connection the reading was read through
+    fn consume(&mut self, reading: &Reading, conn: &Connection);
+
+    // Finishes what the readings started, like deliveries, before biomon exits
+    fn finish(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+}
+
+// Reads every row sent by Store::watch once, through a connection of its own, and hands
+// it to the consumers. Reading never waits on the commands using the database.
+pub struct Listener {
+    inserted: UnboundedSender<Inserted>,
+    settle: UnboundedSender<oneshot::Sender<()>>,
+    stop: oneshot::Sender<()>,
+    listening: JoinHandle<()>,
+}
+
+impl Listener {
+    // None without consumers
+    pub fn start(
+        database: &str,
+        mut consumers: Vec<Box<dyn Consumer>>,
+    ) -> Result<Option<Listener>, Error> {
+        if consumers.is_empty() {
+            return Ok(None);
+        }
+
+        let open = || format!("open {} for listening", database);
+        // Writable, as reading an inserted row takes the write lock, see read_inserted
+        let conn = Connection::open(database).map_err(Error::database(open()))?;
+        conn.busy_timeout(Duration::from_secs(5))
+            .map_err(Error::database(open()))?;
+        let conn = Arc::new(Mutex::new(conn));
+        for consumer in &mut consumers {
+            consumer.start(&conn);
+        }
+
+        let (inserted, rows) = unbounded_channel();
+        let (settle, settles) = unbounded_channel();
+        let (stop, stopped) = oneshot::channel();
+
+        Ok(Some(Listener {
+            inserted,
+            settle,
+            stop,
+            listening: tokio::spawn(listen(rows, settles, stopped, conn, consumers)),
+        }))
+    }
+
+    pub fn listener(&self) -> UnboundedSender<Inserted> {
+        self.inserted.clone()
+    }
+
+    // Waits until the readings committed so far are consumed
+    pub async fn settle(&self) {
+        let (settled, done) = oneshot::channel();
+        if self.settle.send(settled).is_ok() {
+            let _ = done.await;
+        }
+    }
+
+    // Consumes the readings still queued and lets the consumers finish
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        if timeout(STOP_TIMEOUT, self.listening).await.is_err() {
+            warn!("Stopped before all readings were consumed");
+        }
+    }
+}
+
+async fn listen(
+    mut rows: UnboundedReceiver<Inserted>,
+    mut settles: UnboundedReceiver<oneshot::Sender<()>>,
+    mut stopped: oneshot::Receiver<()>,
+    conn: Arc<Mutex<Connection>>,
+    mut consumers: Vec<Box<dyn Consumer>>,
+) {
+    loop {
+        tokio::select! {
+            row = rows.recv() => match row {
+                Some(row) => hand(&row, &conn, &mut consumers),
+                None => break,
+            },
+            Some(settled) = settles.recv() => {
+                while let Ok(row) = rows.try_recv() {
+                    hand(&row, &conn, &mut consumers);
+                }
+                let _ = settled.send(());
+            }
+            _ = &mut stopped => {
+                // Whatever was committed before the stop is still consumed
+                while let Ok(row) = rows.try_recv() {
+                    hand(&row, &conn, &mut consumers);
+                }
+                break;
+            }
+        }
+    }
+
+    join_all(consumers.into_iter().map(|consumer| consumer.finish())).await;
+}
+
+// Reads the row once for all consumers wanting its table
+fn hand(row: &Inserted, conn: &Mutex<Connection>, consumers: &mut [Box<dyn Consumer>]) {
+    if !consumers.iter().any(|consumer| consumer.wants(&row.table)) {
+        return;
+    }
+
+    let conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+    let reading = match read_inserted(&conn, row) {
+        Ok(Some(record)) => Reading {
+            table: row.table.clone(),
+            record,
+        },
+        // Rolled back to a savepoint
+        Ok(None) => return,
+        Err(err) => {
+            error!("Failed to read a committed reading -> {}", err);
+            return;
+        }
+    };
+    for consumer in consumers.iter_mut() {
+        if consumer.wants(&reading.table) {
+            consumer.consume(&reading, &conn);
+        }
+    }
+}
+
+// A biomon database. Timestamps are unix seconds, ranges include from and exclude to.
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    // Opens the database, creating it and any missing tables
+    pub fn open(path: impl AsRef<Path>) -> Result<Store, Error> {
+        let path = path.as_ref();
+        let open = || format!("open {}", path.display());
+        let conn = Connection::open(path).map_err(Error::database(open()))?;
+        // A recording in another biomon may hold the write lock for a moment
+        conn.busy_timeout(Duration::from_secs(5))
+            .map_err(Error::database(open()))?;
+        tables(&conn);
+
+        Ok(Store { conn })
+    }
+
+    // For the text commands, which work on the connection directly
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    // Sends every row committed to a metric table to the listeners, whichever command,
+    // import or recording inserted it. Rows of a rolled back transaction are not sent.
+    // Rows rolled back to a savepoint fire no hook, so they are sent and read_inserted
+    // finds nothing for them.
+    pub fn watch(&self, listeners: Vec<UnboundedSender<Inserted>>) {
+        if listeners.is_empty() {
+            return;
+        }
+
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        let inserted = pending.clone();
+        self.conn
+            .update_hook(Some(move |action, _: &str, table: &str, rowid| {
+                if action == Action::SQLITE_INSERT && METRICS.contains(&table) {
+                    if let Ok(mut inserted) = inserted.lock() {
+                        // A rowid rolled back to a savepoint is handed out again
+                        if !inserted
+                            .iter()
+                            .any(|row: &Inserted| row.rowid == rowid && row.table == table)
+                        {
+                            inserted.push(Inserted {
+                                table: String::from(table),
+                                rowid,
+                            });
+                        }
+                    }
+                }
+            }));
+
+        let committed = pending.clone();
+        self.conn.commit_hook(Some(move || {
+            if let Ok(mut committed) = committed.lock() {
+                for row in committed.drain(..) {
+                    for listener in &listeners {
+                        let _ = listener.send(row.clone());
+                    }
+                }
+            }
+            // Never turns the commit into a rollback
+            false
+        }));
+
+        self.conn.rollback_hook(Some(move || {
+            if let Ok(mut pending) = pending.lock() {
+                pending.clear();
+            }
+        }));
+    }
+
+    pub fn insert_weight(&self, timestamp: i64, kg: f64) -> Result<WeightORM, Error> {
+        weight::insert(timestamp, kg, &self.conn).map_err(Error::database("write weight"))
+    }
+
+    pub fn weight(&self, from: i64, to: i64) -> Result<Vec<WeightORM>, Error> {
+        weight::range(from, to, &self.conn).map_err(Error::database("read weight entries"))
+    }
+
+    pub fn last_weight(&self, count: i64) -> Result<Vec<WeightORM>, Error> {
+        weight::last(count, &self.conn).map_err(Error::database("read weight entries"))
+    }
+
+    pub fn insert_bp(&self, timestamp: i64, sys: i16, dia: i16) -> Result<BloodpressureORM, Error> {
+        bp::insert(timestamp, sys, dia, &self.conn).map_err(Error::database("write bp"))
+    }
+
+    pub fn bp(&self, from: i64, to: i64) -> Result<Vec<BloodpressureORM>, Error> {
+        bp::range(from, to, &self.conn).map_err(Error::database("read bp entries"))
+    }
+
+    pub fn last_bp(&self, count: i64) -> Result<Vec<BloodpressureORM>, Error> {
+        bp::last(count, &self.conn).map_err(Error::database("read bp entries"))
+    }
+
+    pub fn insert_mood(&self, timestamp: i64, mood: &str) -> Result<MoodORM, Error> {
+        mood::insert(timestamp, mood, &self.conn).map_err(Error::database("write mood"))
+    }
+
+    pub fn mood(&self, from: i64, to: i64) -> Result<Vec<MoodORM>, Error> {
+        mood::range(from, to, &self.conn).map_err(Error::database("read mood entries"))
+    }
+
+    pub fn last_mood(&self, count: i64) -> Result<Vec<MoodORM>, Error> {
+        mood::last(count, &self.conn).map_err(Error::database("read mood entries"))
+    }
+
+    pub fn heartrate(&self) -> Continuous<'_, Bpm> {
+        Continuous {
+            series: heartrate::SERIES,
+            conn: &self.conn,
+        }
+    }
+
+    pub fn temperature(&self) -> Continuous<'_, Celsius> {
+        Continuous {
+            series: temperature::SERIES,
+            conn: &self.conn,
+        }
+    }
+
+    pub fn spo2(&self) -> Continuous<'_, Oximetry> {
+        Continuous {
+            series: spo2::SERIES,
+            conn: &self.conn,
+        }
+    }
+
+    // Copies the database to path while it stays usable
+    pub fn backup(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        backup(&self.conn, path)
+    }
+
+    // Replaces the database at path with a backup through the backup API, in one write
+    // transaction, so connections still open on it see the restored data and a failed
+    // copy leaves the database as it was
+    pub fn restore(backup: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<(), Error> {
+        let (backup, path) = (backup.as_ref(), path.as_ref());
+        let copy = || -> Result<(), rusqlite::Error> {
+            let source = Connection::open_with_flags(backup, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            let mut target = Connection::open(path)?;
+            target.busy_timeout(Duration::from_secs(5))?;
+            Backup::new(&source, &mut target)?.run_to_completion(
+                100,
+                Duration::from_millis(250),
+                None,
+            )?;
+            // An older backup may lack tables added since
+            tables(&target);
+            Ok(())
+        };
+        copy().map_err(Error::database(format!("restore {}", backup.display())))
+    }
+}
+
+// A continuous metric of a store, see series::Series
+pub struct Continuous<'a, V: Value> {
+    series: Series<V>,
+    conn: &'a Connection,
+}
+
+impl<V: Value> Continuous<'_, V> {
+    // Inserts a manually taken sample, failing if the second already holds one
+    pub fn insert(&self, timestamp: i64, value: V) -> Result<i64, Error> {
+        self.series
+            .write(
+                value,
+                timestamp,
+                instruments::MANUAL,
+                Collision::Fail,
+                self.conn,
+            )
+            .map_err(self.failed("write"))?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn range(&self, from: i64, to: i64) -> Result<Vec<SeriesORM<V>>, Error> {
+        self.series
+            .range(from, to, self.conn)
+            .map_err(self.failed("read"))
+    }
+
+    pub fn last(&self, count: i64) -> Result<Vec<SeriesORM<V>>, Error> {
+        self.series
+            .last(count, self.conn)
+            .map_err(self.failed("read"))
+    }
+
+    // One value per second, None for gaps
+    pub fn expand(&self, from: i64, to: i64) -> Result<Expanded<V>, Error> {
+        self.series
+            .expand(from, to, self.conn)
+            .map_err(self.failed("read"))
+    }
+
+    pub fn stats(&self, from: i64, to: i64) -> Result<Stats, Error> {
+        self.series
+            .stats(from, to, self.conn)
+            .map_err(self.failed("compute stats of"))
+    }
+
+    // Stats per bucket of step seconds
+    pub fn trend(&self, from: i64, to: i64, step: i64) -> Result<Vec<(i64, Stats)>, Error> {
+        self.series
+            .trend(from, to, step, self.conn)
+            .map_err(self.failed("compute the trend of"))
+    }
+
+    pub fn compress(&self, strategy: &Strategy, dry_run: bool) -> Result<Compression, Error> {
+        self.series
+            .compress(strategy, dry_run, self.conn)
+            .map_err(self.failed("compress"))
+    }
+
+    fn failed(&self, action: &str) -> impl FnOnce(rusqlite::Error) -> Error {
+        Error::database(format!("{} {} data", action, self.series.label))
+    }
+}
+
+// The columns of an inserted row except its id, read through the writable connection of
+// a Listener. The commit hook runs while the committing connection still holds the
+// write lock, so taking it waits for the commit to complete. None if the row was rolled
+// back to a savepoint.
+pub fn read_inserted(
+    reader: &Connection,
+    row: &Inserted,
+) -> Result<Option<Map<String, JsonValue>>, Error> {
+    let read = || -> Result<Option<Map<String, JsonValue>>, rusqlite::Error> {
+        reader.execute_batch("BEGIN IMMEDIATE;")?;
+        let found = read_row(reader, row);
+        reader.execute_batch("COMMIT;")?;
+        found
+    };
+    read().map_err(Error::database(format!(
+        "read {} row {}",
+        row.table, row.rowid
+    )))
+}
+
+fn read_row(
+    conn: &Connection,
+    row: &Inserted,
+) -> Result<Option<Map<String, JsonValue>>, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {} WHERE rowid = ?1", row.table))?;
+    let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+
+    stmt.query_row([row.rowid], |found| {
+        let mut record = Map::new();
+        for (index, name) in names.iter().enumerate().filter(|(_, name)| *name != "id") {
+            let value = match found.get_ref(index)? {
+                ValueRef::Integer(value) => json!(value),
+                ValueRef::Real(value) => json!(series::narrow(value)),
+                ValueRef::Text(value) => json!(String::from_utf8_lossy(value)),
+                ValueRef::Null | ValueRef::Blob(_) => JsonValue::Null,
+            };
+            record.insert(name.clone(), value);
+        }
+        Ok(record)
+    })
+    .optional()
+}
+
+pub fn backup(conn: &Connection, path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    let copy = || -> Result<(), rusqlite::Error> {
+        let mut target = Connection::open(path)?;
+        let backup = Backup::new(conn, &mut target)?;
+        backup.run_to_completion(5, Duration::from_millis(250), None)
+    };
+    copy().map_err(Error::database(format!("back up to {}", path.display())))
+}
+
+pub fn tables(conn: &Connection) {
+    instruments::tables(conn);
+    ble_sync::tables(conn);
+    Weight::tables(conn);
+    BP::tables(conn);
+    Mood::tables(conn);
+    Heartrate::tables(conn);
+    Temperature::tables(conn);
+    Spo2::tables(conn);
+    webhooks::tables(conn);
+    alerts::tables(conn);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::utils;
+
+    fn database(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("biomon-{}-{}.sqlite", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn rows_rolled_back_to_a_savepoint_read_as_nothing() {
+        let path = database("watch");
+        let store = Store::open(&path).unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        store.watch(vec![sender]);
+
+        let conn = store.connection();
+        conn.execute_batch("BEGIN;").unwrap();
+        store.insert_weight(100, 80.0).unwrap();
+        let _ = utils::atomically(conn, || {
+            store.insert_weight(200, 81.0)?;
+            Err::<(), Error>(Error::Parse(String::from("undone")))
+        });
+        store.insert_weight(300, 82.0).unwrap();
+        conn.execute_batch("COMMIT;").unwrap();
+
+        let reader = Connection::open(&path).unwrap();
+        let mut timestamps = Vec::new();
+        while let Ok(row) = receiver.try_recv() {
+            if let Some(record) = read_inserted(&reader, &row).unwrap() {
+                timestamps.push(record["timestamp"].as_i64().unwrap());
+            }
+        }
+        // The rowid of the undone row went to the next one
+        assert_eq!(timestamps, vec![100, 300]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn restore_replaces_the_data_of_an_open_database() {
+        let (path, saved) = (database("restore"), database("restore-backup"));
+        let store = Store::open(&path).unwrap();
+        store.insert_weight(100, 80.0).unwrap();
+        store.backup(&saved).unwrap();
+        store.insert_weight(200, 81.0).unwrap();
+
+        Store::restore(&saved, &path).unwrap();
+        assert_eq!(store.weight(0, 1000).unwrap().len(), 1);
+        assert!(Store::restore(database("missing"), &path).is_err());
+        assert_eq!(store.weight(0, 1000).unwrap().len(), 1);
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&saved);
+    }
+}
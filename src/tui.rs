@@ -0,0 +1,628 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{Local, NaiveTime, TimeZone, Utc};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::{future::LocalBoxFuture, FutureExt, StreamExt};
+use log::error;
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    symbols::Marker,
+    text::{Line, Span},
+    widgets::{Axis, Block, Chart, Dataset, GraphType, Paragraph, Sparkline},
+    DefaultTerminal, Frame,
+};
+use rusqlite::Connection;
+use tokio::sync::{mpsc::unbounded_channel, oneshot};
+
+use crate::{
+    args::{self, Args},
+    ble,
+    bp::BP,
+    heartrate::{self, Heartrate},
+    mood::Mood,
+    output::{self, Format, Output, Record, Value},
+    series::Series,
+    spo2::{self, Spo2},
+    temperature::{self, Temperature},
+    utils,
+    weight::Weight,
+    writer::Sample,
+    Error, SectionedConfigMap, Stat,
+};
+
+// Set while the TUI owns the terminal, so the logger keeps off stdout
+pub static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+const LIVE_WINDOW_SECONDS: i64 = 300;
+const TREND_DAYS: i64 = 30;
+const REFRESH_SECONDS: u32 = 60;
+
+type Command = fn(&mut Args, &Connection) -> Result<Output, Error>;
+
+struct Metric {
+    name: &'static str,
+    command: Command,
+    // Key that logs a reading, if it can be entered by hand here
+    key: Option<char>,
+    // Takes the whole input as one value instead of splitting it
+    whole_line: bool,
+}
+
+const METRICS: [Metric; 6] = [
+    Metric {
+        name: "weight",
+        command: Weight::command,
+        key: Some('w'),
+        whole_line: false,
+    },
+    Metric {
+        name: "bp",
+        command: BP::command,
+        key: Some('b'),
+        whole_line: false,
+    },
+    Metric {
+        name: "mood",
+        command: Mood::command,
+        key: Some('m'),
+        whole_line: true,
+    },
+    Metric {
+        name: "heartrate",
+        command: Heartrate::command,
+        key: None,
+        whole_line: false,
+    },
+    Metric {
+        name: "temp",
+        command: Temperature::command,
+        key: Some('t'),
+        whole_line: false,
+    },
+    Metric {
+        name: "spo2",
+        command: Spo2::command,
+        key: Some('o'),
+        whole_line: false,
+    },
+];
+
+// Daily averages of one value over the last TREND_DAYS days, oldest first
+struct Trend {
+    label: &'static str,
+    unit: &'static str,
+    days: Vec<Option<f64>>,
+}
+
+enum Action {
+    None,
+    Record,
+    Stop,
+    Quit,
+}
+
+struct App {
+    latest: Vec<(&'static str, Option<Record>)>,
+    trends: Vec<Trend>,
+    // Heart rate samples of the running recording within the live window
+    live: VecDeque<(i64, u8)>,
+    // When the running recording started
+    session: Option<i64>,
+    max_heartrate: u8,
+    // Metric being logged and the input so far
+    input: Option<(&'static Metric, String)>,
+    status: String,
+}
+
+// Shows the latest readings, 30 day trends and the live heart rate of a recording,
+// and logs readings from the keyboard, until q is pressed
+pub async fn run(
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let max_heartrate = utils::from_config_or(conf.clone(), "tui", "max_heartrate", "190");
+    let max_heartrate = max_heartrate.parse::<u8>().map_err(|err| {
+        Error::Config(format!(
+            "max_heartrate in section tui must be a heart rate in bpm, not {}: {}",
+            max_heartrate, err
+        ))
+    })?;
+
+    let mut app = App {
+        latest: Vec::new(),
+        trends: Vec::new(),
+        live: VecDeque::new(),
+        session: None,
+        max_heartrate,
+        input: None,
+        status: String::new(),
+    };
+    app.refresh(conn);
+
+    ACTIVE.store(true, Ordering::Relaxed);
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut app, conf, conn).await;
+    ratatui::restore();
+    ACTIVE.store(false, Ordering::Relaxed);
+
+    result
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<String, Error> {
+    let mut events = EventStream::new();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut ticks = 0;
+    let (tap, mut live) = unbounded_channel();
+    let mut recording: Option<LocalBoxFuture<Result<String, Error>>> = None;
+    let mut stop: Option<oneshot::Sender<()>> = None;
+    let mut quitting = false;
+
+    loop {
+        terminal
+            .draw(|frame| draw(frame, app))
+            .map_err(Error::io("draw the TUI"))?;
+
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    match app.key(key, conn) {
+                        Action::None => {}
+                        Action::Record if recording.is_none() => {
+                            let (sender, receiver) = oneshot::channel();
+                            stop = Some(sender);
+                            let stopped = async {
+                                let _ = receiver.await;
+                            };
+                            recording = Some(
+                                ble::record_until(
+                                    Some("hrp"),
+                                    conf.clone(),
+                                    conn,
+                                    stopped,
+                                    Some(tap.clone()),
+                                )
+                                .boxed_local(),
+                            );
+                            app.live.clear();
+                            app.session = Some(Utc::now().timestamp());
+                            app.status = String::from("Connecting to heart rate devices");
+                        }
+                        Action::Record => app.status = String::from("Already recording"),
+                        Action::Stop => match stop.take() {
+                            Some(stop) => {
+                                let _ = stop.send(());
+                                app.status = String::from("Stopping recording");
+                            }
+                            None => app.status = String::from("Not recording"),
+                        },
+                        Action::Quit => {
+                            // Let the writer flush what it holds before leaving
+                            match stop.take() {
+                                Some(stop) => {
+                                    let _ = stop.send(());
+                                    quitting = true;
+                                }
+                                None if recording.is_some() => quitting = true,
+                                None => break,
+                            }
+                        }
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(Error::Io(String::from("read terminal events"), err)),
+                None => break,
+            },
+            Some(sample) = live.recv() => app.sample(sample),
+            result = async { recording.as_mut().unwrap().await }, if recording.is_some() => {
+                recording = None;
+                stop = None;
+                app.session = None;
+                app.status = output::render(&result.map(Output::message), Format::Human);
+                app.refresh(conn);
+                if quitting {
+                    break;
+                }
+            }
+            _ = tick.tick() => {
+                ticks += 1;
+                if ticks % REFRESH_SECONDS == 0 {
+                    app.refresh(conn);
+                }
+            }
+        }
+    }
+
+    Ok(String::new())
+}
+
+impl App {
+    fn refresh(&mut self, conn: &Connection) {
+        self.latest = METRICS
+            .iter()
+            .map(|metric| {
+                let tokens = [String::from("last"), String::from("1")];
+                let record = match (metric.command)(&mut Args::new(&tokens), conn) {
+                    Ok(Output::Records { mut records, .. }) => records.pop(),
+                    _ => None,
+                };
+                (metric.name, record)
+            })
+            .collect();
+
+        let today = Local::now().date_naive().and_time(NaiveTime::MIN);
+        let today = Local
+            .from_local_datetime(&today)
+            .earliest()
+            .map_or_else(|| Utc::now().timestamp(), |today| today.timestamp());
+        let from = today - (TREND_DAYS - 1) * 86400;
+        let to = today + 86400;
+
+        self.trends = vec![
+            daily(&METRICS[0], "weight", "weight", "kg", from, to, conn),
+            daily(&METRICS[1], "sys", "systolic", "mmHg", from, to, conn),
+            daily(&METRICS[1], "dia", "diastolic", "mmHg", from, to, conn),
+            series_daily(&heartrate::SERIES, "heartrate", "bpm", from, to, conn),
+            series_daily(&temperature::SERIES, "temperature", "°C", from, to, conn),
+            series_daily(&spo2::SERIES, "SpO2", "%", from, to, conn),
+        ];
+    }
+
+    fn sample(&mut self, sample: Sample) {
+        if let Sample::Heartrate {
+            timestamp,
+            heartrate,
+            ..
+        } = sample
+        {
+            self.live.push_back((timestamp, heartrate));
+            while self
+                .live
+                .front()
+                .is_some_and(|(oldest, _)| *oldest <= timestamp - LIVE_WINDOW_SECONDS)
+            {
+                self.live.pop_front();
+            }
+            self.status = String::from("Recording heart rate");
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent, conn: &Connection) -> Action {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Action::Quit;
+        }
+
+        if let Some((metric, text)) = &mut self.input {
+            match key.code {
+                KeyCode::Char(c) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Enter => {
+                    let metric = *metric;
+                    let text = text.trim().to_string();
+                    self.input = None;
+                    self.log(metric, &text, conn);
+                }
+                _ => {}
+            }
+            return Action::None;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => Action::Quit,
+            KeyCode::Char('r') => Action::Record,
+            KeyCode::Char('s') => Action::Stop,
+            KeyCode::Char(c) => {
+                if let Some(metric) = METRICS.iter().find(|metric| metric.key == Some(c)) {
+                    self.input = Some((metric, String::new()));
+                }
+                Action::None
+            }
+            _ => Action::None,
+        }
+    }
+
+    // Logs a reading through the same command the REPL uses
+    fn log(&mut self, metric: &Metric, text: &str, conn: &Connection) {
+        let tokens = if metric.whole_line {
+            Ok(vec![String::from(text)])
+        } else {
+            args::tokenize(text)
+        };
+
+        let result = tokens.and_then(|tokens| (metric.command)(&mut Args::new(&tokens), conn));
+        self.status = output::render(&result, Format::Human);
+        if result.is_ok() {
+            self.refresh(conn);
+        }
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(value) => Some(*value as f64),
+        Value::Real(value) => Some(*value),
+        Value::Text(_) => None,
+    }
+}
+
+// Averages a field of a metric's readings per day
+fn daily(
+    metric: &Metric,
+    field: &str,
+    label: &'static str,
+    unit: &'static str,
+    from: i64,
+    to: i64,
+    conn: &Connection,
+) -> Trend {
+    let mut sums = vec![(0.0, 0); TREND_DAYS as usize];
+    let tokens = [String::from("range"), from.to_string(), to.to_string()];
+    if let Ok(Output::Records { records, .. }) = (metric.command)(&mut Args::new(&tokens), conn) {
+        for record in records {
+            let day = ((record.timestamp - from) / 86400) as usize;
+            let value = record
+                .fields
+                .iter()
+                .find(|f| f.name == field)
+                .and_then(|f| number(&f.value));
+            if let (Some(sum), Some(value)) = (sums.get_mut(day), value) {
+                sum.0 += value;
+                sum.1 += 1;
+            }
+        }
+    }
+
+    Trend {
+        label,
+        unit,
+        days: sums
+            .into_iter()
+            .map(|(sum, count)| (count > 0).then(|| sum / count as f64))
+            .collect(),
+    }
+}
+
+// Daily means of the first column of a continuous metric, rollups included
+fn series_daily<V: crate::series::Value>(
+    series: &Series<V>,
+    label: &'static str,
+    unit: &'static str,
+    from: i64,
+    to: i64,
+    conn: &Connection,
+) -> Trend {
+    let mut days = vec![None; TREND_DAYS as usize];
+    match series.trend(from, to, 86400, conn) {
+        Ok(buckets) => {
+            for (timestamp, stats) in buckets {
+                let day = ((timestamp - from) / 86400) as usize;
+                if let (Some(slot), Some(column)) = (days.get_mut(day), stats.columns.first()) {
+                    *slot = Some(column.mean);
+                }
+            }
+        }
+        Err(err) => error!("Failed to query {} trend -> {}", label, err),
+    }
+
+    Trend { label, unit, days }
+}
+
+fn ago(timestamp: i64) -> String {
+    let seconds = (Utc::now().timestamp() - timestamp).max(0);
+    match seconds {
+        0..=59 => format!("{}s ago", seconds),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+// Zones by share of the maximum heart rate
+fn zone(heartrate: u8, max_heartrate: u8) -> (&'static str, Color) {
+    let percent = heartrate as u32 * 100 / max_heartrate.max(1) as u32;
+    match percent {
+        0..=49 => ("resting", Color::Gray),
+        50..=59 => ("zone 1 very light", Color::Blue),
+        60..=69 => ("zone 2 light", Color::Green),
+        70..=79 => ("zone 3 moderate", Color::Yellow),
+        80..=89 => ("zone 4 hard", Color::LightRed),
+        _ => ("zone 5 maximum", Color::Red),
+    }
+}
+
+fn draw(frame: &mut Frame, app: &App) {
+    let [top, trends, footer] = Layout::vertical([
+        Constraint::Length(METRICS.len() as u16 + 2),
+        Constraint::Min(app.trends.len() as u16 + 2),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [latest, live] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(top);
+
+    draw_latest(frame, latest, app);
+    draw_live(frame, live, app);
+    draw_trends(frame, trends, app);
+    draw_footer(frame, footer, app);
+}
+
+fn draw_latest(frame: &mut Frame, area: Rect, app: &App) {
+    let lines: Vec<Line> = app
+        .latest
+        .iter()
+        .map(|(name, record)| match record {
+            Some(record) => Line::from(vec![
+                Span::from(format!("{:<11}", name)).bold(),
+                Span::from(record.values()),
+                Span::from(format!("  {}", ago(record.timestamp))).dark_gray(),
+            ]),
+            None => Line::from(vec![
+                Span::from(format!("{:<11}", name)).bold(),
+                Span::from("no readings").dark_gray(),
+            ]),
+        })
+        .collect();
+
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Latest ")),
+        area,
+    );
+}
+
+fn draw_live(frame: &mut Frame, area: Rect, app: &App) {
+    let block = Block::bordered().title(" Live heart rate ");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    let [header, graph] =
+        Layout::vertical([Constraint::Length(1), Constraint::Min(1)]).areas(inner);
+
+    let now = Utc::now().timestamp();
+    let mut spans = Vec::new();
+    match (app.session, app.live.back()) {
+        (Some(_), Some((_, heartrate))) => {
+            let (zone, color) = zone(*heartrate, app.max_heartrate);
+            spans.push(Span::from(format!("{} bpm ", heartrate)).bold().fg(color));
+            spans.push(Span::from(zone).fg(color));
+        }
+        (Some(_), None) => spans.push(Span::from("waiting for samples").dark_gray()),
+        (None, _) => spans.push(Span::from("not recording, press r to start").dark_gray()),
+    }
+    if let Some(session) = app.session {
+        let seconds = now - session;
+        spans.push(Span::from(format!(
+            "  session {:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )));
+    }
+    frame.render_widget(Paragraph::new(Line::from(spans)), header);
+
+    let points: Vec<(f64, f64)> = app
+        .live
+        .iter()
+        .map(|(timestamp, heartrate)| ((timestamp - now) as f64, *heartrate as f64))
+        .collect();
+    let (low, high) = points
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(low, high), (_, y)| {
+            (low.min(*y), high.max(*y))
+        });
+    let (low, high) = if points.is_empty() {
+        (40.0, 180.0)
+    } else {
+        ((low - 5.0).max(0.0), high + 5.0)
+    };
+
+    let chart = Chart::new(vec![Dataset::default()
+        .marker(Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(Style::default().fg(Color::Red))
+        .data(&points)])
+    .x_axis(
+        Axis::default()
+            .bounds([-LIVE_WINDOW_SECONDS as f64, 0.0])
+            .labels([
+                Span::from(format!("-{}m", LIVE_WINDOW_SECONDS / 60)),
+                Span::from("now"),
+            ]),
+    )
+    .y_axis(
+        Axis::default()
+            .bounds([low, high])
+            .labels([format!("{:.0}", low), format!("{:.0}", high)]),
+    );
+    frame.render_widget(chart, graph);
+}
+
+fn draw_trends(frame: &mut Frame, area: Rect, app: &App) {
+    let block = Block::bordered().title(format!(" Last {} days ", TREND_DAYS));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let rows = Layout::vertical(app.trends.iter().map(|_| Constraint::Length(1))).split(inner);
+    for (trend, row) in app.trends.iter().zip(rows.iter()) {
+        let [label, sparkline, summary] = Layout::horizontal([
+            Constraint::Length(13),
+            Constraint::Min(TREND_DAYS as u16),
+            Constraint::Length(36),
+        ])
+        .areas(*row);
+
+        let values: Vec<f64> = trend.days.iter().flatten().copied().collect();
+        let low = values.iter().copied().fold(f64::MAX, f64::min);
+        let high = values.iter().copied().fold(f64::MIN, f64::max);
+        // Missing days stay empty, the lowest day still gets the smallest bar
+        let data: Vec<u64> = trend
+            .days
+            .iter()
+            .map(|day| match day {
+                Some(value) if high > low => {
+                    1 + ((value - low) / (high - low) * 6.0).round() as u64
+                }
+                Some(_) => 4,
+                None => 0,
+            })
+            .collect();
+
+        frame.render_widget(Paragraph::new(trend.label).bold(), label);
+        frame.render_widget(
+            Sparkline::default()
+                .data(&data)
+                .max(7)
+                .style(Style::default().fg(Color::Cyan)),
+            sparkline,
+        );
+        let text = match values.last() {
+            Some(last) => format!(
+                " last {:.1}{unit}, {:.1} to {:.1}",
+                last,
+                low,
+                high,
+                unit = trend.unit
+            ),
+            None => String::from(" no readings"),
+        };
+        frame.render_widget(Paragraph::new(text).dark_gray(), summary);
+    }
+}
+
+fn draw_footer(frame: &mut Frame, area: Rect, app: &App) {
+    let line = match &app.input {
+        Some((metric, text)) => Line::from(vec![
+            Span::from(format!("{}> ", metric.name)).bold(),
+            Span::from(text.as_str()),
+            Span::from("_").slow_blink(),
+            Span::from("  enter to log, esc to cancel").dark_gray(),
+        ]),
+        None => {
+            let keys: Vec<String> = METRICS
+                .iter()
+                .filter_map(|metric| metric.key.map(|key| format!("{} {}", key, metric.name)))
+                .collect();
+            Line::from(vec![
+                Span::from(format!("{}  r record  s stop  q quit", keys.join("  "))).dark_gray(),
+                Span::from(format!(
+                    "  {}",
+                    app.status.lines().next().unwrap_or_default()
+                )),
+            ])
+        }
+    };
+    frame.render_widget(Paragraph::new(line), area);
+}
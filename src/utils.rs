@@ -1,10 +1,23 @@
 use std::sync::{Arc, RwLock};
 
 use chrono::LocalResult::Single;
-use chrono::{Local, TimeZone, Utc};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
 use log::error;
+use rusqlite::Connection;
 
-use crate::SectionedConfigMap;
+use crate::{args::Args, Error, SectionedConfigMap};
+
+tokio::task_local! {
+    // Time manually entered readings are recorded at, set by batch files
+    pub static ENTRY_TIME: i64;
+}
+
+// Timestamp for a manually entered reading
+pub fn entry_time() -> i64 {
+    ENTRY_TIME
+        .try_with(|timestamp| *timestamp)
+        .unwrap_or_else(|_| Utc::now().timestamp())
+}
 
 pub fn format_timestamp(timestamp: i64) -> String {
     match Utc.timestamp_opt(timestamp, 0) {
@@ -13,33 +26,98 @@ pub fn format_timestamp(timestamp: i64) -> String {
     }
 }
 
-pub fn from_config(
+// Accepts unix seconds, RFC 3339, or a local date or date and time
+// like 2024-03-01 and 2024-03-01T08:30
+pub fn parse_timestamp(input: &str) -> Option<i64> {
+    if let Ok(timestamp) = input.parse::<i64>() {
+        return Some(timestamp);
+    }
+
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(input) {
+        return Some(datetime.timestamp());
+    }
+
+    let datetime = NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(input, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(input, "%Y-%m-%d").map(|date| date.and_time(NaiveTime::MIN))
+        })
+        .ok()?;
+
+    Local
+        .from_local_datetime(&datetime)
+        .earliest()
+        .map(|datetime| datetime.timestamp())
+}
+
+// Reads <from> [<to>], where the range ends now when <to> is left out
+pub fn parse_range(input: &mut Args) -> Result<(i64, i64), Error> {
+    let from = input
+        .next()
+        .ok_or_else(|| Error::Parse(String::from("Missing parameter: from")))?;
+    let from = parse_timestamp(from)
+        .ok_or_else(|| Error::Parse(format!("Failed to parse parameter: <from> {}", from)))?;
+
+    let to = match input.next() {
+        Some(to) => parse_timestamp(to)
+            .ok_or_else(|| Error::Parse(format!("Failed to parse parameter: <to> {}", to)))?,
+        // Include the current second
+        None => Utc::now().timestamp() + 1,
+    };
+
+    Ok((from, to))
+}
+
+// Names the instrument a sample came from, if it is known
+pub fn format_source(source: &Option<String>) -> String {
+    match source {
+        Some(name) => format!(" from {}", name),
+        None => String::new(),
+    }
+}
+
+// Reads an optional setting, falling back to a default if it is missing
+pub fn from_config_or(
     conf: Arc<RwLock<SectionedConfigMap>>,
     section: &str,
     key: &str,
-) -> Result<String, ()> {
+    default: &str,
+) -> String {
     let conf_guard = match conf.read() {
         Ok(conf_guard) => conf_guard,
         Err(err) => {
             error!("Failed to aquire lock on config map -> {}", err);
-            return Err(());
+            return String::from(default);
         }
     };
 
     conf_guard
         .get(section)
-        .ok_or_else(|| {
-            error!("Missing config section {}", section);
-        })
-        .and_then(|secmap| {
-            secmap.get(key).ok_or_else(|| {
-                error!("Missing config key {} in section {}", key, section);
-            })
-        })
-        .and_then(|kv| {
-            kv.as_ref().ok_or_else(|| {
-                error!("Missing config value for {} in section {}", key, section);
-            })
-        })
-        .cloned()
+        .and_then(|secmap| secmap.get(key))
+        .and_then(|kv| kv.clone())
+        .unwrap_or_else(|| String::from(default))
+}
+
+// Runs the closure in a savepoint, so its writes are applied completely or not at all.
+// Unlike a transaction this also works while another transaction is open.
+pub fn atomically<T, E: From<rusqlite::Error>>(
+    conn: &Connection,
+    f: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    conn.execute_batch("SAVEPOINT atomically;")?;
+
+    match f() {
+        Ok(value) => {
+            conn.execute_batch("RELEASE atomically;")?;
+            Ok(value)
+        }
+        Err(err) => {
+            if let Err(rollback_err) =
+                conn.execute_batch("ROLLBACK TO atomically; RELEASE atomically;")
+            {
+                error!("Failed to roll back savepoint -> {}", rollback_err);
+            }
+            Err(err)
+        }
+    }
 }
@@ -0,0 +1,674 @@
+use std::{
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::Duration,
+};
+
+use chrono::Utc;
+use futures::future::{join_all, BoxFuture};
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{json, Map, Value};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::{self, JoinHandle},
+    time::interval,
+};
+
+use crate::{
+    args::Args,
+    http::METRICS,
+    output::{self, Field, Output, Record},
+    store::{Consumer, Reading},
+    utils, Error, SectionedConfigMap,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// How often pending deliveries are retried
+const RETRY_CHECK: Duration = Duration::from_secs(15);
+// Delay before the second attempt, doubled for each further one
+const BACKOFF_SECS: i64 = 30;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Comparison {
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+}
+
+impl Comparison {
+    pub(crate) fn parse(op: &str) -> Option<Comparison> {
+        match op {
+            ">" => Some(Comparison::Above),
+            ">=" => Some(Comparison::AtLeast),
+            "<" => Some(Comparison::Below),
+            "<=" => Some(Comparison::AtMost),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn holds(&self, value: f64, limit: f64) -> bool {
+        match self {
+            Comparison::Above => value > limit,
+            Comparison::AtLeast => value >= limit,
+            Comparison::Below => value < limit,
+            Comparison::AtMost => value <= limit,
+        }
+    }
+
+    // The same comparison in SQL
+    pub(crate) fn operator(&self) -> &'static str {
+        match self {
+            Comparison::Above => ">",
+            Comparison::AtLeast => ">=",
+            Comparison::Below => "<",
+            Comparison::AtMost => "<=",
+        }
+    }
+}
+
+// The metric and field of a numeric value, like bp and sys for sys
+pub(crate) fn field(name: &str) -> Option<(&'static str, &'static str)> {
+    METRICS
+        .iter()
+        .filter(|metric| metric.path != "mood")
+        .find_map(|metric| {
+            let field = metric.fields.iter().find(|candidate| **candidate == name)?;
+            Some((metric.path, *field))
+        })
+}
+
+struct Threshold {
+    metric: &'static str,
+    field: &'static str,
+    comparison: Comparison,
+    limit: f64,
+    // As configured, e.g. "temperature > 38.5"
+    rule: String,
+}
+
+impl Threshold {
+    fn exceeded(&self, value: f64) -> bool {
+        self.comparison.holds(value, self.limit)
+    }
+
+    // Whether the reading before timestamp exceeded it, so a recorded stream only
+    // notifies when it crosses the threshold instead of once per sample, also across
+    // restarts
+    fn exceeded_before(&self, timestamp: i64, conn: &Connection) -> Result<bool, rusqlite::Error> {
+        let previous: Option<f64> = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM {} WHERE timestamp < ?1 ORDER BY timestamp DESC LIMIT 1;",
+                    self.field, self.metric
+                ),
+                [timestamp],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(previous.is_some_and(|previous| self.exceeded(previous)))
+    }
+}
+
+// Configured as `thresholds = <field> <op> <limit>, ...` in section webhooks, where field
+// is a value of a metric like sys, dia, temperature, heartrate or spo2 and op is one of
+// >, >=, < and <=.
+fn thresholds(entries: &str) -> Result<Vec<Threshold>, Error> {
+    entries
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let invalid = || {
+                Error::Config(format!(
+                    "Invalid threshold '{}' in section webhooks, expected e.g. temperature > 38.5",
+                    entry.trim()
+                ))
+            };
+            let parts: Vec<&str> = entry.split_whitespace().collect();
+            let [field, op, limit] = parts[..] else {
+                return Err(invalid());
+            };
+            let (metric, field) = self::field(field).ok_or_else(invalid)?;
+
+            Ok(Threshold {
+                metric,
+                field,
+                comparison: Comparison::parse(op).ok_or_else(invalid)?,
+                limit: limit.parse().map_err(|_| invalid())?,
+                rule: parts.join(" "),
+            })
+        })
+        .collect()
+}
+
+struct Settings {
+    urls: Vec<String>,
+    thresholds: Vec<Threshold>,
+    // Before a delivery is given up
+    attempts: i64,
+}
+
+impl Settings {
+    // None without urls in section webhooks
+    fn from_config(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Option<Settings>, Error> {
+        let urls: Vec<String> = utils::from_config_or(conf.clone(), "webhooks", "urls", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect();
+        if urls.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Settings {
+            urls,
+            thresholds: thresholds(&utils::from_config_or(
+                conf.clone(),
+                "webhooks",
+                "thresholds",
+                "",
+            ))?,
+            attempts: attempts(conf)?,
+        }))
+    }
+}
+
+fn attempts(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<i64, Error> {
+    let attempts = utils::from_config_or(conf, "webhooks", "attempts", "5");
+    match attempts.parse::<i64>() {
+        Ok(attempts) if attempts > 0 => Ok(attempts),
+        _ => Err(Error::Config(format!(
+            "attempts in section webhooks must be a positive number, not {}",
+            attempts
+        ))),
+    }
+}
+
+pub fn tables(conn: &Connection) {
+    let _ = conn
+        .execute(
+            "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                    id          INTEGER PRIMARY KEY,
+                    created     INTEGER NOT NULL,
+                    url         TEXT NOT NULL,
+                    payload     TEXT NOT NULL,
+                    attempts    INTEGER DEFAULT (0) NOT NULL,
+                    next_attempt INTEGER NOT NULL,
+                    delivered   INTEGER,
+                    error       TEXT
+                );",
+            [],
+        )
+        .map_err(|err| {
+            error!(
+                "Failed to ensure table 'webhook_deliveries' exists -> {}",
+                err
+            )
+        });
+}
+
+// Posts readings crossing a threshold to the webhook urls, see Listener. Deliveries are
+// queued in the database, so the ones failing now are retried later, also by the next
+// biomon to start. They run in a task of their own, so a slow url does not hold up
+// checking the readings.
+pub struct Notifier {
+    settings: Settings,
+    // Both set once started
+    queued: Option<UnboundedSender<()>>,
+    delivering: Option<JoinHandle<()>>,
+}
+
+impl Notifier {
+    // None if no webhook is configured
+    pub fn from_config(conf: Arc<RwLock<SectionedConfigMap>>) -> Result<Option<Notifier>, Error> {
+        let settings = match Settings::from_config(conf)? {
+            Some(settings) => settings,
+            None => return Ok(None),
+        };
+
+        info!("Notifying {} webhooks", settings.urls.len());
+        Ok(Some(Notifier {
+            settings,
+            queued: None,
+            delivering: None,
+        }))
+    }
+}
+
+impl Consumer for Notifier {
+    fn wants(&self, table: &str) -> bool {
+        self.settings
+            .thresholds
+            .iter()
+            .any(|threshold| threshold.metric == table)
+    }
+
+    // Deliveries left by the last biomon are tried right away
+    fn start(&mut self, conn: &Arc<Mutex<Connection>>) {
+        let (queued, wakeups) = unbounded_channel();
+        self.queued = Some(queued);
+        self.delivering = Some(tokio::spawn(deliveries(
+            wakeups,
+            conn.clone(),
+            self.settings.attempts,
+        )));
+    }
+
+    fn consume(&mut self, reading: &Reading, conn: &Connection) {
+        if check(reading, conn, &self.settings) {
+            if let Some(queued) = &self.queued {
+                let _ = queued.send(());
+            }
+        }
+    }
+
+    // Tries the deliveries that are due once more
+    fn finish(self: Box<Self>) -> BoxFuture<'static, ()> {
+        let Notifier {
+            queued, delivering, ..
+        } = *self;
+        drop(queued);
+        Box::pin(async move {
+            if let Some(delivering) = delivering {
+                let _ = delivering.await;
+            }
+        })
+    }
+}
+
+// Delivers what is due whenever deliveries were queued and every RETRY_CHECK, until the
+// sender of queued is dropped
+async fn deliveries(
+    mut queued: UnboundedReceiver<()>,
+    conn: Arc<Mutex<Connection>>,
+    attempts: i64,
+) {
+    let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+    let mut retry = interval(RETRY_CHECK);
+
+    loop {
+        let open = tokio::select! {
+            woken = queued.recv() => woken.is_some(),
+            _ = retry.tick() => true,
+        };
+        deliver(&agent, &conn, attempts).await;
+        if !open {
+            break;
+        }
+    }
+}
+
+// Queues a delivery to each url for the thresholds the reading crossed, returning
+// whether it did
+fn check(reading: &Reading, conn: &Connection, settings: &Settings) -> bool {
+    let Reading { table, record } = reading;
+    let Some(timestamp) = record.get("timestamp").and_then(Value::as_i64) else {
+        return false;
+    };
+
+    let mut queued = false;
+    for threshold in &settings.thresholds {
+        if threshold.metric != table {
+            continue;
+        }
+        let Some(value) = record.get(threshold.field).and_then(Value::as_f64) else {
+            continue;
+        };
+        if !threshold.exceeded(value) {
+            continue;
+        }
+        match threshold.exceeded_before(timestamp, conn) {
+            Ok(false) => {}
+            Ok(true) => continue,
+            Err(err) => {
+                error!("Failed to check threshold {} -> {}", threshold.rule, err);
+                continue;
+            }
+        }
+
+        info!("Reading of {} crossed {}", table, threshold.rule);
+        let payload = payload(threshold, record).to_string();
+        match queue(&settings.urls, &payload, conn) {
+            Ok(()) => queued = true,
+            Err(err) => error!("Failed to queue webhook deliveries -> {}", err),
+        }
+    }
+    queued
+}
+
+fn payload(threshold: &Threshold, reading: &Map<String, Value>) -> Value {
+    let timestamp = reading.get("timestamp").and_then(Value::as_i64);
+    json!({
+        "event": "threshold",
+        "threshold": threshold.rule,
+        "metric": threshold.metric,
+        "field": threshold.field,
+        "value": reading.get(threshold.field),
+        "timestamp": timestamp,
+        "time": timestamp.map(utils::format_timestamp),
+        "reading": reading,
+    })
+}
+
+fn queue(urls: &[String], payload: &str, conn: &Connection) -> Result<(), rusqlite::Error> {
+    let now = Utc::now().timestamp();
+    let tx = conn.unchecked_transaction()?;
+    for url in urls {
+        tx.execute(
+            "INSERT INTO webhook_deliveries (created, url, payload, next_attempt) VALUES (?1, ?2, ?3, ?1);",
+            params![now, url, payload],
+        )?;
+    }
+    tx.commit()
+}
+
+// Attempts the deliveries that are due all at once, backing off after failures
+async fn deliver(agent: &ureq::Agent, conn: &Mutex<Connection>, attempts: i64) {
+    let due = match due(
+        &conn.lock().unwrap_or_else(PoisonError::into_inner),
+        attempts,
+    ) {
+        Ok(due) => due,
+        Err(err) => {
+            error!("Failed to read due webhook deliveries -> {}", err);
+            return;
+        }
+    };
+
+    let posts = due.into_iter().map(|(id, url, payload, attempt)| {
+        let agent = agent.clone();
+        async move {
+            let post = {
+                let url = url.clone();
+                task::spawn_blocking(move || {
+                    agent
+                        .post(&url)
+                        .set("Content-Type", "application/json")
+                        .send_string(&payload)
+                        .map(|_| ())
+                        .map_err(|err| err.to_string())
+                })
+            };
+            let result = post.await.unwrap_or_else(|err| Err(err.to_string()));
+            (id, url, attempt, result)
+        }
+    });
+
+    for (id, url, attempt, result) in join_all(posts).await {
+        let attempt = attempt + 1;
+        let now = Utc::now().timestamp();
+        let conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let updated = match &result {
+            Ok(()) => {
+                info!("Delivered webhook {} to {}", id, url);
+                conn.execute(
+                    "UPDATE webhook_deliveries SET attempts = ?1, delivered = ?2, error = NULL WHERE id = ?3;",
+                    params![attempt, now, id],
+                )
+            }
+            Err(err) => {
+                if attempt >= attempts {
+                    error!(
+                        "Gave up webhook {} to {} after {} attempts -> {}",
+                        id, url, attempt, err
+                    );
+                } else {
+                    warn!(
+                        "Failed to deliver webhook {} to {}, retrying -> {}",
+                        id, url, err
+                    );
+                }
+                let next_attempt = now + BACKOFF_SECS * (1 << (attempt - 1).min(16));
+                conn.execute(
+                    "UPDATE webhook_deliveries SET attempts = ?1, next_attempt = ?2, error = ?3 WHERE id = ?4;",
+                    params![attempt, next_attempt, err, id],
+                )
+            }
+        };
+        if let Err(err) = updated {
+            error!("Failed to log webhook delivery {} -> {}", id, err);
+        }
+    }
+}
+
+fn due(
+    conn: &Connection,
+    attempts: i64,
+) -> Result<Vec<(i64, String, String, i64)>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, url, payload, attempts FROM webhook_deliveries
+            WHERE delivered IS NULL AND attempts < ?1 AND next_attempt <= ?2
+            ORDER BY id;",
+    )?;
+    let rows = stmt.query_map(params![attempts, Utc::now().timestamp()], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    rows.collect()
+}
+
+// Lists the latest deliveries with their outcome
+pub fn command(
+    input: &mut Args,
+    conf: Arc<RwLock<SectionedConfigMap>>,
+    conn: &Connection,
+) -> Result<Output, Error> {
+    let count = match input.next() {
+        Some(count) => count
+            .parse::<i64>()
+            .map_err(|e| Error::Parse(format!("Failed to parse parameter: {}: {}", count, e)))?,
+        None => 10,
+    };
+    let attempts = attempts(conf)?;
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, created, url, attempts, delivered, error FROM webhook_deliveries
+                ORDER BY id DESC LIMIT ?1;",
+        )
+        .map_err(Error::database("read webhook deliveries"))?;
+    let records = stmt
+        .query_map([count], |row| {
+            let tried: i64 = row.get(3)?;
+            let delivered: Option<i64> = row.get(4)?;
+            let failure: Option<String> = row.get(5)?;
+            let status = match (delivered, failure) {
+                (Some(delivered), _) => {
+                    format!("delivered {}", utils::format_timestamp(delivered))
+                }
+                (None, Some(failure)) if tried >= attempts => {
+                    format!("gave up after {} attempts: {}", tried, failure)
+                }
+                (None, Some(failure)) => format!("pending after {} attempts: {}", tried, failure),
+                (None, None) => String::from("pending"),
+            };
+
+            Ok(Record {
+                id: row.get(0)?,
+                timestamp: row.get(1)?,
+                duration: None,
+                source: None,
+                fields: vec![
+                    Field {
+                        name: "url",
+                        value: output::Value::Text(row.get(2)?),
+                        unit: "",
+                        label: "{}",
+                    },
+                    Field {
+                        name: "status",
+                        value: output::Value::Text(status),
+                        unit: "",
+                        label: "{}",
+                    },
+                ],
+            })
+        })
+        .and_then(|rows| rows.collect::<Result<Vec<Record>, rusqlite::Error>>())
+        .map_err(Error::database("read webhook deliveries"))?;
+
+    Ok(Output::Records {
+        notes: Vec::new(),
+        records,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tiny_http::{Response, Server};
+
+    use super::*;
+    use crate::store::{self, Inserted};
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        store::tables(&conn);
+        conn
+    }
+
+    fn settings(urls: &[&str], thresholds: &str) -> Settings {
+        Settings {
+            urls: urls.iter().map(|url| String::from(*url)).collect(),
+            thresholds: self::thresholds(thresholds).unwrap(),
+            attempts: 5,
+        }
+    }
+
+    // Attempts, whether delivered and whether an error was logged, oldest first
+    fn deliveries(conn: &Connection) -> Vec<(i64, bool, bool)> {
+        conn.prepare(
+            "SELECT attempts, delivered IS NOT NULL, error IS NOT NULL FROM webhook_deliveries ORDER BY id;",
+        )
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+    }
+
+    #[test]
+    fn thresholds_name_a_field_an_operator_and_a_limit() {
+        let parsed = thresholds("temperature > 38.5, sys >= 140").unwrap();
+        assert_eq!(
+            (parsed[0].metric, parsed[0].field),
+            ("temperature", "temperature")
+        );
+        assert_eq!((parsed[1].metric, parsed[1].field), ("bp", "sys"));
+        assert_eq!(parsed[1].limit, 140.0);
+        assert_eq!(parsed[1].rule, "sys >= 140");
+
+        for invalid in [
+            "mood > 3",
+            "temperature 38",
+            "temperature => 38",
+            "temperature > x",
+        ] {
+            assert!(thresholds(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn comparisons_include_their_limit_only_with_equals() {
+        let holds = |op: &str, value: f64| Comparison::parse(op).unwrap().holds(value, 38.0);
+        assert!(!holds(">", 38.0) && holds(">", 38.1));
+        assert!(holds(">=", 38.0) && !holds(">=", 37.9));
+        assert!(!holds("<", 38.0) && holds("<", 37.9));
+        assert!(holds("<=", 38.0) && !holds("<=", 38.1));
+        for op in [">", ">=", "<", "<="] {
+            assert_eq!(Comparison::parse(op).unwrap().operator(), op);
+        }
+        assert!(Comparison::parse("=").is_none());
+    }
+
+    #[test]
+    fn only_readings_crossing_a_threshold_are_queued() {
+        let conn = database();
+        conn.execute_batch(
+            "INSERT INTO temperature (timestamp, temperature) VALUES (1, 39.0);
+            INSERT INTO temperature (timestamp, temperature) VALUES (2, 39.5);
+            INSERT INTO temperature (timestamp, temperature) VALUES (3, 37.0);
+            INSERT INTO temperature (timestamp, temperature) VALUES (4, 39.0);",
+        )
+        .unwrap();
+        let settings = settings(&["http://a", "http://b"], "temperature > 38");
+
+        // The first reading crosses, as nothing was stored before it
+        let queued: Vec<bool> = (1..=4)
+            .map(|rowid| {
+                let row = Inserted {
+                    table: String::from("temperature"),
+                    rowid,
+                };
+                let reading = Reading {
+                    record: store::read_inserted(&conn, &row).unwrap().unwrap(),
+                    table: row.table,
+                };
+                check(&reading, &conn, &settings)
+            })
+            .collect();
+        assert_eq!(queued, vec![true, false, false, true]);
+        assert_eq!(deliveries(&conn).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_back_off_until_given_up() {
+        let conn = Mutex::new(database());
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+        // Nothing listens on the discard port
+        queue(
+            &[String::from("http://127.0.0.1:9/")],
+            "{}",
+            &conn.lock().unwrap(),
+        )
+        .unwrap();
+
+        deliver(&agent, &conn, 5).await;
+        {
+            let conn = conn.lock().unwrap();
+            assert_eq!(deliveries(&conn), vec![(1, false, true)]);
+            let wait: i64 = conn
+                .query_row(
+                    "SELECT next_attempt - created FROM webhook_deliveries;",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert!(wait >= BACKOFF_SECS);
+            assert!(due(&conn, 5).unwrap().is_empty());
+
+            conn.execute_batch("UPDATE webhook_deliveries SET attempts = 4, next_attempt = 0;")
+                .unwrap();
+            assert_eq!(due(&conn, 5).unwrap().len(), 1);
+        }
+
+        deliver(&agent, &conn, 5).await;
+        let conn = conn.lock().unwrap();
+        assert_eq!(deliveries(&conn), vec![(5, false, true)]);
+        conn.execute_batch("UPDATE webhook_deliveries SET next_attempt = 0;")
+            .unwrap();
+        assert!(due(&conn, 5).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deliveries_post_their_payload() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+        let stub = thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            request.respond(Response::empty(204)).unwrap();
+            body
+        });
+
+        let conn = Mutex::new(database());
+        queue(&[url], r#"{"event":"threshold"}"#, &conn.lock().unwrap()).unwrap();
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+        deliver(&agent, &conn, 5).await;
+
+        assert_eq!(stub.join().unwrap(), r#"{"event":"threshold"}"#);
+        assert_eq!(deliveries(&conn.lock().unwrap()), vec![(1, true, false)]);
+    }
+}
@@ -1,15 +1,42 @@
-use std::str::SplitWhitespace;
-
-use chrono::Utc;
 use log::error;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, Params, Row};
 
-use crate::{utils, Stat};
+use crate::{
+    args::Args,
+    output::{Field, Output, Record, Value},
+    utils, Error, Stat,
+};
 
-struct WeightORM {
-    _id: i64,
-    timestamp: i64,
-    weight: f64,
+pub struct WeightORM {
+    pub id: i64,
+    pub timestamp: i64,
+    // Kilograms
+    pub weight: f64,
+}
+
+impl WeightORM {
+    fn read(row: &Row) -> Result<WeightORM, rusqlite::Error> {
+        Ok(WeightORM {
+            id: row.get(0)?,
+            timestamp: row.get(1)?,
+            weight: row.get(2)?,
+        })
+    }
+
+    pub fn record(self) -> Record {
+        Record {
+            id: self.id,
+            timestamp: self.timestamp,
+            duration: None,
+            source: None,
+            fields: vec![Field {
+                name: "weight",
+                value: Value::Real(self.weight),
+                unit: "kg",
+                label: "{}",
+            }],
+        }
+    }
 }
 
 pub struct Weight;
@@ -28,100 +55,118 @@ impl Stat for Weight {
             .map_err(|err| error!("Failed to ensure table 'weight' exists -> {}", err));
     }
 
-    fn command(input: &mut SplitWhitespace, conn: &Connection) -> String {
+    fn command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
         let param = match input.next() {
             Some(param) => param,
-            None => return String::from("No further parameters"),
+            None => return Err(Error::Parse(String::from("No further parameters"))),
         };
 
         match param {
-            "last" => last(input, conn),
+            "last" => last_command(input, conn),
+            "range" => range_command(input, conn),
             _ => {
-                let weight = match param.parse::<f64>() {
-                    Ok(weight) => weight,
-                    Err(e) => return format!("Failed to parse parameter: {}", e),
-                };
-
-                let timestamp = Utc::now().timestamp();
-                match conn.execute(
-                    "INSERT INTO weight (timestamp, weight) VALUES (?1, ?2);",
-                    params![timestamp, weight],
-                ) {
-                    Ok(_) => format!("Recorded weight: {}kg", weight),
-                    Err(err) => {
-                        error!("Failed to write weight to database -> {}", err);
-                        String::from(
-                            "Failed to write weight to database. Check log for full error.",
-                        )
-                    }
+                let weight = param.parse::<f64>().map_err(|err| {
+                    Error::Parse(format!("Failed to parse parameter: {}: {}", param, err))
+                })?;
+                // NaN and infinity cannot be averaged or shown as JSON
+                if !weight.is_finite() {
+                    return Err(Error::Parse(format!(
+                        "Failed to parse parameter: {}: not a finite number",
+                        param
+                    )));
                 }
+
+                let entry = insert(utils::entry_time(), weight, conn)
+                    .map_err(Error::database("write weight"))?;
+                Ok(Output::Recorded {
+                    metric: "weight",
+                    record: entry.record(),
+                })
             }
         }
     }
 
     fn help() -> String {
-        String::from("\tweight <last <count:i64> | weight:f64>\n")
+        String::from("\tweight <last <count:i64> | range <from> <to> | weight:f64>\n")
     }
 }
 
-fn last(input: &mut SplitWhitespace, conn: &Connection) -> String {
-    let mut output = String::new();
+pub fn insert(
+    timestamp: i64,
+    weight: f64,
+    conn: &Connection,
+) -> Result<WeightORM, rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO weight (timestamp, weight) VALUES (?1, ?2);",
+        params![timestamp, weight],
+    )?;
+
+    Ok(WeightORM {
+        id: conn.last_insert_rowid(),
+        timestamp,
+        weight,
+    })
+}
+
+// The latest count entries, newest first
+pub fn last(count: i64, conn: &Connection) -> Result<Vec<WeightORM>, rusqlite::Error> {
+    query("ORDER BY timestamp DESC LIMIT (?1)", [count], conn)
+}
+
+// Entries from from up to but excluding to, oldest first
+pub fn range(from: i64, to: i64, conn: &Connection) -> Result<Vec<WeightORM>, rusqlite::Error> {
+    query(
+        "WHERE timestamp >= ?1 AND timestamp < ?2 ORDER BY timestamp ASC",
+        [from, to],
+        conn,
+    )
+}
+
+fn query<P: Params>(
+    clause: &str,
+    params: P,
+    conn: &Connection,
+) -> Result<Vec<WeightORM>, rusqlite::Error> {
+    let mut query = conn.prepare(&format!(
+        "SELECT id, timestamp, weight FROM weight {};",
+        clause
+    ))?;
+    let entries = query.query_map(params, WeightORM::read)?.collect();
+    entries
+}
+
+fn last_command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+    let mut notes = Vec::new();
 
     let take_default = 3;
     let take = match input.next() {
         Some(take) => take.parse::<i64>().unwrap_or_else(|_| {
-            output.push_str(&format!(
-                "Failed to parse query parameter\nUsing default query parameter {}\n",
-                take_default
-            ));
+            notes.push(String::from("Failed to parse query parameter"));
+            notes.push(format!("Using default query parameter {}", take_default));
             take_default
         }),
         None => {
-            output.push_str(&format!("Using default query parameter {}\n", take_default));
+            notes.push(format!("Using default query parameter {}", take_default));
             take_default
         }
     };
 
-    let mut query = match conn.prepare(
-        "
-        SELECT id, timestamp, weight
-        FROM weight
-        ORDER BY timestamp DESC
-        LIMIT (?1);
-    ",
-    ) {
-        Ok(query) => query,
-        Err(err) => {
-            error!("Failed to prepare query 'last' for weight -> {}", err);
-            output.push_str("Failed to prepare query 'last' for weight. Check log for full error.");
-            return output;
-        }
-    };
+    let entries = last(take, conn).map_err(Error::database(format!(
+        "read the last {} weight entries",
+        take
+    )))?;
+    Ok(Output::Records {
+        notes,
+        records: entries.into_iter().map(WeightORM::record).collect(),
+    })
+}
 
-    let results = query.query_map([take], |row| {
-        Ok(WeightORM {
-            _id: row.get(0)?,
-            timestamp: row.get(1)?,
-            weight: row.get(2)?,
-        })
-    });
-
-    match results {
-        Ok(results) => {
-            for result in results {
-                let result = result.unwrap();
-                output.push_str(&format!(
-                    "{}kg, recorded {}\n",
-                    result.weight,
-                    utils::format_timestamp(result.timestamp)
-                ));
-            }
-        }
-        Err(err) => output.push_str(&format!(
-            "Failed to retrieve last {} entries: {}\n",
-            take, err
-        )),
-    }
+fn range_command(input: &mut Args, conn: &Connection) -> Result<Output, Error> {
+    let (from, to) = utils::parse_range(input)?;
 
-    output
+    let entries = range(from, to, conn).map_err(Error::database("read weight entries"))?;
+    Ok(Output::Records {
+        notes: Vec::new(),
+        records: entries.into_iter().map(WeightORM::record).collect(),
+    })
 }
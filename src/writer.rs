@@ -0,0 +1,235 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use log::{error, info};
+use rusqlite::Connection;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{heartrate, spo2, temperature, utils, SectionedConfigMap};
+
+#[derive(Clone, Copy)]
+pub enum Sample {
+    Heartrate {
+        timestamp: i64,
+        instrument: i64,
+        heartrate: u8,
+    },
+    Temperature {
+        timestamp: i64,
+        instrument: i64,
+        temperature: f32,
+    },
+    Spo2 {
+        timestamp: i64,
+        instrument: i64,
+        spo2: u8,
+        pulse: u8,
+    },
+}
+
+// What happens when a sample lands on a second that already holds one from the same
+// instrument. Manual entries fail, recorded streams keep the first or last sample.
+#[derive(Clone, Copy)]
+pub enum Collision {
+    Fail,
+    First,
+    Last,
+}
+
+impl Collision {
+    pub fn parse(value: &str) -> Option<Collision> {
+        match value {
+            "fail" => Some(Collision::Fail),
+            "first" => Some(Collision::First),
+            "last" => Some(Collision::Last),
+            _ => None,
+        }
+    }
+
+    // Conflict clause for an INSERT into a table unique on (timestamp, instrument)
+    pub fn clause(&self, columns: &[&str]) -> String {
+        match self {
+            Collision::Fail => String::new(),
+            Collision::First => String::from("ON CONFLICT (timestamp, instrument) DO NOTHING"),
+            Collision::Last => format!(
+                "ON CONFLICT (timestamp, instrument) DO UPDATE SET {}",
+                columns
+                    .iter()
+                    .map(|column| format!("{} = excluded.{}", column, column))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+// Samples kept for retrying while the database fails, an hour of three sensors at 1 Hz
+const MAX_BUFFERED: usize = 3 * 3600;
+
+pub struct Settings {
+    flush_secs: u64,
+    flush_samples: usize,
+    collision: Collision,
+}
+
+impl Settings {
+    pub fn from_config(conf: Arc<RwLock<SectionedConfigMap>>) -> Settings {
+        let flush_secs = utils::from_config_or(conf.clone(), "recording", "flush_secs", "10");
+        let flush_secs = match flush_secs.parse::<u64>() {
+            Ok(flush_secs) if flush_secs > 0 => flush_secs,
+            _ => {
+                error!("Invalid flush_secs in section recording: {}", flush_secs);
+                10
+            }
+        };
+
+        let flush_samples = utils::from_config_or(conf.clone(), "recording", "flush_samples", "60");
+        let flush_samples = match flush_samples.parse::<usize>() {
+            Ok(flush_samples) if flush_samples > 0 => flush_samples,
+            _ => {
+                error!(
+                    "Invalid flush_samples in section recording: {}",
+                    flush_samples
+                );
+                60
+            }
+        };
+
+        let collision = utils::from_config_or(conf, "recording", "collision", "first");
+        let collision = match Collision::parse(&collision) {
+            Some(Collision::Fail) | None => {
+                error!(
+                    "Invalid collision in section recording: {}. Use first or last",
+                    collision
+                );
+                Collision::First
+            }
+            Some(collision) => collision,
+        };
+
+        Settings {
+            flush_secs,
+            flush_samples,
+            collision,
+        }
+    }
+}
+
+// Buffers samples from the receiver and commits them in one transaction every
+// flush_secs seconds or flush_samples samples, whichever comes first. Whatever is
+// left is flushed once all senders are gone.
+pub async fn run(mut samples: UnboundedReceiver<Sample>, settings: Settings, conn: &Connection) {
+    let mut buffer = Vec::with_capacity(settings.flush_samples);
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.flush_secs));
+
+    loop {
+        tokio::select! {
+            sample = samples.recv() => match sample {
+                Some(sample) => {
+                    buffer.push(sample);
+                    if buffer.len() >= settings.flush_samples {
+                        flush(&mut buffer, &settings, conn);
+                    }
+                }
+                None => {
+                    flush(&mut buffer, &settings, conn);
+                    info!("Recording writer stopped");
+                    return;
+                }
+            },
+            _ = interval.tick() => flush(&mut buffer, &settings, conn),
+        }
+    }
+}
+
+// Samples stay buffered if the transaction fails, so the next flush retries them. Past
+// MAX_BUFFERED the oldest ones are dropped.
+fn flush(buffer: &mut Vec<Sample>, settings: &Settings, conn: &Connection) {
+    if buffer.is_empty() {
+        return;
+    }
+
+    match write(buffer, settings.collision, conn) {
+        Ok(written) => {
+            info!("Committed {} of {} samples", written, buffer.len());
+            buffer.clear();
+        }
+        Err(err) => {
+            error!("Failed to commit {} samples -> {}", buffer.len(), err);
+            let excess = buffer
+                .len()
+                .saturating_sub(MAX_BUFFERED.max(settings.flush_samples));
+            if excess > 0 {
+                buffer.drain(..excess);
+                error!(
+                    "Dropped the {} oldest samples, the database keeps failing",
+                    excess
+                );
+            }
+        }
+    }
+}
+
+fn write(
+    buffer: &[Sample],
+    collision: Collision,
+    conn: &Connection,
+) -> Result<usize, rusqlite::Error> {
+    let tx = conn.unchecked_transaction()?;
+
+    let mut written = 0;
+    for sample in buffer {
+        written += match *sample {
+            Sample::Heartrate {
+                timestamp,
+                instrument,
+                heartrate,
+            } => heartrate::write_heartrate(heartrate, timestamp, instrument, collision, &tx)?,
+            Sample::Temperature {
+                timestamp,
+                instrument,
+                temperature,
+            } => {
+                temperature::write_temperature(temperature, timestamp, instrument, collision, &tx)?
+            }
+            Sample::Spo2 {
+                timestamp,
+                instrument,
+                spo2,
+                pulse,
+            } => spo2::write_spo2(spo2, pulse, timestamp, instrument, collision, &tx)?,
+        };
+    }
+
+    tx.commit()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_flushes_keep_only_the_newest_samples() {
+        // Without tables every write fails
+        let conn = Connection::open_in_memory().unwrap();
+        let settings = Settings {
+            flush_secs: 10,
+            flush_samples: 60,
+            collision: Collision::First,
+        };
+        let mut buffer: Vec<Sample> = (0..MAX_BUFFERED as i64 + 5)
+            .map(|timestamp| Sample::Heartrate {
+                timestamp,
+                instrument: 0,
+                heartrate: 60,
+            })
+            .collect();
+
+        flush(&mut buffer, &settings, &conn);
+        assert_eq!(buffer.len(), MAX_BUFFERED);
+        assert!(matches!(buffer[0], Sample::Heartrate { timestamp: 5, .. }));
+    }
+}